@@ -0,0 +1,493 @@
+use crate::auth_scope::AuthScope;
+use crate::connection_info::ConnectionInfo;
+use crate::crypto::NodeSecret;
+use crate::errors::{ApiError, FieldError};
+use crate::events::{self, NodeEvent};
+use crate::models::{Role, User};
+use crate::password;
+use crate::state::AppState;
+use crate::tls;
+use crate::validation::{validate_mac_id, validate_pool_name, OptionalJson, ValidJson};
+use crate::{
+    disconnect_node, Disconnect, NodeAddress, ProxyNode, RegisteredNode, RegistrationInfo,
+    Rejection,
+};
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use validator::Validate;
+
+/// A live ws session as shown by `/admin/sessions`.
+#[derive(Serialize, ToSchema)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    pub node_id: Uuid,
+    pub mac_id: String,
+    pub connected_at: DateTime<Utc>,
+    #[schema(value_type = String)]
+    pub source_ip: IpAddr,
+    pub connection: ConnectionInfo,
+}
+
+#[utoipa::path(
+    responses(
+        (status = 200, body = Vec<SessionInfo>),
+        (status = 403, description = "Admin role required"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/admin/sessions", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn list_sessions(state: web::Data<AppState>) -> impl Responder {
+    let sessions = state.sessions.lock().await;
+    let list: Vec<SessionInfo> = sessions
+        .iter()
+        .map(|(node_id, handle)| SessionInfo {
+            session_id: handle.session_id,
+            node_id: *node_id,
+            mac_id: handle.mac_id.clone(),
+            connected_at: handle.connected_at,
+            source_ip: handle.source_ip,
+            connection: handle.connection.clone(),
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Closes a session and drops its node from `ActiveNodes` right away rather than
+/// waiting for the session to stop.
+#[utoipa::path(
+    params(("id" = Uuid, Path, description = "Session id")),
+    responses(
+        (status = 200, description = "Session revoked"),
+        (status = 403, description = "Admin role required"),
+        (status = 404, description = "Session not found"),
+    ),
+    security(("bearer" = []))
+)]
+#[delete("/admin/sessions/{id}", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn revoke_session(path: web::Path<Uuid>, state: web::Data<AppState>) -> impl Responder {
+    let session_id = path.into_inner();
+    let revoked = {
+        let mut sessions = state.sessions.lock().await;
+        let node_id = sessions
+            .iter()
+            .find(|(_, handle)| handle.session_id == session_id)
+            .map(|(node_id, _)| *node_id);
+        node_id.and_then(|node_id| sessions.remove(&node_id).map(|handle| (node_id, handle)))
+    };
+    let Some((node_id, handle)) = revoked else {
+        return HttpResponse::NotFound().body("Session not found");
+    };
+
+    handle.addr.do_send(Disconnect(Rejection::Revoked));
+    if state.active_nodes.lock().await.remove(&node_id).is_some() {
+        state.metrics.count_leave();
+        events::publish(&state.events, NodeEvent::Left { id: node_id });
+    }
+    HttpResponse::Ok().body("Session revoked")
+}
+
+/// How long the replaced key keeps working unless the request says otherwise.
+const DEFAULT_KEY_GRACE: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, ToSchema, Default)]
+pub struct RotateApiKeyRequest {
+    /// New key; generated when omitted.
+    pub api_key: Option<String>,
+    /// How long the old key stays valid (default 300; 0 revokes it immediately).
+    pub grace_period_secs: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RotateApiKeyResponse {
+    /// Shown only in this response.
+    pub api_key: String,
+    pub grace_period_secs: u64,
+}
+
+#[utoipa::path(
+    request_body(content = Option<RotateApiKeyRequest>),
+    responses(
+        (status = 200, body = RotateApiKeyResponse),
+        (status = 400, description = "Empty api_key"),
+        (status = 403, description = "Admin role required"),
+    ),
+    security(("bearer" = []))
+)]
+#[post("/admin/api-key/rotate", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn rotate_api_key(
+    OptionalJson(body): OptionalJson<RotateApiKeyRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let body = body.unwrap_or_default();
+    if body.api_key.as_deref().is_some_and(str::is_empty) {
+        return HttpResponse::BadRequest().body("api_key must not be empty");
+    }
+    let grace = body
+        .grace_period_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_KEY_GRACE);
+
+    let api_key = state.api_keys.rotate(body.api_key, grace);
+    HttpResponse::Ok().json(RotateApiKeyResponse {
+        api_key,
+        grace_period_secs: grace.as_secs(),
+    })
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+    /// Close existing ws sessions when enabling; defaults to `MAINTENANCE_DRAIN_SESSIONS`.
+    #[serde(default)]
+    pub drain_sessions: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MaintenanceResponse {
+    pub enabled: bool,
+    /// Sessions closed by this request.
+    pub drained_sessions: usize,
+}
+
+/// Switches maintenance mode. Enabling it can also drain ws sessions, which are closed with
+/// "try again later" so nodes reconnect once maintenance ends.
+#[utoipa::path(
+    request_body = MaintenanceRequest,
+    responses(
+        (status = 200, body = MaintenanceResponse),
+        (status = 403, description = "Admin role required"),
+    ),
+    security(("bearer" = []))
+)]
+#[post("/admin/maintenance", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn set_maintenance(
+    body: web::Json<MaintenanceRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    state.maintenance.set(body.enabled);
+    let drain = body.enabled
+        && body
+            .drain_sessions
+            .unwrap_or(state.config.maintenance_drain_sessions);
+    let mut drained_sessions = 0;
+    if drain {
+        // Each session removes itself and its active node as it stops.
+        for handle in state.sessions.lock().await.values() {
+            handle.addr.do_send(Disconnect(Rejection::Maintenance));
+            drained_sessions += 1;
+        }
+    }
+    eprintln!(
+        "Maintenance mode {} ({} ws sessions drained)",
+        if body.enabled { "on" } else { "off" },
+        drained_sessions
+    );
+    HttpResponse::Ok().json(MaintenanceResponse {
+        enabled: body.enabled,
+        drained_sessions,
+    })
+}
+
+/// A user as exported: no password hash.
+#[derive(Serialize, ToSchema)]
+pub struct ExportedUser {
+    pub username: String,
+    pub role: Role,
+    pub scopes: Vec<String>,
+    pub tenant: Option<String>,
+}
+
+/// Body of `/admin/export`. Lists are sorted so exports diff cleanly.
+#[derive(Serialize, ToSchema)]
+pub struct TopologyExport {
+    pub exported_at: DateTime<Utc>,
+    pub registered_nodes: Vec<RegistrationInfo>,
+    pub active_nodes: Vec<ProxyNode>,
+    pub users: Vec<ExportedUser>,
+}
+
+/// Downloads registrations, active nodes and users as one JSON document, with node passwords
+/// and password hashes left out.
+#[utoipa::path(
+    responses(
+        (status = 200, body = TopologyExport),
+        (status = 403, description = "Admin role required"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/admin/export", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn export(state: web::Data<AppState>) -> impl Responder {
+    let mut registered_nodes: Vec<RegistrationInfo> = state
+        .registered_nodes
+        .lock()
+        .await
+        .values()
+        .map(RegistrationInfo::from)
+        .collect();
+    registered_nodes.sort_by_key(|node| node.id);
+
+    let mut active_nodes: Vec<ProxyNode> =
+        state.active_nodes.lock().await.values().cloned().collect();
+    active_nodes.sort_by_key(|node| node.id);
+
+    let mut users: Vec<ExportedUser> = state
+        .users
+        .lock()
+        .await
+        .values()
+        .map(|user| ExportedUser {
+            username: user.username.clone(),
+            role: user.role,
+            scopes: user.scopes.clone(),
+            tenant: user.tenant.clone(),
+        })
+        .collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let exported_at = Utc::now();
+    let filename = format!(
+        "fer_net-export-{}.json",
+        exported_at.format("%Y%m%dT%H%M%SZ")
+    );
+    HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .json(TopologyExport {
+            exported_at,
+            registered_nodes,
+            active_nodes,
+            users,
+        })
+}
+
+/// A registration to import. Same shape as `RegistrationInfo` plus a password, which
+/// exports never contain.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct ImportedRegistration {
+    pub id: Uuid,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub password: String,
+    #[validate(custom(function = "validate_mac_id"))]
+    pub mac_id: String,
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_pool_name"))]
+    pub pool: Option<String>,
+    #[serde(default)]
+    #[validate(nested)]
+    pub address: Option<NodeAddress>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_pool_name"))]
+    pub tenant: Option<String>,
+}
+
+/// A user to import; `password` is plaintext and hashed on import.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct ImportedUser {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub password: String,
+    pub role: Role,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_pool_name"))]
+    pub tenant: Option<String>,
+}
+
+/// Body of `/admin/import`: an export with passwords filled in. Other export fields
+/// (`active_nodes`, `exported_at`) are accepted and ignored.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct TopologyImport {
+    #[serde(default)]
+    #[validate(nested)]
+    pub registered_nodes: Vec<ImportedRegistration>,
+    #[serde(default)]
+    #[validate(nested)]
+    pub users: Vec<ImportedUser>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    /// Upsert into the current data instead of replacing it.
+    #[serde(default)]
+    pub merge: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportSummary {
+    pub mode: &'static str,
+    pub registered_nodes: usize,
+    pub users: usize,
+}
+
+/// Checks what `Validate` can't: duplicates within the import, and that a replace import
+/// keeps at least one admin.
+fn check_import(import: &TopologyImport, merge: bool) -> Result<(), ApiError> {
+    let mut fields = Vec::new();
+    let mut ids = HashSet::new();
+    for (index, node) in import.registered_nodes.iter().enumerate() {
+        if !ids.insert(node.id) {
+            fields.push(FieldError {
+                field: format!("registered_nodes[{}].id", index),
+                message: "duplicate id".to_string(),
+            });
+        }
+    }
+    let mut usernames = HashSet::new();
+    for (index, user) in import.users.iter().enumerate() {
+        if !usernames.insert(&user.username) {
+            fields.push(FieldError {
+                field: format!("users[{}].username", index),
+                message: "duplicate username".to_string(),
+            });
+        }
+    }
+    if !merge && !import.users.iter().any(|user| user.role == Role::Admin) {
+        fields.push(FieldError {
+            field: "users".to_string(),
+            message: "a replace import must include an admin".to_string(),
+        });
+    }
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let mut error = ApiError::new(StatusCode::BAD_REQUEST, "Validation failed");
+    error.fields = fields;
+    Err(error)
+}
+
+/// Restores registrations and users from an export-shaped document. Every record is validated
+/// before anything is applied, so an invalid import changes nothing. Without `?merge=true` the
+/// current registrations and users are replaced; nodes that are no longer registered are
+/// dropped from the active list as on deregister.
+#[utoipa::path(
+    params(ImportQuery),
+    request_body = TopologyImport,
+    responses(
+        (status = 200, body = ImportSummary),
+        (status = 400, body = ApiError),
+        (status = 403, description = "Admin role required"),
+        (status = 507, description = "Registered node limit reached"),
+    ),
+    security(("bearer" = []))
+)]
+#[post("/admin/import", wrap = "AuthScope::role(Role::Admin)")]
+pub async fn import_topology(
+    query: web::Query<ImportQuery>,
+    body: ValidJson<TopologyImport>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let merge = query.merge;
+    let import = body.0;
+    if let Err(error) = check_import(&import, merge) {
+        return actix_web::ResponseError::error_response(&error);
+    }
+
+    // Hash up front so a failure can't leave a half-applied import.
+    let mut users = Vec::with_capacity(import.users.len());
+    for user in import.users {
+        let hashed = password::hash_blocking(state.password_hasher.clone(), user.password).await;
+        let Ok(password_hash) = hashed else {
+            return HttpResponse::InternalServerError().body("Failed to hash password");
+        };
+        users.push(User {
+            username: user.username,
+            password_hash,
+            role: user.role,
+            scopes: user.scopes,
+            tenant: user.tenant,
+        });
+    }
+    let registrations: Vec<RegisteredNode> = import
+        .registered_nodes
+        .into_iter()
+        .map(|node| RegisteredNode {
+            id: node.id,
+            secret: NodeSecret::new(&node.password),
+            mac_id: node.mac_id,
+            cert_fingerprint: node
+                .cert_fingerprint
+                .as_deref()
+                .map(tls::normalize_fingerprint),
+            tags: node.tags,
+            pool: node.pool,
+            address: node.address,
+            owner: node.owner,
+            tenant: node.tenant,
+            expires_at: state.config.registration_expiry(),
+            token_nonce: Uuid::new_v4(),
+        })
+        .collect();
+    let summary = ImportSummary {
+        mode: if merge { "merge" } else { "replace" },
+        registered_nodes: registrations.len(),
+        users: users.len(),
+    };
+
+    let mut reg_nodes = state.registered_nodes.lock().await;
+    let total = if merge {
+        let new = registrations
+            .iter()
+            .filter(|node| !reg_nodes.contains_key(&node.id));
+        reg_nodes.len() + new.count()
+    } else {
+        registrations.len()
+    };
+    if state
+        .config
+        .max_registered_nodes
+        .is_some_and(|max| total > max)
+    {
+        return HttpResponse::InsufficientStorage().body("Registered node limit reached");
+    }
+
+    if !merge {
+        reg_nodes.clear();
+    }
+    for node in registrations {
+        reg_nodes.insert(node.id, node);
+    }
+    state.registered_nodes_cache.invalidate();
+    let dropped: Vec<Uuid> = if merge {
+        Vec::new()
+    } else {
+        let active = state.active_nodes.lock().await;
+        active
+            .keys()
+            .filter(|id| !reg_nodes.contains_key(id))
+            .copied()
+            .collect()
+    };
+    drop(reg_nodes);
+    // Nodes whose registration didn't survive the replace are disconnected, not just unlisted.
+    for id in dropped {
+        disconnect_node(&state, id, Rejection::Deregistered).await;
+    }
+
+    let mut user_store = state.users.lock().await;
+    if !merge {
+        user_store.clear();
+    }
+    for user in users {
+        user_store.insert(user.username.clone(), user);
+    }
+
+    HttpResponse::Ok().json(summary)
+}
@@ -0,0 +1,59 @@
+use crate::sync::LockExt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+struct Keys {
+    current: String,
+    /// The key replaced by the last rotation, accepted until the deadline.
+    previous: Option<(String, Instant)>,
+}
+
+/// The live registration API key. Starts as `Config::api_key` and can be rotated at runtime.
+pub struct ApiKeys {
+    keys: Mutex<Keys>,
+}
+
+impl ApiKeys {
+    pub fn new(initial: String) -> Self {
+        ApiKeys {
+            keys: Mutex::new(Keys {
+                current: initial,
+                previous: None,
+            }),
+        }
+    }
+
+    pub fn accepts(&self, key: &str) -> bool {
+        let keys = self.keys.lock_or_recover();
+        let current = constant_time_eq(key, &keys.current);
+        let previous = keys.previous.as_ref().is_some_and(|(previous, until)| {
+            constant_time_eq(key, previous) && Instant::now() < *until
+        });
+        current | previous
+    }
+
+    /// Replaces the current key (generating one if `new_key` is `None`), keeping the old key
+    /// valid for `grace`. Returns the new key.
+    pub fn rotate(&self, new_key: Option<String>, grace: Duration) -> String {
+        let new_key = new_key.unwrap_or_else(generate);
+        let mut keys = self.keys.lock_or_recover();
+        let old = std::mem::replace(&mut keys.current, new_key.clone());
+        keys.previous = (!grace.is_zero()).then(|| (old, Instant::now() + grace));
+        new_key
+    }
+}
+
+fn generate() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Compares without stopping at the first differing byte, so response timing doesn't reveal
+/// how much of a guessed key was right. Only the length can leak.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
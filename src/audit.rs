@@ -0,0 +1,278 @@
+use crate::shutdown::ShutdownSignal;
+use crate::sync::LockExt;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Records the writer may fall behind by; past that, new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Minimum gap between "dropping audit records" warnings while the queue stays full.
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why a ws session ended, as recorded in `AuditEvent::Disconnect`.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// The node closed the connection, or it dropped.
+    Graceful,
+    /// Closed by the inactivity timeout.
+    Timeout,
+    /// Closed by the server: revoked, superseded by a newer session, or drained.
+    Kicked,
+    /// Closed for misbehaving: failed auth, rate limits, oversized or malformed frames.
+    Rejected,
+}
+
+/// One entry of the node audit trail. `session_id` ties together the events of one ws
+/// connection; HTTP-only events (registration, address updates over HTTP) have none.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Register {
+        node_id: Uuid,
+        mac_id: String,
+        source_ip: IpAddr,
+    },
+    Connect {
+        session_id: Uuid,
+        source_ip: IpAddr,
+    },
+    /// `node_id` is the claimed id for a failed password auth, and absent for a bad token.
+    Auth {
+        session_id: Uuid,
+        node_id: Option<Uuid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mac_id: Option<String>,
+        source_ip: IpAddr,
+        success: bool,
+    },
+    AddressSet {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<Uuid>,
+        node_id: Uuid,
+        mac_id: String,
+        source_ip: IpAddr,
+        ip: String,
+        port: u16,
+    },
+    /// `node_id` and `mac_id` are absent when the session never authenticated.
+    Disconnect {
+        session_id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        node_id: Option<Uuid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mac_id: Option<String>,
+        source_ip: IpAddr,
+        reason: DisconnectReason,
+        /// The close message sent to the node, if the server closed the session.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<&'static str>,
+    },
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+/// Hands `AuditEvent`s to the writer task (see `run`) without blocking the caller. When the
+/// writer falls `QUEUE_CAPACITY` records behind (e.g. a slow disk), new records are dropped
+/// and counted rather than stalling ws sessions.
+pub struct AuditLog {
+    sender: Option<mpsc::Sender<AuditRecord>>,
+    /// Taken by `main` to start the writer.
+    receiver: Mutex<Option<mpsc::Receiver<AuditRecord>>>,
+    dropped: AtomicU64,
+    /// When the last drop warning was logged, and drops since then.
+    drop_warning: Mutex<(Option<Instant>, u64)>,
+}
+
+/// Writer backlog, shown in `/health` and `/metrics`.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct AuditHealth {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    /// Records dropped because the queue was full, since startup.
+    pub dropped: u64,
+}
+
+impl AuditHealth {
+    /// Nearly full: the writer isn't keeping up and drops are imminent (or happening).
+    pub fn is_degraded(&self) -> bool {
+        self.queue_depth * 10 >= self.queue_capacity * 9
+    }
+}
+
+impl AuditLog {
+    /// A disabled log drops every event.
+    pub fn new(enabled: bool) -> Self {
+        let (sender, receiver) = if enabled {
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+        AuditLog {
+            sender,
+            receiver: Mutex::new(receiver),
+            dropped: AtomicU64::new(0),
+            drop_warning: Mutex::new((None, 0)),
+        }
+    }
+
+    /// Timestamps `event` and queues it for writing, or drops it if the queue is full.
+    pub fn record(&self, event: AuditEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let record = AuditRecord {
+            at: Utc::now(),
+            event,
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.warn_dropped();
+        }
+    }
+
+    /// Logs drops at most once per `DROP_WARNING_INTERVAL`, with how many were dropped since.
+    fn warn_dropped(&self) {
+        let mut warning = self.drop_warning.lock_or_recover();
+        let (last, pending) = &mut *warning;
+        *pending += 1;
+        if last.is_some_and(|last| last.elapsed() < DROP_WARNING_INTERVAL) {
+            return;
+        }
+        eprintln!(
+            "Audit: queue full ({} records), dropped {} records; is the audit log disk slow?",
+            QUEUE_CAPACITY, pending
+        );
+        *last = Some(Instant::now());
+        *pending = 0;
+    }
+
+    /// `None` when auditing is off.
+    pub fn health(&self) -> Option<AuditHealth> {
+        let sender = self.sender.as_ref()?;
+        Some(AuditHealth {
+            queue_depth: sender.max_capacity() - sender.capacity(),
+            queue_capacity: sender.max_capacity(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        })
+    }
+
+    pub fn take_receiver(&self) -> Option<mpsc::Receiver<AuditRecord>> {
+        self.receiver.lock_or_recover().take()
+    }
+}
+
+/// Appends queued records to `path`, one JSON object per line, until shutdown; whatever is
+/// still queued then is written before returning.
+pub async fn run(
+    path: PathBuf,
+    mut records: mpsc::Receiver<AuditRecord>,
+    mut shutdown: ShutdownSignal,
+) {
+    loop {
+        let first = tokio::select! {
+            _ = shutdown.wait() => break,
+            record = records.recv() => record,
+        };
+        let Some(first) = first else {
+            return;
+        };
+        let mut batch = vec![first];
+        while let Ok(record) = records.try_recv() {
+            batch.push(record);
+        }
+        append(&path, batch).await;
+    }
+    let mut batch = Vec::new();
+    while let Ok(record) = records.try_recv() {
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        append(&path, batch).await;
+    }
+}
+
+async fn append(path: &Path, batch: Vec<AuditRecord>) {
+    let mut lines = Vec::new();
+    for record in &batch {
+        match serde_json::to_vec(record) {
+            Ok(line) => {
+                lines.extend(line);
+                lines.push(b'\n');
+            }
+            Err(err) => eprintln!("Audit: can't serialize record: {}", err),
+        }
+    }
+    let path = path.to_path_buf();
+    let result = web::block(move || {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?
+            .write_all(&lines)
+    })
+    .await;
+    if let Ok(Err(err)) = result {
+        eprintln!("Audit: failed to write {} records: {}", batch.len(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn connect() -> AuditEvent {
+        AuditEvent::Connect {
+            session_id: Uuid::new_v4(),
+            source_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_and_counts() {
+        let audit = AuditLog::new(true);
+        let mut records = audit.take_receiver().unwrap();
+        for _ in 0..QUEUE_CAPACITY {
+            audit.record(connect());
+        }
+        let health = audit.health().unwrap();
+        assert_eq!((health.queue_depth, health.dropped), (QUEUE_CAPACITY, 0));
+        assert!(health.is_degraded());
+
+        audit.record(connect());
+        audit.record(connect());
+        assert_eq!(audit.health().unwrap().dropped, 2);
+
+        // Once the writer catches up, records are queued again.
+        while records.try_recv().is_ok() {}
+        audit.record(connect());
+        let health = audit.health().unwrap();
+        assert_eq!((health.queue_depth, health.dropped), (1, 2));
+        assert!(!health.is_degraded());
+    }
+
+    #[test]
+    fn disabled_log_reports_no_health() {
+        let audit = AuditLog::new(false);
+        audit.record(connect());
+        assert!(audit.health().is_none());
+    }
+}
@@ -1,46 +1,704 @@
-use crate::models::Claims;
-use actix_web::{dev::ServiceRequest, Error};
+use crate::api_key::constant_time_eq;
+use crate::connection_info::ConnectionInfo;
+use crate::crypto::hmac_sha256_hex;
+use crate::models::{Claims, User};
+use crate::state::AppState;
+use crate::validation::{validate_mac_id, OptionalJson};
+use actix_web::http::header;
+use actix_web::{
+    dev::ServiceRequest, get, post, web, Error, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use std::env;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use std::{env, fs, io};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
 
-pub fn create_jwt(username: &str) -> String {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
-    let expiration = chrono::Utc::now()
+/// Audience claim carried by node-scoped tokens, so they can't be used as user tokens.
+pub const NODE_AUDIENCE: &str = "node";
+
+/// Cookie `/login?cookie=true` stores the user token in, for browser UIs. It is read only when
+/// there's no `Authorization` header.
+pub const TOKEN_COOKIE: &str = "fer_net_token";
+
+/// Public half of an RS256 signing key in JWK form.
+#[derive(Clone, Serialize)]
+pub struct Jwk {
+    kty: &'static str,
+    r#use: &'static str,
+    alg: &'static str,
+    kid: String,
+    n: String,
+    e: String,
+}
+
+/// Signing configuration: HS256 with `JWT_SECRET` (default), or RS256 when `JWT_ALG=RS256`
+/// with `JWT_PRIVATE_KEY_FILE`/`JWT_PUBLIC_KEY_FILE` PEM files and an optional `JWT_KID`.
+///
+/// `JWT_ISSUER` and `JWT_AUDIENCE`, when set, are stamped on issued tokens and required on
+/// incoming ones, so tokens minted for another service sharing the key are refused.
+///
+/// `JWT_SKIP_EXP_VALIDATION=true` accepts expired tokens, for test suites replaying
+/// pre-generated ones. It only takes effect together with `DEV_MODE=true` and must never be
+/// enabled in production: a leaked token would then work forever.
+struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    kid: Option<String>,
+    jwk: Option<Jwk>,
+    issuer: Option<String>,
+    /// Audience of user tokens; node tokens always use `NODE_AUDIENCE`.
+    audience: Option<String>,
+    skip_exp: bool,
+}
+
+static KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value.parse().unwrap_or(false))
+}
+
+fn invalid<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl JwtKeys {
+    fn from_env() -> io::Result<Self> {
+        let mut keys = Self::signing_keys()?;
+        keys.issuer = env::var("JWT_ISSUER").ok();
+        keys.audience = env::var("JWT_AUDIENCE").ok();
+        keys.skip_exp = env_flag("JWT_SKIP_EXP_VALIDATION");
+        if keys.skip_exp && !env_flag("DEV_MODE") {
+            return Err(invalid(
+                "JWT_SKIP_EXP_VALIDATION is for testing only and requires DEV_MODE=true",
+            ));
+        }
+        if keys.audience.as_deref() == Some(NODE_AUDIENCE) {
+            return Err(invalid(format!(
+                "JWT_AUDIENCE can't be {:?}, which is reserved for node tokens",
+                NODE_AUDIENCE
+            )));
+        }
+        Ok(keys)
+    }
+
+    fn signing_keys() -> io::Result<Self> {
+        match env::var("JWT_ALG").as_deref() {
+            Ok("RS256") => Self::rs256(),
+            Ok("HS256") | Err(_) => {
+                let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
+                Ok(Self::hs256(&secret))
+            }
+            Ok(other) => Err(invalid(format!("unsupported JWT_ALG {}", other))),
+        }
+    }
+
+    fn hs256(secret: &str) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret.as_ref()),
+            decoding: DecodingKey::from_secret(secret.as_ref()),
+            kid: None,
+            jwk: None,
+            issuer: None,
+            audience: None,
+            skip_exp: false,
+        }
+    }
+
+    fn rs256() -> io::Result<Self> {
+        let private_pem = fs::read(env::var("JWT_PRIVATE_KEY_FILE").map_err(invalid)?)?;
+        let public_pem = fs::read_to_string(env::var("JWT_PUBLIC_KEY_FILE").map_err(invalid)?)?;
+
+        let public_key = RsaPublicKey::from_public_key_pem(&public_pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(&public_pem))
+            .map_err(invalid)?;
+        let n = public_key.n().to_bytes_be();
+        let e = public_key.e().to_bytes_be();
+
+        // Default kid: a short digest of the modulus, stable across restarts with the same key.
+        let kid = env::var("JWT_KID").unwrap_or_else(|_| {
+            Sha256::digest(&n)[..8]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        });
+
+        Ok(JwtKeys {
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(&private_pem).map_err(invalid)?,
+            decoding: DecodingKey::from_rsa_pem(public_pem.as_bytes()).map_err(invalid)?,
+            kid: Some(kid.clone()),
+            jwk: Some(Jwk {
+                kty: "RSA",
+                r#use: "sig",
+                alg: "RS256",
+                kid,
+                n: URL_SAFE_NO_PAD.encode(n),
+                e: URL_SAFE_NO_PAD.encode(e),
+            }),
+            issuer: None,
+            audience: None,
+            skip_exp: false,
+        })
+    }
+}
+
+/// Loads the signing keys up front so a bad key configuration fails at startup.
+pub fn init_keys() -> io::Result<()> {
+    let keys = JwtKeys::from_env()?;
+    if keys.skip_exp {
+        eprintln!(
+            "WARNING: JWT_SKIP_EXP_VALIDATION is on; expired tokens are accepted. \
+             Never run like this in production."
+        );
+    }
+    let _ = KEYS.set(keys);
+    Ok(())
+}
+
+/// Signing setup for the startup log, with the HMAC secret redacted.
+pub fn describe_keys() -> String {
+    let keys = keys();
+    let signing = match &keys.kid {
+        Some(kid) => format!("jwt_alg={:?} jwt_kid={}", keys.algorithm, kid),
+        None if env::var("JWT_SECRET").is_ok() => {
+            format!("jwt_alg={:?} jwt_secret=***", keys.algorithm)
+        }
+        None => format!("jwt_alg={:?} jwt_secret=default", keys.algorithm),
+    };
+    format!(
+        "{} jwt_issuer={} jwt_audience={} jwt_skip_exp_validation={}",
+        signing,
+        keys.issuer.as_deref().unwrap_or("none"),
+        keys.audience.as_deref().unwrap_or("none"),
+        keys.skip_exp
+    )
+}
+
+fn keys() -> &'static JwtKeys {
+    KEYS.get_or_init(|| JwtKeys::from_env().expect("invalid JWT key configuration"))
+}
+
+fn expiration() -> usize {
+    chrono::Utc::now()
         .checked_add_signed(chrono::Duration::hours(24))
         .expect("valid timestamp")
-        .timestamp() as usize;
+        .timestamp() as usize
+}
 
-    let claims = Claims {
-        sub: username.to_owned(),
-        exp: expiration,
-    };
+impl JwtKeys {
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(self.algorithm);
+        validation.validate_exp = !self.skip_exp;
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .unwrap()
+    fn issue(&self, claims: &Claims) -> String {
+        let header = Header {
+            kid: self.kid.clone(),
+            ..Header::new(self.algorithm)
+        };
+        encode(&header, claims, &self.encoding).unwrap()
+    }
+
+    fn decode_claims(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<Claims, jsonwebtoken::errors::Error> {
+        decode::<Claims>(token, &self.decoding, validation).map(|data| data.claims)
+    }
+
+    fn user_claims(&self, user: &User) -> Claims {
+        Claims {
+            sub: user.username.clone(),
+            exp: expiration(),
+            iat: Some(chrono::Utc::now().timestamp() as usize),
+            jti: Some(Uuid::new_v4().to_string()),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            role: Some(user.role),
+            scopes: user.scopes.clone(),
+            tenant: user.tenant.clone(),
+            nonce: None,
+        }
+    }
+
+    fn node_claims(&self, node_id: &Uuid, nonce: &Uuid) -> Claims {
+        Claims {
+            sub: node_id.to_string(),
+            exp: expiration(),
+            iat: Some(chrono::Utc::now().timestamp() as usize),
+            jti: None,
+            iss: self.issuer.clone(),
+            aud: Some(NODE_AUDIENCE.to_string()),
+            role: None,
+            scopes: Vec::new(),
+            tenant: None,
+            nonce: Some(nonce.to_string()),
+        }
+    }
+
+    fn validate_user_token(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let claims = self.decode_claims(token, &self.validation())?;
+        if claims.sub.is_empty() {
+            return Err(jsonwebtoken::errors::ErrorKind::InvalidSubject.into());
+        }
+        Ok(claims)
+    }
+
+    fn validate_node_token(&self, token: &str) -> Result<NodeToken, jsonwebtoken::errors::Error> {
+        let mut validation = self.validation();
+        validation.set_audience(&[NODE_AUDIENCE]);
+        let mut required = vec!["exp", "aud"];
+        if self.issuer.is_some() {
+            required.push("iss");
+        }
+        validation.set_required_spec_claims(&required);
+        let claims = self.decode_claims(token, &validation)?;
+        let node_id = Uuid::parse_str(&claims.sub)
+            .map_err(|_| jsonwebtoken::errors::ErrorKind::InvalidSubject)?;
+        // Tokens from before registrations carried a nonce can't be tied to one.
+        let nonce = claims
+            .nonce
+            .as_deref()
+            .and_then(|nonce| Uuid::parse_str(nonce).ok())
+            .ok_or(jsonwebtoken::errors::ErrorKind::InvalidToken)?;
+        Ok(NodeToken { node_id, nonce })
+    }
+}
+
+/// What a valid node token proves: the node id and the registration it was issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeToken {
+    pub node_id: Uuid,
+    pub nonce: Uuid,
+}
+
+/// Issues a user token, returning its claims too so the caller can record the `jti`.
+pub fn create_jwt(user: &User) -> (String, Claims) {
+    let claims = keys().user_claims(user);
+    (keys().issue(&claims), claims)
+}
+
+/// Issues a node token bound to `nonce`, the registration's `token_nonce`.
+pub fn create_node_jwt(node_id: &Uuid, nonce: &Uuid) -> String {
+    keys().issue(&keys().node_claims(node_id, nonce))
 }
 
+/// Validates a user token. Tokens without a subject are rejected even if correctly signed.
 pub fn validate_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
-    )
-    .map(|data| data.claims)
+    keys().validate_user_token(token)
+}
+
+/// Validates a node-scoped token. The caller still has to check the registration it names
+/// is current (`db::find_node`).
+pub fn validate_node_jwt(token: &str) -> Result<NodeToken, jsonwebtoken::errors::Error> {
+    keys().validate_node_token(token)
 }
 
+/// How far a `SignedRegistration` timestamp may be from the server clock, either way.
+const SIGNED_REGISTRATION_SKEW_SECS: i64 = 300;
+
+/// Body of `POST /register/signed`: proof of holding `REGISTRATION_PSK` instead of an API
+/// key and password. `signature` is the hex HMAC-SHA256, keyed with the PSK, of
+/// `fer_net-register\n{id}\n{mac_id}\n{timestamp}` (id hyphenated and lowercase).
+///
+/// Nothing secret is sent: the node's password is derived from the PSK on both sides (see
+/// `derive_node_password`), so the node can compute it locally for ws `Auth`.
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct SignedRegistration {
+    pub id: Uuid,
+    #[validate(custom(function = "validate_mac_id"))]
+    pub mac_id: String,
+    /// Unix seconds when signed; must be within 5 minutes of the server clock.
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+impl SignedRegistration {
+    fn signing_input(&self) -> String {
+        format!(
+            "fer_net-register\n{}\n{}\n{}",
+            self.id, self.mac_id, self.timestamp
+        )
+    }
+
+    /// Checks the signature and that the timestamp is current, which bounds replays.
+    pub fn verify(&self, psk: &str) -> Result<(), &'static str> {
+        let skew = (chrono::Utc::now().timestamp() - self.timestamp).abs();
+        if skew > SIGNED_REGISTRATION_SKEW_SECS {
+            return Err("Signature timestamp out of range");
+        }
+        let expected = hmac_sha256_hex(psk, self.signing_input().as_bytes());
+        if constant_time_eq(&expected, &self.signature.to_ascii_lowercase()) {
+            Ok(())
+        } else {
+            Err("Invalid signature")
+        }
+    }
+}
+
+/// The password of a node registered with a `SignedRegistration`: the hex HMAC-SHA256 of
+/// `fer_net-node-password\n{id}`, keyed with the PSK.
+pub fn derive_node_password(psk: &str, id: &Uuid) -> String {
+    hmac_sha256_hex(psk, format!("fer_net-node-password\n{}", id).as_bytes())
+}
+
+/// Why `authenticate_user` refused a token.
+#[derive(Debug)]
+pub struct AuthFailure {
+    /// Generic 401 message, safe to send to the client.
+    pub message: &'static str,
+    /// Specific cause (e.g. `expired`, `invalid signature`), for server logs only.
+    pub reason: String,
+}
+
+impl AuthFailure {
+    fn new(message: &'static str, reason: impl Into<String>) -> Self {
+        AuthFailure {
+            message,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Full user-token check as done by `validator`: size cap, signature and claims, revocation.
+pub fn authenticate_user(state: Option<&AppState>, token: &str) -> Result<Claims, AuthFailure> {
+    if state.is_some_and(|state| token.len() > state.config.max_jwt_bytes) {
+        return Err(AuthFailure::new("Token too large", "too large"));
+    }
+    let claims = validate_jwt(token)
+        .map_err(|err| AuthFailure::new("Invalid token", rejection_reason(&err)))?;
+    let revoked = match (state, &claims.jti) {
+        (Some(state), Some(jti)) => state.tokens.is_revoked(jti),
+        _ => false,
+    };
+    if revoked {
+        return Err(AuthFailure::new("Token revoked", "revoked"));
+    }
+    Ok(claims)
+}
+
+/// Logs a rejected token with its specific reason when `LOG_AUTH_FAILURES` is on.
+pub fn log_auth_failure(state: Option<&AppState>, req: &HttpRequest, failure: &AuthFailure) {
+    if !state.is_some_and(|state| state.config.log_auth_failures) {
+        return;
+    }
+    eprintln!(
+        "Auth failure: {} {} ({}) request_id={}",
+        req.method(),
+        req.path(),
+        failure.reason,
+        ConnectionInfo::from_request(req).request_id
+    );
+}
+
+/// User-token check for ws upgrades, which run outside the bearer-protected scope: the
+/// `Authorization` header if present, else the `?token=` that browsers have to use.
+pub fn ws_claims(
+    req: &HttpRequest,
+    bearer: Option<&BearerAuth>,
+    query_token: Option<&str>,
+) -> Result<Claims, HttpResponse> {
+    let cookie = req.cookie(TOKEN_COOKIE);
+    let token = bearer
+        .map(BearerAuth::token)
+        .or(query_token)
+        .or(cookie.as_ref().map(|cookie| cookie.value()));
+    let Some(token) = token else {
+        return Err(HttpResponse::Unauthorized().body("Missing credentials"));
+    };
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .map(|state| state.get_ref());
+    authenticate_user(state, token).map_err(|failure| {
+        log_auth_failure(state, req, &failure);
+        HttpResponse::Unauthorized().body(failure.message)
+    })
+}
+
+/// Bearer middleware check: the `Authorization` header, or the `TOKEN_COOKIE` without one.
 pub async fn validator(
     req: ServiceRequest,
-    credentials: BearerAuth,
+    credentials: Option<BearerAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    // Modified return type
-    match validate_jwt(credentials.token()) {
-        Ok(_claims) => Ok(req),
-        Err(_) => Err((actix_web::error::ErrorUnauthorized("Invalid token"), req)), // Modified error return
+    let cookie = req.cookie(TOKEN_COOKIE);
+    let token = match (&credentials, &cookie) {
+        (Some(credentials), _) => credentials.token(),
+        (None, Some(cookie)) => cookie.value(),
+        (None, None) => {
+            return Err((
+                actix_web::error::ErrorUnauthorized("Missing credentials"),
+                req,
+            ))
+        }
+    };
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .map(|state| state.get_ref());
+    match authenticate_user(state, token) {
+        Ok(claims) => {
+            req.extensions_mut().insert(claims);
+            Ok(req)
+        }
+        Err(failure) => {
+            log_auth_failure(state, req.request(), &failure);
+            Err((actix_web::error::ErrorUnauthorized(failure.message), req))
+        }
+    }
+}
+
+/// JWKS document with the RS256 verification key; 404 when tokens are HMAC-signed.
+#[utoipa::path(
+    responses(
+        (status = 200, description = "JSON Web Key Set"),
+        (status = 404, description = "Not using RS256"),
+    )
+)]
+#[get("/.well-known/jwks.json")]
+pub async fn jwks() -> impl Responder {
+    match &keys().jwk {
+        Some(jwk) => HttpResponse::Ok().json(serde_json::json!({ "keys": [jwk] })),
+        None => HttpResponse::NotFound().body("JWKS is only available with JWT_ALG=RS256"),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ValidateTokenRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ValidateTokenResponse {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    /// Why the token was rejected, e.g. `expired` or `invalid signature`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Checks a user token (from the body or the `Authorization` header) exactly as the bearer
+/// middleware would, without side effects.
+#[utoipa::path(
+    request_body(content = Option<ValidateTokenRequest>),
+    responses(
+        (status = 200, body = ValidateTokenResponse),
+        (status = 400, description = "No token supplied"),
+    )
+)]
+#[post("/auth/validate")]
+pub async fn validate_token(
+    req: HttpRequest,
+    OptionalJson(body): OptionalJson<ValidateTokenRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let header_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let Some(token) = body.map(|body| body.token).or(header_token) else {
+        return HttpResponse::BadRequest().body("No token supplied");
+    };
+
+    if token.len() > state.config.max_jwt_bytes {
+        return HttpResponse::Ok().json(ValidateTokenResponse {
+            valid: false,
+            sub: None,
+            exp: None,
+            reason: Some("too large".to_string()),
+        });
+    }
+    let response = match validate_jwt(&token) {
+        Ok(claims) => {
+            let revoked = claims
+                .jti
+                .as_deref()
+                .is_some_and(|jti| state.tokens.is_revoked(jti));
+            ValidateTokenResponse {
+                valid: !revoked,
+                sub: Some(claims.sub),
+                exp: Some(claims.exp),
+                reason: revoked.then(|| "revoked".to_string()),
+            }
+        }
+        Err(err) => ValidateTokenResponse {
+            valid: false,
+            sub: None,
+            exp: None,
+            reason: Some(rejection_reason(&err)),
+        },
+    };
+    HttpResponse::Ok().json(response)
+}
+
+fn rejection_reason(err: &jsonwebtoken::errors::Error) -> String {
+    use jsonwebtoken::errors::ErrorKind;
+    match err.kind() {
+        ErrorKind::ExpiredSignature => "expired".to_string(),
+        ErrorKind::ImmatureSignature => "not yet valid".to_string(),
+        ErrorKind::InvalidSignature => "invalid signature".to_string(),
+        ErrorKind::InvalidAudience => "invalid audience".to_string(),
+        ErrorKind::InvalidIssuer => "invalid issuer".to_string(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("missing {}", claim),
+        ErrorKind::InvalidAlgorithm => "invalid algorithm".to_string(),
+        ErrorKind::InvalidSubject => "invalid subject".to_string(),
+        ErrorKind::InvalidToken
+        | ErrorKind::Base64(_)
+        | ErrorKind::Json(_)
+        | ErrorKind::Utf8(_) => "malformed token".to_string(),
+        _ => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::Role;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use jsonwebtoken::errors::ErrorKind;
+    use std::collections::HashMap;
+
+    fn user() -> User {
+        User {
+            username: "alice".to_string(),
+            password_hash: String::new(),
+            role: Role::User,
+            scopes: Vec::new(),
+            tenant: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_tokens_are_refused_before_decoding() {
+        let (token, _) = create_jwt(&user());
+        let config = Config {
+            max_jwt_bytes: token.len() - 1,
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let failure = authenticate_user(Some(&state), &token).unwrap_err();
+        assert_eq!(failure.message, "Token too large");
+        assert!(authenticate_user(None, &token).is_ok());
+
+        let app = init_service(
+            App::new().app_data(state).service(
+                web::scope("")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn tokens_without_a_subject_are_refused() {
+        let keys = JwtKeys::hs256("test-secret");
+        let mut claims = keys.user_claims(&user());
+        assert!(keys.validate_user_token(&keys.issue(&claims)).is_ok());
+        claims.sub = String::new();
+        let err = keys.validate_user_token(&keys.issue(&claims)).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidSubject);
+    }
+
+    fn keys_for(issuer: Option<&str>, audience: Option<&str>) -> JwtKeys {
+        JwtKeys {
+            issuer: issuer.map(str::to_string),
+            audience: audience.map(str::to_string),
+            ..JwtKeys::hs256("test-secret")
+        }
+    }
+
+    #[test]
+    fn issuer_and_audience_must_match() {
+        let ours = keys_for(Some("fer_net"), Some("dashboard"));
+        let claims = ours.user_claims(&user());
+        assert_eq!(claims.iss.as_deref(), Some("fer_net"));
+        assert_eq!(claims.aud.as_deref(), Some("dashboard"));
+        assert!(ours.validate_user_token(&ours.issue(&claims)).is_ok());
+
+        // Same key, but minted for another service or by another issuer.
+        for (issuer, audience, kind) in [
+            (Some("other"), Some("dashboard"), ErrorKind::InvalidIssuer),
+            (Some("fer_net"), Some("billing"), ErrorKind::InvalidAudience),
+            (
+                None,
+                Some("dashboard"),
+                ErrorKind::MissingRequiredClaim("iss".to_string()),
+            ),
+            (
+                Some("fer_net"),
+                None,
+                ErrorKind::MissingRequiredClaim("aud".to_string()),
+            ),
+        ] {
+            let theirs = keys_for(issuer, audience);
+            let token = theirs.issue(&theirs.user_claims(&user()));
+            let err = ours.validate_user_token(&token).unwrap_err();
+            assert_eq!(*err.kind(), kind, "iss={:?} aud={:?}", issuer, audience);
+        }
+    }
+
+    #[test]
+    fn user_and_node_tokens_are_not_interchangeable() {
+        let keys = keys_for(Some("fer_net"), Some("dashboard"));
+        let node_id = Uuid::new_v4();
+        let nonce = Uuid::new_v4();
+        let node_token = keys.issue(&keys.node_claims(&node_id, &nonce));
+        assert_eq!(
+            keys.validate_node_token(&node_token).unwrap(),
+            NodeToken { node_id, nonce }
+        );
+        let err = keys.validate_user_token(&node_token).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidAudience);
+
+        let user_token = keys.issue(&keys.user_claims(&user()));
+        let err = keys.validate_node_token(&user_token).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidAudience);
+
+        // A node token from another issuer is refused too.
+        let theirs = keys_for(Some("other"), None);
+        let token = theirs.issue(&theirs.node_claims(&node_id, &nonce));
+        let err = keys.validate_node_token(&token).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidIssuer);
     }
 }
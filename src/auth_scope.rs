@@ -0,0 +1,225 @@
+use crate::models::{Claims, Role};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::{self, Either, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+/// Per-service authorization, checked against the `Claims` that `auth::validator` stores in
+/// the request extensions, so it only works inside the bearer-authenticated scope:
+///
+/// `#[get("/admin/sessions", wrap = "AuthScope::role(Role::Admin)")]`
+/// `#[get("/stats", wrap = "AuthScope::scope(\"stats:read\")")]`
+///
+/// Callers without the role or scope get 403 before the handler runs.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthScope {
+    requirement: Requirement,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Requirement {
+    Role(Role),
+    /// An entry of the token's `scopes`.
+    Scope(&'static str),
+}
+
+impl AuthScope {
+    pub fn role(role: Role) -> Self {
+        AuthScope {
+            requirement: Requirement::Role(role),
+        }
+    }
+
+    pub fn scope(scope: &'static str) -> Self {
+        AuthScope {
+            requirement: Requirement::Scope(scope),
+        }
+    }
+
+    /// `None` when `claims` satisfy the requirement, otherwise the 403 message. Admins satisfy
+    /// every role and scope.
+    fn rejection(&self, claims: &Claims) -> Option<String> {
+        if claims.is_admin() {
+            return None;
+        }
+        match self.requirement {
+            Requirement::Role(role) => {
+                (claims.role != Some(role)).then(|| format!("{} role required", role_name(role)))
+            }
+            Requirement::Scope(scope) => (!claims.scopes.iter().any(|s| s == scope))
+                .then(|| format!("{} scope required", scope)),
+        }
+    }
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Admin => "Admin",
+        Role::User => "User",
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(AuthScopeMiddleware {
+            service: Rc::new(service),
+            scope: *self,
+        })
+    }
+}
+
+pub struct AuthScopeMiddleware<S> {
+    service: Rc<S>,
+    scope: AuthScope,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Either<
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let rejection = match req.extensions().get::<Claims>() {
+            Some(claims) => self
+                .scope
+                .rejection(claims)
+                .map(|message| HttpResponse::Forbidden().body(message)),
+            None => Some(HttpResponse::Unauthorized().body("Missing credentials")),
+        };
+        if let Some(response) = rejection {
+            return Either::Right(future::ok(
+                req.into_response(response.map_into_right_body()),
+            ));
+        }
+        let service = self.service.clone();
+        Either::Left(Box::pin(async move {
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpRequest};
+
+    fn claims(role: Option<Role>, scopes: &[&str]) -> Claims {
+        Claims {
+            sub: "someone".to_string(),
+            exp: usize::MAX,
+            iat: None,
+            jti: None,
+            iss: None,
+            aud: None,
+            role,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            tenant: None,
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn role_requirement() {
+        let user_only = AuthScope::role(Role::User);
+        assert!(user_only
+            .rejection(&claims(Some(Role::User), &[]))
+            .is_none());
+        assert!(user_only
+            .rejection(&claims(Some(Role::Admin), &[]))
+            .is_none());
+        assert_eq!(
+            user_only.rejection(&claims(None, &[])).as_deref(),
+            Some("User role required")
+        );
+
+        let admin_only = AuthScope::role(Role::Admin);
+        assert!(admin_only
+            .rejection(&claims(Some(Role::Admin), &[]))
+            .is_none());
+        assert_eq!(
+            admin_only
+                .rejection(&claims(Some(Role::User), &["stats:read"]))
+                .as_deref(),
+            Some("Admin role required")
+        );
+    }
+
+    #[test]
+    fn scope_requirement() {
+        let stats = AuthScope::scope("stats:read");
+        assert!(stats
+            .rejection(&claims(Some(Role::User), &["edge", "stats:read"]))
+            .is_none());
+        assert!(stats.rejection(&claims(Some(Role::Admin), &[])).is_none());
+        assert_eq!(
+            stats
+                .rejection(&claims(Some(Role::User), &["stats"]))
+                .as_deref(),
+            Some("stats:read scope required")
+        );
+    }
+
+    async fn call(scope: AuthScope, claims: Option<Claims>) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    // Stands in for `auth::validator`.
+                    if let Some(claims) = claims.clone() {
+                        req.extensions_mut().insert(claims);
+                    }
+                    srv.call(req)
+                })
+                .route(
+                    "/",
+                    web::get().to(|_: HttpRequest| async { "ok" }).wrap(scope),
+                ),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri("/").to_request())
+            .await
+            .status()
+    }
+
+    #[actix_web::test]
+    async fn middleware_allows_and_forbids() {
+        let admin = AuthScope::role(Role::Admin);
+        let admin_claims = claims(Some(Role::Admin), &[]);
+        let user_claims = claims(Some(Role::User), &["stats:read"]);
+        assert_eq!(call(admin, Some(admin_claims)).await, StatusCode::OK);
+        assert_eq!(
+            call(admin, Some(user_claims.clone())).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call(admin, None).await, StatusCode::UNAUTHORIZED);
+
+        let stats = AuthScope::scope("stats:read");
+        assert_eq!(call(stats, Some(user_claims)).await, StatusCode::OK);
+        let no_scope = claims(Some(Role::User), &[]);
+        assert_eq!(call(stats, Some(no_scope)).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::sync::LockExt;
+use actix_web::web::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A serialized response reused until the data behind it changes. Writers call `invalidate`
+/// after each mutation, while still holding the data's lock, so a reader holding the same lock
+/// never serves bytes older than what it sees.
+#[derive(Default)]
+pub struct ResponseCache {
+    version: AtomicU64,
+    cached: Mutex<Option<(u64, Bytes)>>,
+}
+
+impl ResponseCache {
+    pub fn invalidate(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the cached bytes if still current, otherwise renders and caches them.
+    pub fn get_or_render(
+        &self,
+        render: impl FnOnce() -> serde_json::Result<Vec<u8>>,
+    ) -> serde_json::Result<Bytes> {
+        let version = self.version.load(Ordering::SeqCst);
+        let mut cached = self.cached.lock_or_recover();
+        if let Some((cached_version, bytes)) = cached.as_ref() {
+            if *cached_version == version {
+                return Ok(bytes.clone());
+            }
+        }
+        let bytes = Bytes::from(render()?);
+        *cached = Some((version, bytes.clone()));
+        Ok(bytes)
+    }
+}
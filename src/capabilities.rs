@@ -0,0 +1,39 @@
+/// Newest ws protocol version this server speaks. Nodes that send no `protocol_version`
+/// are treated as version 1, the format before versioning.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Lets nodes exchange `Broadcast`/`SendTo` payloads through the hub.
+pub const RELAY: &str = "relay";
+
+/// After `Authenticated`, either side may send raw-DEFLATE-compressed JSON as binary frames
+/// (text frames stay plain JSON). Stands in for `permessage-deflate`, which actix-web-actors
+/// can't negotiate.
+pub const COMPRESSION: &str = "compression";
+
+/// Capability names the protocol defines, whether or not this server implements them yet.
+const KNOWN: &[&str] = &["binary", COMPRESSION, RELAY];
+
+/// Capabilities this server implements.
+const SUPPORTED: &[&str] = &[RELAY, COMPRESSION];
+
+/// Enabled for nodes that declare nothing (older clients). Opt-in features aren't.
+const DEFAULTS: &[&str] = &[RELAY];
+
+/// Returns the capabilities enabled for a node: those it declared that the server supports.
+/// Nodes that declare nothing get `DEFAULTS`. Unknown names are ignored with a warning
+/// rather than failing auth.
+pub fn negotiate(declared: Option<&[String]>) -> Vec<String> {
+    let Some(declared) = declared else {
+        return DEFAULTS.iter().map(|name| name.to_string()).collect();
+    };
+    for name in declared {
+        if !KNOWN.contains(&name.as_str()) {
+            eprintln!("Ignoring unknown node capability {:?}", name);
+        }
+    }
+    SUPPORTED
+        .iter()
+        .filter(|name| declared.iter().any(|declared| declared == *name))
+        .map(|name| name.to_string())
+        .collect()
+}
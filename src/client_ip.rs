@@ -0,0 +1,52 @@
+use crate::state::AppState;
+use actix_web::{web, HttpRequest};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// The caller's address. `X-Forwarded-For` is only honoured through hops in `TRUSTED_PROXIES`:
+/// the chain is walked right to left from the direct peer, stopping at the first untrusted hop.
+pub fn real_client_ip(req: &HttpRequest) -> IpAddr {
+    let peer = req
+        .peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let trusted = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => &state.config.trusted_proxies,
+        None => return peer,
+    };
+    if !is_trusted(trusted, &peer) {
+        return peer;
+    }
+
+    let hops: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        client = hop;
+        if !is_trusted(trusted, &hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// False for `/admin/*` requests from outside a non-empty `ADMIN_IP_ALLOWLIST`.
+pub fn admin_ip_allowed(req: &HttpRequest) -> bool {
+    if !req.path().starts_with("/admin/") {
+        return true;
+    }
+    let allowlist = match req.app_data::<web::Data<AppState>>() {
+        Some(state) => &state.config.admin_ip_allowlist,
+        None => return true,
+    };
+    allowlist.is_empty() || is_trusted(allowlist, &real_client_ip(req))
+}
+
+fn is_trusted(trusted: &[IpNet], ip: &IpAddr) -> bool {
+    trusted.iter().any(|net| net.contains(ip))
+}
@@ -0,0 +1,109 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+/// Outbound messages shorter than this go uncompressed; deflate only grows tiny JSON.
+pub const MIN_COMPRESS_LEN: usize = 256;
+
+/// Raw DEFLATE (no zlib header), as used for ws frames on sessions with the
+/// `compression` capability.
+///
+/// Each message is compressed on its own, without a shared window, so the gain depends on
+/// message size. As measured in `measured_savings`: a flow of 1000 `SetAddress` messages
+/// (~100 bytes each) shrinks by only about 10%, and their `AddressUpdated` replies stay
+/// plain under `MIN_COMPRESS_LEN`. Large replies are where it pays off: a 100-node `Peers`
+/// list goes from about 19.7 KB to 1.5 KB.
+pub fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Inverse of `deflate`. Stops at `max_len` bytes of output (the same cap as uncompressed
+/// messages, `Config::ws_max_message_bytes`), so a tiny frame can't expand without bound;
+/// longer output is a `FileTooLarge` error.
+pub fn inflate(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(data)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut out)?;
+    if out.len() > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            "inflated message too large",
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_address(i: u32) -> String {
+        format!(
+            r#"{{"type":"SetAddress","ip":"10.{}.{}.{}","port":{},"expected_version":{},"request_id":"req-{}"}}"#,
+            i % 256,
+            i / 7 % 256,
+            i * 13 % 256,
+            20000 + i,
+            i,
+            i
+        )
+    }
+
+    fn peers(count: u32) -> String {
+        let nodes: Vec<String> = (0..count)
+            .map(|i| {
+                format!(
+                    r#"{{"id":"{:08x}-1111-4111-8111-111111111111","name":"node-{:08x}","ip":"10.0.{}.{}","port":{},"status":"Healthy","tags":["edge","eu-west"],"pool":"edge","capabilities":["relay","compression"]}}"#,
+                    i * 7919,
+                    i * 7919,
+                    i / 256,
+                    i % 256,
+                    30000 + i
+                )
+            })
+            .collect();
+        format!(r#"{{"type":"Peers","nodes":[{}]}}"#, nodes.join(","))
+    }
+
+    #[test]
+    fn round_trips() {
+        let message = peers(10);
+        let compressed = deflate(message.as_bytes()).unwrap();
+        let inflated = inflate(&compressed, message.len()).unwrap();
+        assert_eq!(inflated, message.as_bytes());
+    }
+
+    #[test]
+    fn inflate_stops_at_max_len() {
+        let bomb = deflate(&vec![b'a'; 1024 * 1024]).unwrap();
+        assert!(bomb.len() < 2048);
+        let err = inflate(&bomb, 64 * 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(inflate(&bomb, 1024 * 1024).unwrap().len(), 1024 * 1024);
+    }
+
+    /// The figures quoted on `deflate`.
+    #[test]
+    fn measured_savings() {
+        let (mut plain, mut compressed) = (0, 0);
+        for i in 0..1000 {
+            let message = set_address(i);
+            plain += message.len();
+            compressed += deflate(message.as_bytes()).unwrap().len();
+        }
+        let ratio = compressed as f64 / plain as f64;
+        assert!(
+            (0.8..0.95).contains(&ratio),
+            "SetAddress flow ratio {}",
+            ratio
+        );
+
+        let message = peers(100);
+        let ratio = deflate(message.as_bytes()).unwrap().len() as f64 / message.len() as f64;
+        assert!(ratio < 0.1, "Peers ratio {}", ratio);
+    }
+}
@@ -0,0 +1,666 @@
+use crate::capabilities::PROTOCOL_VERSION;
+use crate::json_case::JsonCase;
+use crate::password::PasswordHashAlgorithm;
+use actix_web::http::header::HeaderValue;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Shorter pre-shared keys make a captured signature too easy to brute-force.
+const MIN_REGISTRATION_PSK_BYTES: usize = 32;
+
+/// What `GET /` serves (`ROOT_RESPONSE=html|json|none`): the HTML status page, a JSON
+/// summary, or a 404.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootResponse {
+    #[default]
+    Html,
+    Json,
+    None,
+}
+
+impl std::str::FromStr for RootResponse {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "html" => Ok(RootResponse::Html),
+            "json" => Ok(RootResponse::Json),
+            "none" => Ok(RootResponse::None),
+            other => Err(format!("unknown root response {:?}", other)),
+        }
+    }
+}
+
+/// Server settings read once at startup. TLS and JWT key material stay with `tls` and `auth`.
+///
+/// Values come from an optional TOML file (`--config path.toml` or `CONFIG_FILE`) whose keys
+/// are the lowercase env var names; env vars override the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Interfaces to listen on with `port`; `BIND_ADDR` takes a comma-separated list.
+    #[serde(rename = "bind_addr")]
+    pub bind_addrs: Vec<IpAddr>,
+    /// Listen on this Unix socket instead of TCP (`bind_addrs`/`port` are then ignored).
+    pub bind_uds: Option<PathBuf>,
+    pub port: u16,
+    /// Initial registration API key; see `AppState::api_keys` for the live value.
+    pub api_key: String,
+    /// `REGISTRATION_ENABLED=false` locks down `/register` after provisioning.
+    /// Already-registered nodes can still authenticate.
+    pub registration_enabled: bool,
+    /// Start in maintenance mode: 503 for everything but `maintenance::EXEMPT_PATHS`.
+    pub maintenance_mode: bool,
+    /// Whether switching maintenance on closes existing ws sessions (unless the request says
+    /// otherwise) or only refuses new ones.
+    pub maintenance_drain_sessions: bool,
+    /// Have `/register` generate node ids instead of taking them from the client; a request
+    /// can override this with `?server_assigned=`.
+    pub server_assigned_ids: bool,
+    /// `None` means unlimited.
+    pub max_registered_nodes: Option<usize>,
+    /// Registrations lapse this long after they were made or last renewed by re-registering.
+    /// Zero (the default) keeps them until deregistered.
+    #[serde(rename = "registration_ttl_secs", deserialize_with = "secs")]
+    pub registration_ttl: Duration,
+    /// `None` means unlimited.
+    pub max_active_nodes: Option<usize>,
+    /// Cap on open ws connections, authenticated or not. `None` means unlimited.
+    pub max_ws_connections: Option<usize>,
+    pub ws_messages_per_sec: f64,
+    pub ws_message_burst: f64,
+    /// Separate, tighter limit for ws `Broadcast` relays.
+    pub ws_broadcasts_per_sec: f64,
+    pub ws_broadcast_burst: f64,
+    pub snapshot_path: Option<PathBuf>,
+    /// JSON-lines file that `audit::AuditEvent`s are appended to. `None` disables auditing.
+    pub audit_log_path: Option<PathBuf>,
+    /// Proxies (CIDRs) whose `X-Forwarded-For` entries are believed. Empty trusts nobody.
+    pub trusted_proxies: Vec<IpNet>,
+    /// When non-empty, `/admin/*` is only reachable from these CIDRs (after trusted-proxy
+    /// resolution), on top of the admin token check.
+    pub admin_ip_allowlist: Vec<IpNet>,
+    /// Password checks `/login` runs at once, each on the blocking pool. Defaults to the
+    /// number of CPUs.
+    pub login_max_concurrent: usize,
+    /// How long a login waits for a free slot before getting 503.
+    #[serde(rename = "login_queue_ms", deserialize_with = "millis")]
+    pub login_queue_timeout: Duration,
+    /// Algorithm for newly hashed user passwords; either kind verifies.
+    pub password_hash: PasswordHashAlgorithm,
+    /// Key naming of JSON responses; see `JsonCase`.
+    pub json_case: JsonCase,
+    pub root_response: RootResponse,
+    /// Whether JSON bodies must come with `Content-Type: application/json` (or `+json`);
+    /// others get 415. Turn it off for legacy clients that can't set the header: bodies are
+    /// then parsed as JSON whatever their type.
+    pub json_content_type_required: bool,
+    /// Security headers added to every response (unless a handler set them); see
+    /// `security_headers`. An empty value turns that header off.
+    pub header_nosniff: bool,
+    pub header_frame_options: String,
+    pub header_referrer_policy: String,
+    /// Only sent when TLS is on.
+    pub header_hsts: String,
+    /// `Content-Security-Policy` of the HTML index page.
+    pub index_csp: String,
+    #[serde(rename = "snapshot_interval_secs", deserialize_with = "secs")]
+    pub snapshot_interval: Duration,
+    /// How often nodes are expected to check in; also the `Retry-After` hint when none is available.
+    #[serde(rename = "heartbeat_interval_secs", deserialize_with = "secs")]
+    pub heartbeat_interval: Duration,
+    /// Active nodes without a ws session (HTTP heartbeat users) are dropped once they haven't
+    /// checked in for this long; see `sweep::run`. Zero keeps them until deregistered.
+    #[serde(rename = "http_node_timeout_secs", deserialize_with = "secs")]
+    pub http_node_timeout: Duration,
+    /// How often `probe::run` TCP-connects to each active node's advertised address.
+    /// Zero (the default) disables probing.
+    #[serde(rename = "probe_interval_secs", deserialize_with = "secs")]
+    pub probe_interval: Duration,
+    /// Consecutive failed probes before a node is marked `Unhealthy`.
+    pub probe_failure_threshold: u32,
+    /// Authenticated ws sessions sending no application messages for this long are closed,
+    /// even if they still answer pings. Zero disables the check.
+    #[serde(rename = "ws_inactivity_timeout_secs", deserialize_with = "secs")]
+    pub ws_inactivity_timeout: Duration,
+    /// Requests taking at least this long are logged as slow. Zero disables the warning.
+    #[serde(rename = "slow_request_ms", deserialize_with = "millis")]
+    pub slow_request_threshold: Duration,
+    /// Minimum gap between accepted address updates for one node, over ws `SetAddress` or
+    /// HTTP, across reconnects; faster ones are refused. Zero disables the limit.
+    #[serde(rename = "address_update_min_interval_ms", deserialize_with = "millis")]
+    pub address_update_min_interval: Duration,
+    /// Oldest ws `protocol_version` still accepted on `Auth`; raise it to retire old formats.
+    pub ws_protocol_min: u32,
+    /// Newest ws `protocol_version` accepted, at most `capabilities::PROTOCOL_VERSION`.
+    /// Nodes on older (but still accepted) versions are logged as deprecated.
+    pub ws_protocol_max: u32,
+    /// Largest ws message accepted, whether sent as one frame or reassembled from fragments.
+    pub ws_max_message_bytes: usize,
+    /// Failed ws password `Auth` attempts for one node id before it is banned; 0 disables.
+    pub node_auth_max_failures: u32,
+    /// How long a node id stays banned, which is also the window failures are counted in.
+    #[serde(rename = "node_auth_ban_secs", deserialize_with = "secs")]
+    pub node_auth_ban: Duration,
+    /// Bearer tokens longer than this are rejected before any decoding.
+    pub max_jwt_bytes: usize,
+    /// Log why each bearer token was rejected (expired, bad signature, revoked, ...) with the
+    /// request id and path. Clients only ever see a generic 401.
+    pub log_auth_failures: bool,
+    /// Dev-only JSON file of users and registered nodes inserted at startup; see `seed::load`.
+    pub seed_file: Option<PathBuf>,
+    /// One-time secret for `POST /users/bootstrap`, which creates the first admin while no
+    /// users exist. Together with `seed_file`, the only way to get an admin.
+    pub bootstrap_token: Option<String>,
+    /// Pre-shared key for `POST /register/signed`; see `auth::SignedRegistration`. Unset
+    /// disables signed registration.
+    pub registration_psk: Option<String>,
+    /// Register/join/leave events are POSTed here, signed with `webhook_secret`.
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    /// Deliveries still failing after this many tries are dropped.
+    pub webhook_max_attempts: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addrs: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            bind_uds: None,
+            port: 8000,
+            api_key: String::new(),
+            registration_enabled: true,
+            maintenance_mode: false,
+            maintenance_drain_sessions: false,
+            server_assigned_ids: false,
+            max_registered_nodes: None,
+            registration_ttl: Duration::ZERO,
+            max_active_nodes: None,
+            max_ws_connections: None,
+            ws_messages_per_sec: 10.0,
+            ws_message_burst: 20.0,
+            ws_broadcasts_per_sec: 1.0,
+            ws_broadcast_burst: 5.0,
+            snapshot_path: None,
+            audit_log_path: None,
+            trusted_proxies: Vec::new(),
+            admin_ip_allowlist: Vec::new(),
+            login_max_concurrent: std::thread::available_parallelism().map_or(4, usize::from),
+            login_queue_timeout: Duration::from_millis(1000),
+            password_hash: PasswordHashAlgorithm::default(),
+            json_case: JsonCase::default(),
+            root_response: RootResponse::default(),
+            json_content_type_required: true,
+            header_nosniff: true,
+            header_frame_options: "DENY".to_string(),
+            header_referrer_policy: "no-referrer".to_string(),
+            header_hsts: "max-age=31536000".to_string(),
+            index_csp: "default-src 'none'; style-src 'unsafe-inline'; base-uri 'none'; \
+                        form-action 'none'; frame-ancestors 'none'"
+                .to_string(),
+            snapshot_interval: Duration::from_secs(60),
+            heartbeat_interval: Duration::from_secs(30),
+            http_node_timeout: Duration::from_secs(90),
+            probe_interval: Duration::ZERO,
+            probe_failure_threshold: 3,
+            ws_inactivity_timeout: Duration::from_secs(300),
+            slow_request_threshold: Duration::from_millis(1000),
+            address_update_min_interval: Duration::from_millis(1000),
+            ws_protocol_min: 1,
+            ws_protocol_max: PROTOCOL_VERSION,
+            ws_max_message_bytes: 64 * 1024,
+            node_auth_max_failures: 5,
+            node_auth_ban: Duration::from_secs(300),
+            max_jwt_bytes: 8192,
+            log_auth_failures: false,
+            seed_file: None,
+            bootstrap_token: None,
+            registration_psk: None,
+            webhook_url: None,
+            webhook_secret: None,
+            webhook_max_attempts: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named on the command line or in `CONFIG_FILE` (if any),
+    /// applies env overrides and validates the result.
+    pub fn load() -> io::Result<Self> {
+        let mut config = match config_file() {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        toml::from_str(&text).map_err(|err| invalid(&format!("{}: {}", path.display(), err)))
+    }
+
+    /// Overrides fields with any env vars that are set. An unparsable value is a startup
+    /// error naming the variable.
+    fn apply_env(&mut self) -> io::Result<()> {
+        if let Ok(value) = env::var("BIND_ADDR") {
+            self.bind_addrs = value
+                .split(',')
+                .map(|addr| {
+                    addr.trim()
+                        .parse()
+                        .map_err(|_| invalid(&format!("BIND_ADDR: invalid address {:?}", addr)))
+                })
+                .collect::<io::Result<_>>()?;
+        }
+        env_override_opt("BIND_UDS", &mut self.bind_uds)?;
+        env_override("PORT", &mut self.port)?;
+        env_override("API_KEY", &mut self.api_key)?;
+        env_override("REGISTRATION_ENABLED", &mut self.registration_enabled)?;
+        env_override("MAINTENANCE_MODE", &mut self.maintenance_mode)?;
+        env_override(
+            "MAINTENANCE_DRAIN_SESSIONS",
+            &mut self.maintenance_drain_sessions,
+        )?;
+        env_override("SERVER_ASSIGNED_IDS", &mut self.server_assigned_ids)?;
+        env_override_opt("MAX_REGISTERED_NODES", &mut self.max_registered_nodes)?;
+        if let Some(secs) = env_opt("REGISTRATION_TTL_SECS")? {
+            self.registration_ttl = Duration::from_secs(secs);
+        }
+        env_override_opt("MAX_ACTIVE_NODES", &mut self.max_active_nodes)?;
+        env_override_opt("MAX_WS_CONNECTIONS", &mut self.max_ws_connections)?;
+        env_override("WS_MESSAGES_PER_SEC", &mut self.ws_messages_per_sec)?;
+        env_override("WS_MESSAGE_BURST", &mut self.ws_message_burst)?;
+        env_override("WS_BROADCASTS_PER_SEC", &mut self.ws_broadcasts_per_sec)?;
+        env_override("WS_BROADCAST_BURST", &mut self.ws_broadcast_burst)?;
+        env_override_opt("SNAPSHOT_PATH", &mut self.snapshot_path)?;
+        env_override_opt("AUDIT_LOG_PATH", &mut self.audit_log_path)?;
+        env_override("PASSWORD_HASH", &mut self.password_hash)?;
+        env_override("LOGIN_MAX_CONCURRENT", &mut self.login_max_concurrent)?;
+        if let Some(ms) = env_opt("LOGIN_QUEUE_MS")? {
+            self.login_queue_timeout = Duration::from_millis(ms);
+        }
+        env_override("JSON_CASE", &mut self.json_case)?;
+        env_override("ROOT_RESPONSE", &mut self.root_response)?;
+        env_override(
+            "JSON_CONTENT_TYPE_REQUIRED",
+            &mut self.json_content_type_required,
+        )?;
+        env_override("HEADER_NOSNIFF", &mut self.header_nosniff)?;
+        env_override("HEADER_FRAME_OPTIONS", &mut self.header_frame_options)?;
+        env_override("HEADER_REFERRER_POLICY", &mut self.header_referrer_policy)?;
+        env_override("HEADER_HSTS", &mut self.header_hsts)?;
+        env_override("INDEX_CSP", &mut self.index_csp)?;
+        if let Ok(value) = env::var("TRUSTED_PROXIES") {
+            self.trusted_proxies = parse_nets("TRUSTED_PROXIES", &value)?;
+        }
+        if let Ok(value) = env::var("ADMIN_IP_ALLOWLIST") {
+            self.admin_ip_allowlist = parse_nets("ADMIN_IP_ALLOWLIST", &value)?;
+        }
+        if let Some(secs) = env_opt("SNAPSHOT_INTERVAL_SECS")? {
+            self.snapshot_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_opt("HEARTBEAT_INTERVAL_SECS")? {
+            self.heartbeat_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_opt("HTTP_NODE_TIMEOUT_SECS")? {
+            self.http_node_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_opt("PROBE_INTERVAL_SECS")? {
+            self.probe_interval = Duration::from_secs(secs);
+        }
+        env_override("PROBE_FAILURE_THRESHOLD", &mut self.probe_failure_threshold)?;
+        if let Some(secs) = env_opt("WS_INACTIVITY_TIMEOUT_SECS")? {
+            self.ws_inactivity_timeout = Duration::from_secs(secs);
+        }
+        if let Some(ms) = env_opt("SLOW_REQUEST_MS")? {
+            self.slow_request_threshold = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_opt("ADDRESS_UPDATE_MIN_INTERVAL_MS")? {
+            self.address_update_min_interval = Duration::from_millis(ms);
+        }
+        env_override("WS_PROTOCOL_MIN", &mut self.ws_protocol_min)?;
+        env_override("WS_PROTOCOL_MAX", &mut self.ws_protocol_max)?;
+        env_override("WS_MAX_MESSAGE_BYTES", &mut self.ws_max_message_bytes)?;
+        env_override("NODE_AUTH_MAX_FAILURES", &mut self.node_auth_max_failures)?;
+        if let Some(secs) = env_opt("NODE_AUTH_BAN_SECS")? {
+            self.node_auth_ban = Duration::from_secs(secs);
+        }
+        env_override("MAX_JWT_BYTES", &mut self.max_jwt_bytes)?;
+        env_override("LOG_AUTH_FAILURES", &mut self.log_auth_failures)?;
+        env_override_opt("SEED_FILE", &mut self.seed_file)?;
+        env_override_opt("BOOTSTRAP_TOKEN", &mut self.bootstrap_token)?;
+        env_override_opt("REGISTRATION_PSK", &mut self.registration_psk)?;
+        env_override_opt("WEBHOOK_URL", &mut self.webhook_url)?;
+        env_override_opt("WEBHOOK_SECRET", &mut self.webhook_secret)?;
+        env_override("WEBHOOK_MAX_ATTEMPTS", &mut self.webhook_max_attempts)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if self.bind_addrs.is_empty() {
+            return Err(invalid("BIND_ADDR must list at least one address"));
+        }
+        if cfg!(not(unix)) && self.bind_uds.is_some() {
+            return Err(invalid("BIND_UDS is only supported on Unix"));
+        }
+        if self.ws_messages_per_sec <= 0.0 {
+            return Err(invalid("WS_MESSAGES_PER_SEC must be positive"));
+        }
+        if self.ws_message_burst < 1.0 {
+            return Err(invalid("WS_MESSAGE_BURST must be at least 1"));
+        }
+        if self.ws_broadcasts_per_sec <= 0.0 {
+            return Err(invalid("WS_BROADCASTS_PER_SEC must be positive"));
+        }
+        if self.ws_broadcast_burst < 1.0 {
+            return Err(invalid("WS_BROADCAST_BURST must be at least 1"));
+        }
+        if self.snapshot_interval.is_zero() {
+            return Err(invalid("SNAPSHOT_INTERVAL_SECS must be positive"));
+        }
+        if self.heartbeat_interval.is_zero() {
+            return Err(invalid("HEARTBEAT_INTERVAL_SECS must be positive"));
+        }
+        if !self.http_node_timeout.is_zero() && self.http_node_timeout <= self.heartbeat_interval {
+            return Err(invalid(
+                "HTTP_NODE_TIMEOUT_SECS must be 0 or longer than HEARTBEAT_INTERVAL_SECS",
+            ));
+        }
+        if self.ws_protocol_min == 0 || self.ws_protocol_min > self.ws_protocol_max {
+            return Err(invalid(
+                "WS_PROTOCOL_MIN must be between 1 and WS_PROTOCOL_MAX",
+            ));
+        }
+        if self.ws_protocol_max > PROTOCOL_VERSION {
+            return Err(invalid(&format!(
+                "WS_PROTOCOL_MAX must be at most {} (the newest version this server speaks)",
+                PROTOCOL_VERSION
+            )));
+        }
+        if self.probe_failure_threshold == 0 {
+            return Err(invalid("PROBE_FAILURE_THRESHOLD must be at least 1"));
+        }
+        if self.ws_max_message_bytes == 0 {
+            return Err(invalid("WS_MAX_MESSAGE_BYTES must be positive"));
+        }
+        for (var, value) in [
+            ("HEADER_FRAME_OPTIONS", &self.header_frame_options),
+            ("HEADER_REFERRER_POLICY", &self.header_referrer_policy),
+            ("HEADER_HSTS", &self.header_hsts),
+            ("INDEX_CSP", &self.index_csp),
+        ] {
+            if HeaderValue::from_str(value).is_err() {
+                return Err(invalid(&format!("{} is not a valid header value", var)));
+            }
+        }
+        if self
+            .registration_psk
+            .as_ref()
+            .is_some_and(|psk| psk.len() < MIN_REGISTRATION_PSK_BYTES)
+        {
+            return Err(invalid(&format!(
+                "REGISTRATION_PSK must be at least {} bytes",
+                MIN_REGISTRATION_PSK_BYTES
+            )));
+        }
+        if self.login_max_concurrent == 0 {
+            return Err(invalid("LOGIN_MAX_CONCURRENT must be at least 1"));
+        }
+        if self.node_auth_max_failures > 0 && self.node_auth_ban.is_zero() {
+            return Err(invalid(
+                "NODE_AUTH_BAN_SECS must be positive unless NODE_AUTH_MAX_FAILURES is 0",
+            ));
+        }
+        if self.max_jwt_bytes == 0 {
+            return Err(invalid("MAX_JWT_BYTES must be positive"));
+        }
+        if self.webhook_url.is_some() && self.webhook_secret.as_deref().is_none_or(str::is_empty) {
+            return Err(invalid("WEBHOOK_URL requires WEBHOOK_SECRET"));
+        }
+        if self.webhook_max_attempts == 0 {
+            return Err(invalid("WEBHOOK_MAX_ATTEMPTS must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// When a registration made now expires under `registration_ttl`; `None` if it never does.
+    pub fn registration_expiry(&self) -> Option<DateTime<Utc>> {
+        if self.registration_ttl.is_zero() {
+            return None;
+        }
+        let ttl = chrono::Duration::from_std(self.registration_ttl).ok()?;
+        Utc::now().checked_add_signed(ttl)
+    }
+
+    /// One-line `key=value` summary of the effective settings for the startup log.
+    /// Secrets are redacted.
+    pub fn summary(&self) -> String {
+        let limit = |limit: Option<usize>| limit.map_or("unlimited".to_string(), |n| n.to_string());
+        let listen = match &self.bind_uds {
+            Some(path) => format!("unix:{}", path.display()),
+            None => self
+                .listen_addrs()
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let trusted_proxies: Vec<String> =
+            self.trusted_proxies.iter().map(IpNet::to_string).collect();
+        let admin_ip_allowlist: Vec<String> = self
+            .admin_ip_allowlist
+            .iter()
+            .map(IpNet::to_string)
+            .collect();
+        format!(
+            "listen={} api_key={} registration_enabled={} maintenance_mode={} maintenance_drain_sessions={} server_assigned_ids={} max_registered_nodes={} registration_ttl_secs={} \
+             max_active_nodes={} max_ws_connections={} heartbeat_interval_secs={} http_node_timeout_secs={} probe_interval_secs={} probe_failure_threshold={} ws_inactivity_timeout_secs={} slow_request_ms={} address_update_min_interval_ms={} ws_protocol={}-{} ws_max_message_bytes={} \
+             ws_messages_per_sec={} ws_message_burst={} ws_broadcasts_per_sec={} \
+             ws_broadcast_burst={} node_auth_max_failures={} node_auth_ban_secs={} max_jwt_bytes={} log_auth_failures={} login_max_concurrent={} login_queue_ms={} password_hash={} json_case={} root_response={} json_content_type_required={} \
+             header_nosniff={} header_frame_options={:?} header_referrer_policy={:?} header_hsts={:?} index_csp={} trusted_proxies=[{}] admin_ip_allowlist=[{}] \
+             snapshot_path={} snapshot_interval_secs={} audit_log_path={} seed_file={} bootstrap_token={} registration_psk={} \
+             webhook={} webhook_max_attempts={}",
+            listen,
+            if self.api_key.is_empty() {
+                "(empty)"
+            } else {
+                "***"
+            },
+            self.registration_enabled,
+            self.maintenance_mode,
+            self.maintenance_drain_sessions,
+            self.server_assigned_ids,
+            limit(self.max_registered_nodes),
+            self.registration_ttl.as_secs(),
+            limit(self.max_active_nodes),
+            limit(self.max_ws_connections),
+            self.heartbeat_interval.as_secs(),
+            self.http_node_timeout.as_secs(),
+            self.probe_interval.as_secs(),
+            self.probe_failure_threshold,
+            self.ws_inactivity_timeout.as_secs(),
+            self.slow_request_threshold.as_millis(),
+            self.address_update_min_interval.as_millis(),
+            self.ws_protocol_min,
+            self.ws_protocol_max,
+            self.ws_max_message_bytes,
+            self.ws_messages_per_sec,
+            self.ws_message_burst,
+            self.ws_broadcasts_per_sec,
+            self.ws_broadcast_burst,
+            self.node_auth_max_failures,
+            self.node_auth_ban.as_secs(),
+            self.max_jwt_bytes,
+            self.log_auth_failures,
+            self.login_max_concurrent,
+            self.login_queue_timeout.as_millis(),
+            format!("{:?}", self.password_hash).to_lowercase(),
+            format!("{:?}", self.json_case).to_lowercase(),
+            format!("{:?}", self.root_response).to_lowercase(),
+            self.json_content_type_required,
+            self.header_nosniff,
+            self.header_frame_options,
+            self.header_referrer_policy,
+            self.header_hsts,
+            if self.index_csp.is_empty() { "off" } else { "on" },
+            trusted_proxies.join(","),
+            admin_ip_allowlist.join(","),
+            display_path(self.snapshot_path.as_deref()),
+            self.snapshot_interval.as_secs(),
+            display_path(self.audit_log_path.as_deref()),
+            display_path(self.seed_file.as_deref()),
+            if self.bootstrap_token.is_some() {
+                "***"
+            } else {
+                "none"
+            },
+            if self.registration_psk.is_some() {
+                "***"
+            } else {
+                "none"
+            },
+            // The URL itself may embed a token.
+            if self.webhook_url.is_some() { "on" } else { "off" },
+            self.webhook_max_attempts,
+        )
+    }
+
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.bind_addrs
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+}
+
+/// Checks `len` against an optional capacity limit.
+pub fn limit_reached(limit: Option<usize>, len: usize) -> bool {
+    limit.is_some_and(|max| len >= max)
+}
+
+/// `--config path`, `--config=path`, or else `CONFIG_FILE`.
+fn config_file() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var("CONFIG_FILE").ok().map(PathBuf::from)
+}
+
+/// Reads an optional typed setting; `None` when unset, an error naming the variable when
+/// set but unparsable.
+fn env_opt<T: std::str::FromStr>(var: &str) -> io::Result<Option<T>> {
+    match env::var(var) {
+        Ok(value) => parse_env(var, &value).map(Some),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(invalid(&format!("{}: not valid UTF-8", var))),
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(var: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(&format!("{}: invalid value {:?}", var, value)))
+}
+
+fn env_override<T: std::str::FromStr>(var: &str, field: &mut T) -> io::Result<()> {
+    if let Some(value) = env_opt(var)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+fn env_override_opt<T: std::str::FromStr>(var: &str, field: &mut Option<T>) -> io::Result<()> {
+    if let Some(value) = env_opt(var)? {
+        *field = Some(value);
+    }
+    Ok(())
+}
+
+/// Parses a comma-separated network list. Strict: silently dropping a typo would change who
+/// is trusted, and an allowlist left empty means no restriction at all.
+fn parse_nets(var: &str, value: &str) -> io::Result<Vec<IpNet>> {
+    value
+        .split(',')
+        .filter(|net| !net.trim().is_empty())
+        .map(|net| {
+            parse_net(net).ok_or_else(|| invalid(&format!("{}: invalid network {:?}", var, net)))
+        })
+        .collect()
+}
+
+/// Parses a CIDR, treating a bare address as a single-host network.
+fn parse_net(value: &str) -> Option<IpNet> {
+    let value = value.trim();
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+fn display_path(path: Option<&Path>) -> String {
+    path.map_or("none".to_string(), |path| path.display().to_string())
+}
+
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_env_names_the_variable_on_error() {
+        assert_eq!(parse_env::<u16>("PORT", "8080").unwrap(), 8080);
+        let err = parse_env::<u16>("PORT", "80800").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), r#"PORT: invalid value "80800""#);
+        assert!(parse_env::<bool>("REGISTRATION_ENABLED", "yes").is_err());
+    }
+
+    #[test]
+    fn parse_nets_rejects_any_bad_entry() {
+        let nets = parse_nets("TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1,").unwrap();
+        assert_eq!(nets.len(), 2);
+        assert!(nets[1].contains(&"127.0.0.1".parse::<IpAddr>().unwrap()));
+        let err = parse_nets("TRUSTED_PROXIES", "10.0.0.0/8,10.0.0.300").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"TRUSTED_PROXIES: invalid network "10.0.0.300""#
+        );
+    }
+
+    #[test]
+    fn limit_reached_at_the_limit() {
+        assert!(!limit_reached(Some(3), 2));
+        assert!(limit_reached(Some(3), 3));
+        assert!(limit_reached(Some(3), 4));
+        assert!(limit_reached(Some(0), 0));
+        assert!(!limit_reached(None, usize::MAX));
+    }
+}
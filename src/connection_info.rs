@@ -0,0 +1,57 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use serde::Serialize;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Longest header value kept; anything longer is truncated.
+const MAX_HEADER_LEN: usize = 256;
+
+/// A few headers from the ws upgrade request, kept to correlate a session with the client
+/// software that opened it. Only these whitelisted values are stored.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ConnectionInfo {
+    /// `X-Request-Id` from the upgrade request, or a generated one.
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(truncate)
+        };
+        ConnectionInfo {
+            request_id: header("X-Request-Id").unwrap_or_else(|| Uuid::new_v4().to_string()),
+            user_agent: header(header::USER_AGENT.as_str()),
+            origin: header(header::ORIGIN.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request_id={}", self.request_id)?;
+        if let Some(user_agent) = &self.user_agent {
+            write!(f, " user_agent={:?}", user_agent)?;
+        }
+        if let Some(origin) = &self.origin {
+            write!(f, " origin={:?}", origin)?;
+        }
+        Ok(())
+    }
+}
+
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(MAX_HEADER_LEN) {
+        Some((end, _)) => value[..end].to_string(),
+        None => value.to_string(),
+    }
+}
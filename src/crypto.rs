@@ -0,0 +1,69 @@
+use crate::api_key::constant_time_eq;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use uuid::Uuid;
+
+/// Hex HMAC-SHA256 of `data`, keyed with `key`. Signs webhooks and `SignedRegistration`s and
+/// derives their node passwords.
+pub fn hmac_sha256_hex(key: &str, data: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// What a registration keeps instead of the node's password: a random salt and the HMAC of
+/// the password keyed with it. A leaked record can't be replayed as the password.
+#[derive(Clone)]
+pub struct NodeSecret {
+    salt: String,
+    digest: String,
+}
+
+impl NodeSecret {
+    pub fn new(password: &str) -> Self {
+        let salt = Uuid::new_v4().simple().to_string();
+        let digest = hmac_sha256_hex(&salt, password.as_bytes());
+        NodeSecret { salt, digest }
+    }
+
+    /// Whether `password` is the one this was made from, in constant time.
+    pub fn matches(&self, password: &str) -> bool {
+        constant_time_eq(
+            &hmac_sha256_hex(&self.salt, password.as_bytes()),
+            &self.digest,
+        )
+    }
+}
+
+impl fmt::Debug for NodeSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NodeSecret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_secret_matches_only_its_password() {
+        let secret = NodeSecret::new("hunter22");
+        assert!(secret.matches("hunter22"));
+        assert!(!secret.matches("hunter23"));
+        assert!(!secret.matches(""));
+    }
+
+    #[test]
+    fn node_secret_keeps_no_password() {
+        let secret = NodeSecret::new("hunter22");
+        assert!(!secret.digest.contains("hunter22"));
+        assert_ne!(secret.digest, NodeSecret::new("hunter22").digest);
+        assert_eq!(format!("{:?}", secret), "NodeSecret(..)");
+    }
+}
@@ -1,21 +1,123 @@
-use crate::models::User;
-use bcrypt::{hash, DEFAULT_COST};
-use lazy_static::lazy_static;
+use crate::auth::NodeToken;
+use crate::models::{Role, User};
+use crate::password::{self, PasswordHasher};
+use crate::{RegisteredNode, RegisteredNodes};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use uuid::Uuid;
 
-pub type UserDB = Arc<Mutex<HashMap<String, User>>>;
+/// Login accounts keyed by username. Owned by `AppState` so each app instance has its own set.
+pub type UserStore = Arc<Mutex<HashMap<String, User>>>;
 
-lazy_static! {
-    pub static ref USERS: UserDB = Arc::new(Mutex::new(HashMap::new()));
-}
-
-pub async fn add_user(username: &str, password: &str) {
-    let hashed = hash(password, DEFAULT_COST).unwrap();
+pub async fn add_user(
+    users: &UserStore,
+    hasher: Arc<dyn PasswordHasher>,
+    username: &str,
+    password: &str,
+    role: Role,
+    scopes: Vec<String>,
+    tenant: Option<String>,
+) {
+    let hashed = password::hash_blocking(hasher, password.to_string())
+        .await
+        .unwrap();
     let user = User {
         username: username.to_string(),
         password_hash: hashed,
+        role,
+        scopes,
+        tenant,
     };
-    USERS.lock().await.insert(username.to_string(), user);
+    users.lock().await.insert(username.to_string(), user);
+}
+
+/// Looks up a registered node and checks its password, in constant time. Expired
+/// registrations are treated as absent.
+pub async fn verify_node(
+    reg_nodes: &RegisteredNodes,
+    id: &Uuid,
+    password: &str,
+) -> Option<RegisteredNode> {
+    let reg_nodes = reg_nodes.lock().await;
+    reg_nodes
+        .get(id)
+        .filter(|node| node.secret.matches(password) && !node.is_expired())
+        .cloned()
+}
+
+/// Looks up the registration a node token was issued for. Expired registrations, and
+/// later registrations of the same id, are treated as absent.
+pub async fn find_node(reg_nodes: &RegisteredNodes, token: &NodeToken) -> Option<RegisteredNode> {
+    reg_nodes
+        .lock()
+        .await
+        .get(&token.node_id)
+        .filter(|node| node.token_nonce == token.nonce && !node.is_expired())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn store(node: RegisteredNode) -> RegisteredNodes {
+        Arc::new(Mutex::new(HashMap::from([(node.id, node)])))
+    }
+
+    #[actix_web::test]
+    async fn verify_node_accepts_the_right_password() {
+        let id = Uuid::new_v4();
+        let reg_nodes = store(RegisteredNode::test(id, "hunter22"));
+        let found = verify_node(&reg_nodes, &id, "hunter22").await;
+        assert_eq!(found.map(|node| node.id), Some(id));
+    }
+
+    #[actix_web::test]
+    async fn verify_node_rejects_a_wrong_password() {
+        let id = Uuid::new_v4();
+        let reg_nodes = store(RegisteredNode::test(id, "hunter22"));
+        assert!(verify_node(&reg_nodes, &id, "hunter2").await.is_none());
+        assert!(verify_node(&reg_nodes, &id, "").await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn verify_node_rejects_an_unknown_id() {
+        let reg_nodes = store(RegisteredNode::test(Uuid::new_v4(), "hunter22"));
+        assert!(verify_node(&reg_nodes, &Uuid::new_v4(), "hunter22")
+            .await
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn just_expired_registration_cannot_authenticate() {
+        let id = Uuid::new_v4();
+        let mut node = RegisteredNode::test(id, "hunter22");
+        node.expires_at = Some(chrono::Utc::now() - chrono::Duration::milliseconds(1));
+        let token = NodeToken {
+            node_id: id,
+            nonce: node.token_nonce,
+        };
+        let reg_nodes = store(node);
+        assert!(verify_node(&reg_nodes, &id, "hunter22").await.is_none());
+        assert!(find_node(&reg_nodes, &token).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn find_node_requires_the_registration_the_token_names() {
+        let id = Uuid::new_v4();
+        let node = RegisteredNode::test(id, "hunter22");
+        let current = NodeToken {
+            node_id: id,
+            nonce: node.token_nonce,
+        };
+        let reg_nodes = store(node);
+        assert!(find_node(&reg_nodes, &current).await.is_some());
+        let earlier = NodeToken {
+            node_id: id,
+            nonce: Uuid::new_v4(),
+        };
+        assert!(find_node(&reg_nodes, &earlier).await.is_none());
+    }
 }
@@ -0,0 +1,198 @@
+use crate::config::RootResponse;
+use crate::state::AppState;
+use actix_web::body::EitherBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+use utoipa::ToSchema;
+use validator::{ValidationErrors, ValidationErrorsKind};
+
+/// Problem with a single request field.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Structured JSON error body: `{ "error": "...", "fields": [...] }`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiError {
+    #[serde(skip)]
+    #[schema(ignore)]
+    pub status: StatusCode,
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    /// Where to look for help, e.g. the endpoint list at `/`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docs: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            error: error.into(),
+            fields: Vec::new(),
+            docs: None,
+        }
+    }
+
+    pub fn validation(errors: &ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        collect_field_errors(errors, "", &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            error: "Validation failed".to_string(),
+            fields,
+            docs: None,
+        }
+    }
+}
+
+/// Flattens nested errors into paths like `users[2].password`.
+fn collect_field_errors(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => out.extend(errors.iter().map(|error| {
+                FieldError {
+                    field: path.clone(),
+                    message: error
+                        .message
+                        .as_ref()
+                        .map(|message| message.to_string())
+                        .unwrap_or_else(|| error.code.to_string()),
+                }
+            })),
+            ValidationErrorsKind::Struct(errors) => collect_field_errors(errors, &path, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_field_errors(errors, &format!("{}[{}]", path, index), out);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.error)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self)
+    }
+}
+
+/// Default service for unmatched routes, pointing callers at the endpoint list on `/`
+/// (or the OpenAPI document when the status page is off).
+pub async fn not_found(req: HttpRequest) -> HttpResponse {
+    let mut api_error = ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("No route for {} {}", req.method(), req.path()),
+    );
+    let root_is_html = req
+        .app_data::<web::Data<AppState>>()
+        .is_none_or(|state| state.config.root_response == RootResponse::Html);
+    api_error.docs = Some(if root_is_html { "/" } else { "/openapi.json" }.to_string());
+    api_error.error_response()
+}
+
+/// `JsonConfig` error handler turning body deserialization failures into `ApiError`s,
+/// with serde's "missing field" errors reported against the field.
+pub fn json_error_handler(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    let mut api_error = ApiError::new(StatusCode::BAD_REQUEST, "Invalid request body");
+    if let JsonPayloadError::Deserialize(ref source) = err {
+        let message = source.to_string();
+        match message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next())
+        {
+            Some(field) => api_error.fields.push(FieldError {
+                field: field.to_string(),
+                message: "is required".to_string(),
+            }),
+            None => api_error.error = format!("Invalid request body: {}", message),
+        }
+    } else if let JsonPayloadError::ContentType = err {
+        api_error = ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected Content-Type: application/json",
+        );
+    }
+    api_error.into()
+}
+
+/// Replaces the bare-text 500 that `HttpResponse::json` produces when a body fails to
+/// serialize with a logged `ApiError`, so the failure shows up server-side and clients
+/// still get the structured error shape.
+pub fn serialization_failure<B>(response: ServiceResponse<B>) -> ServiceResponse<EitherBody<B>> {
+    let failed = response
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<JsonPayloadError>())
+        .is_some_and(|err| matches!(err, JsonPayloadError::Serialize(_)));
+    if !failed {
+        return response.map_into_left_body();
+    }
+    let (req, res) = response.into_parts();
+    if let Some(err) = res.error() {
+        eprintln!(
+            "Failed to serialize response to {} {}: {}",
+            req.method(),
+            req.path(),
+            err
+        );
+    }
+    let error = ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to serialize response",
+    );
+    ServiceResponse::new(req, error.error_response().map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use futures_util::TryFutureExt;
+    use std::collections::HashMap;
+
+    #[actix_web::test]
+    async fn unserializable_response_becomes_an_api_error() {
+        let app = init_service(
+            App::new()
+                .wrap_fn(|req, srv| srv.call(req).map_ok(serialization_failure))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        // serde_json only accepts string-like map keys.
+                        HttpResponse::Ok().json(HashMap::from([((1, 2), 3)]))
+                    }),
+                ),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["error"], "Failed to serialize response");
+    }
+}
@@ -0,0 +1,57 @@
+use crate::ProxyNode;
+use actix_web::web::Bytes;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+pub type NodeEvents = broadcast::Sender<NodeEvent>;
+
+/// Change published whenever a node registers, joins or leaves `ActiveNodes`, or its
+/// address/status changes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NodeEvent {
+    Joined { node: ProxyNode },
+    Updated { node: ProxyNode },
+    Left { id: Uuid },
+    Registered { id: Uuid, mac_id: String },
+}
+
+impl NodeEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeEvent::Joined { .. } => "joined",
+            NodeEvent::Updated { .. } => "updated",
+            NodeEvent::Left { .. } => "left",
+            NodeEvent::Registered { .. } => "registered",
+        }
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        match self {
+            NodeEvent::Joined { node } | NodeEvent::Updated { node } => node.id,
+            NodeEvent::Left { id } | NodeEvent::Registered { id, .. } => *id,
+        }
+    }
+
+    pub fn to_sse(&self) -> Bytes {
+        sse_frame(self.name(), self)
+    }
+}
+
+pub fn channel() -> NodeEvents {
+    broadcast::channel(256).0
+}
+
+/// Publishes an event; having no subscribers is not an error.
+pub fn publish(events: &NodeEvents, event: NodeEvent) {
+    let _ = events.send(event);
+}
+
+pub fn sse_frame<T: Serialize>(event: &str, data: &T) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_else(|err| {
+        eprintln!("Failed to serialize {} event: {}", event, err);
+        String::new()
+    });
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
@@ -0,0 +1,130 @@
+use crate::Rejection;
+use actix_http::ws::Item;
+use actix_web::web::Bytes;
+use actix_web_actors::ws;
+
+/// Reassembles fragmented ws messages (`Continuation` frames) into whole `Text`/`Binary`
+/// messages, capping the total size at `max_len` bytes.
+pub struct Reassembler {
+    max_len: usize,
+    /// The message in progress and whether it's binary.
+    partial: Option<(bool, Vec<u8>)>,
+}
+
+impl Reassembler {
+    pub fn new(max_len: usize) -> Self {
+        Reassembler {
+            max_len,
+            partial: None,
+        }
+    }
+
+    /// Adds a fragment, returning the message once its last fragment arrives.
+    pub fn push(&mut self, item: Item) -> Result<Option<ws::Message>, Rejection> {
+        let (data, last) = match item {
+            // A new message can't start before the previous one is finished.
+            Item::FirstText(_) | Item::FirstBinary(_) if self.partial.is_some() => {
+                self.partial = None;
+                return Err(Rejection::ProtocolError);
+            }
+            Item::FirstText(data) => {
+                self.partial = Some((false, Vec::new()));
+                (data, false)
+            }
+            Item::FirstBinary(data) => {
+                self.partial = Some((true, Vec::new()));
+                (data, false)
+            }
+            Item::Continue(data) => (data, false),
+            Item::Last(data) => (data, true),
+        };
+
+        let Some((_, buffer)) = self.partial.as_mut() else {
+            return Err(Rejection::ProtocolError);
+        };
+        if buffer.len() + data.len() > self.max_len {
+            self.partial = None;
+            return Err(Rejection::FrameTooLarge);
+        }
+        buffer.extend_from_slice(&data);
+        if !last {
+            return Ok(None);
+        }
+
+        let (binary, buffer) = self.partial.take().expect("checked above");
+        if binary {
+            Ok(Some(ws::Message::Binary(Bytes::from(buffer))))
+        } else {
+            String::from_utf8(buffer)
+                .map(|text| Some(ws::Message::Text(text.into())))
+                .map_err(|_| Rejection::ProtocolError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: Option<ws::Message>) -> String {
+        match message {
+            Some(ws::Message::Text(text)) => text.to_string(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn joins_fragments_into_one_message() {
+        let mut reassembler = Reassembler::new(64);
+        let push = |reassembler: &mut Reassembler, item| reassembler.push(item).unwrap();
+        assert!(push(&mut reassembler, Item::FirstText(Bytes::from("{\"type\":"))).is_none());
+        assert!(push(&mut reassembler, Item::Continue(Bytes::from("\"GetCon"))).is_none());
+        let whole = push(&mut reassembler, Item::Last(Bytes::from("fig\"}")));
+        assert_eq!(text(whole), r#"{"type":"GetConfig"}"#);
+
+        // Ready for the next one; a character split across fragments survives.
+        let euro = "€".as_bytes();
+        push(
+            &mut reassembler,
+            Item::FirstText(Bytes::copy_from_slice(&euro[..1])),
+        );
+        let whole = push(
+            &mut reassembler,
+            Item::Last(Bytes::copy_from_slice(&euro[1..])),
+        );
+        assert_eq!(text(whole), "€");
+
+        push(
+            &mut reassembler,
+            Item::FirstBinary(Bytes::from_static(&[1, 2])),
+        );
+        match push(&mut reassembler, Item::Last(Bytes::from_static(&[3]))) {
+            Some(ws::Message::Binary(data)) => assert_eq!(&data[..], [1, 2, 3]),
+            other => panic!("expected a binary message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_and_out_of_order_fragments() {
+        let mut reassembler = Reassembler::new(8);
+        reassembler
+            .push(Item::FirstText(Bytes::from("12345")))
+            .unwrap();
+        let err = reassembler.push(Item::Continue(Bytes::from("6789")));
+        assert!(matches!(err, Err(Rejection::FrameTooLarge)));
+
+        // The oversized message was dropped, so its tail has nothing to continue.
+        let err = reassembler.push(Item::Last(Bytes::from("0")));
+        assert!(matches!(err, Err(Rejection::ProtocolError)));
+
+        reassembler.push(Item::FirstText(Bytes::from("a"))).unwrap();
+        let err = reassembler.push(Item::FirstBinary(Bytes::from("b")));
+        assert!(matches!(err, Err(Rejection::ProtocolError)));
+
+        reassembler
+            .push(Item::FirstText(Bytes::from_static(&[0xff])))
+            .unwrap();
+        let err = reassembler.push(Item::Last(Bytes::new()));
+        assert!(matches!(err, Err(Rejection::ProtocolError)));
+    }
+}
@@ -0,0 +1,230 @@
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+/// Objects under these keys hold client-chosen names, which are returned as given.
+const OPAQUE_KEYS: &[&str] = &["metadata", "payload"];
+
+/// Map-valued fields: their keys are data (tags and the like) and are kept, only the values
+/// are converted.
+const MAP_KEYS: &[&str] = &["active_nodes_by_tag"];
+
+/// `ApiError::fields`, whose `field` values name request fields and are converted with the keys.
+const FIELD_ERRORS_KEY: &str = "fields";
+
+/// Marks a response whose top-level JSON object is a map (e.g. `/nodes/by-mac`), so its keys
+/// are kept like those under `MAP_KEYS`. Insert it into the response extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct MapBody;
+
+/// Key naming of JSON responses (`JSON_CASE=snake|camel`). A request can pick one with
+/// `?case=`; the structs themselves always serialize as snake_case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonCase {
+    #[default]
+    Snake,
+    Camel,
+}
+
+impl FromStr for JsonCase {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "snake" => Ok(JsonCase::Snake),
+            "camel" => Ok(JsonCase::Camel),
+            other => Err(format!("unknown JSON case {:?}", other)),
+        }
+    }
+}
+
+impl JsonCase {
+    /// `?case=` from the query string, falling back to `default` when absent or unknown.
+    pub fn for_query(query: &str, default: JsonCase) -> JsonCase {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("case="))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+/// Rewrites the keys of a JSON response body to camelCase. Other responses (plain text,
+/// event streams, errors raised before a body exists) pass through untouched.
+pub async fn to_camel<B>(response: ServiceResponse<B>) -> ServiceResponse<BoxBody>
+where
+    B: MessageBody + 'static,
+{
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return response.map_into_boxed_body();
+    }
+    let is_map = response.response().extensions().get::<MapBody>().is_some();
+    let (req, res) = response.into_parts();
+    let (res, body) = res.into_parts();
+    let bytes = body::to_bytes(body).await.unwrap_or_default();
+    let converted = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) => {
+            let value = if is_map {
+                camel_values(value)
+            } else {
+                camel_keys(value)
+            };
+            serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec())
+        }
+        Err(_) => bytes.to_vec(),
+    };
+    ServiceResponse::new(req, res.set_body(BoxBody::new(converted)))
+}
+
+/// Converts the keys of struct-shaped objects, recursing into their values.
+fn camel_keys(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(camel_keys).collect()),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let value = if OPAQUE_KEYS.contains(&key.as_str()) {
+                        value
+                    } else if MAP_KEYS.contains(&key.as_str()) {
+                        camel_values(value)
+                    } else if key == FIELD_ERRORS_KEY {
+                        camel_field_errors(value)
+                    } else {
+                        camel_keys(value)
+                    };
+                    (camel(&key), value)
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        other => other,
+    }
+}
+
+/// Keeps the keys of a map-shaped object and converts its values.
+fn camel_values(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, camel_keys(value)))
+                .collect(),
+        ),
+        other => camel_keys(other),
+    }
+}
+
+fn camel_field_errors(value: Value) -> Value {
+    let mut value = camel_keys(value);
+    if let Value::Array(errors) = &mut value {
+        for error in errors {
+            if let Some(Value::String(field)) = error.get_mut("field") {
+                *field = camel(field);
+            }
+        }
+    }
+    value
+}
+
+fn camel(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper = false;
+    for ch in key.chars() {
+        if ch == '_' && !out.is_empty() {
+            upper = true;
+        } else if upper {
+            out.extend(ch.to_uppercase());
+            upper = false;
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use serde_json::json;
+
+    #[test]
+    fn struct_keys_are_converted_recursively() {
+        let body = json!({
+            "node_id": 1,
+            "last_seen": {"unix_secs": 2},
+            "nodes": [{"mac_id": "aa:bb:cc:dd:ee:ff"}],
+        });
+        assert_eq!(
+            camel_keys(body),
+            json!({
+                "nodeId": 1,
+                "lastSeen": {"unixSecs": 2},
+                "nodes": [{"macId": "aa:bb:cc:dd:ee:ff"}],
+            })
+        );
+    }
+
+    #[test]
+    fn client_chosen_names_are_kept() {
+        let body = json!({
+            "metadata": {"rack_id": "r1"},
+            "active_nodes_by_tag": {"eu_west": 3},
+        });
+        assert_eq!(
+            camel_keys(body),
+            json!({
+                "metadata": {"rack_id": "r1"},
+                "activeNodesByTag": {"eu_west": 3},
+            })
+        );
+    }
+
+    #[test]
+    fn field_errors_name_the_converted_fields() {
+        let body = json!({
+            "error": "Validation failed",
+            "fields": [{"field": "mac_id", "message": "must be a MAC address"}],
+        });
+        assert_eq!(
+            camel_keys(body),
+            json!({
+                "error": "Validation failed",
+                "fields": [{"field": "macId", "message": "must be a MAC address"}],
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn map_bodies_keep_their_top_level_keys() {
+        let app = init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let response = srv.call(req);
+                    async move { Ok(to_camel(response.await?).await) }
+                })
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        let mut response =
+                            HttpResponse::Ok().json(json!({"group_a": [{"mac_id": "x"}]}));
+                        response.extensions_mut().insert(MapBody);
+                        response
+                    }),
+                ),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body, json!({"group_a": [{"macId": "x"}]}));
+    }
+}
@@ -1,218 +1,2359 @@
 use actix::*;
-use actix_web::{get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::error::JsonPayloadError;
+use actix_web::{
+    delete, get, http::header, post, routes, web, App, Error, HttpRequest, HttpResponse,
+    HttpServer, Responder,
+};
 use actix_web_actors::ws;
+use chrono::{DateTime, Utc};
+use futures_util::future::{self, Either, TryFutureExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+mod admin_handlers;
+mod api_key;
+mod audit;
 mod auth;
+mod auth_scope;
+mod cache;
+mod capabilities;
+mod client_ip;
+mod compression;
+mod config;
+mod connection_info;
+mod crypto;
 mod db;
+mod errors;
+mod events;
+mod fragments;
+mod json_case;
+mod maintenance;
+mod metrics;
 mod models;
+mod monitor;
+mod node_handlers;
+mod openapi;
+mod password;
+mod pools;
+mod probe;
+mod rate_limit;
+mod security_headers;
+mod seed;
+mod shutdown;
+mod snapshot;
+mod state;
+mod stats;
+mod sweep;
+mod sync;
+mod tls;
+mod tokens;
 mod user_handlers;
+mod validation;
+mod webhooks;
 
+use crate::audit::{AuditEvent, AuditHealth, DisconnectReason};
 use crate::auth::validator;
+use crate::config::{limit_reached, Config, RootResponse};
+use crate::connection_info::ConnectionInfo;
+use crate::crypto::NodeSecret;
+use crate::events::NodeEvent;
+use crate::fragments::Reassembler;
+use crate::json_case::JsonCase;
+use crate::models::Claims;
+use crate::rate_limit::{AddressUpdateLimiter, TokenBucket};
+use crate::security_headers::SecurityHeaders;
+use crate::state::AppState;
+use crate::sync::LockExt;
+use crate::validation::{
+    validate_ip, validate_mac_id, validate_node_name, validate_pool_name, ValidJson,
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::middleware::HttpAuthentication;
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A node's registration. Only a verifier of the node's password is kept, and even that is
+/// deliberately not `Serialize`: responses use `RegistrationInfo`.
+#[derive(Debug, Clone)]
 struct RegisteredNode {
     id: Uuid,
-    password: String,
+    secret: NodeSecret,
+    mac_id: String,
+    cert_fingerprint: Option<String>,
+    tags: Vec<String>,
+    pool: Option<String>,
+    /// Static address the node is listed with until it sends `SetAddress`.
+    address: Option<NodeAddress>,
+    /// User who created the registration; `None` for API-key-only, seeded and older records.
+    owner: Option<String>,
+    /// Tenant the node belongs to; `None` is the default tenant.
+    tenant: Option<String>,
+    /// When the registration lapses (`Config::registration_ttl`); `None` never does.
+    expires_at: Option<DateTime<Utc>>,
+    /// Fresh for every registration and carried by its node tokens, so tokens issued
+    /// before a deregistration or expiry don't work for a later registration of the id.
+    token_nonce: Uuid,
+}
+
+impl RegisteredNode {
+    /// Expired registrations stay in the map until `sweep::run` prunes them, but can no
+    /// longer authenticate and don't block re-registering the id.
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+}
+
+/// A registration as listed by `/registered-nodes` and `/admin/export`: everything except
+/// the node's credential.
+#[derive(Serialize, ToSchema)]
+struct RegistrationInfo {
+    id: Uuid,
     mac_id: String,
+    cert_fingerprint: Option<String>,
+    tags: Vec<String>,
+    pool: Option<String>,
+    address: Option<NodeAddress>,
+    owner: Option<String>,
+    tenant: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    /// Past `expires_at`: the node can no longer authenticate, and the entry goes at the next sweep.
+    expired: bool,
+}
+
+impl From<&RegisteredNode> for RegistrationInfo {
+    fn from(node: &RegisteredNode) -> Self {
+        RegistrationInfo {
+            id: node.id,
+            mac_id: node.mac_id.clone(),
+            cert_fingerprint: node.cert_fingerprint.clone(),
+            tags: node.tags.clone(),
+            pool: node.pool.clone(),
+            address: node.address.clone(),
+            owner: node.owner.clone(),
+            tenant: node.tenant.clone(),
+            expires_at: node.expires_at,
+            expired: node.is_expired(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[cfg(test)]
+impl RegisteredNode {
+    /// A registration with just an id and password, in the default tenant.
+    fn test(id: Uuid, password: &str) -> Self {
+        RegisteredNode {
+            id,
+            secret: NodeSecret::new(password),
+            mac_id: "aa:bb:cc:dd:ee:ff".to_string(),
+            cert_fingerprint: None,
+            tags: Vec::new(),
+            pool: None,
+            address: None,
+            owner: None,
+            tenant: None,
+            expires_at: None,
+            token_nonce: Uuid::new_v4(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate, ToSchema)]
+struct NodeAddress {
+    #[validate(custom(function = "validate_ip"))]
+    ip: String,
+    #[validate(range(min = 1, message = "must not be 0"))]
+    port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum NodeStatus {
+    Healthy,
+    /// Known from a previous run (or otherwise suspect) and not yet heard from.
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 struct ProxyNode {
     id: Uuid,
     name: String,
     ip: String,
     port: u16,
     active: bool,
+    status: NodeStatus,
     mac_id: String,
+    tags: Vec<String>,
+    /// When the node's session started. All timestamps serialize as RFC3339 UTC.
+    connected_at: DateTime<Utc>,
+    /// When the node's record (e.g. its address) last changed.
+    updated_at: DateTime<Utc>,
+    /// When the node last showed any sign of life (heartbeat or update).
+    last_seen: DateTime<Utc>,
+    /// Incremented on every update so clients can detect concurrent writes.
+    version: u64,
+    /// Where the node last connected or reported from (see `client_ip::real_client_ip`).
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    source_ip: Option<IpAddr>,
+    /// Negotiated on ws auth; see `capabilities::negotiate`.
+    #[serde(default)]
+    capabilities: Vec<String>,
+    /// Named group used by `/pools`; set at registration or with `SetPool`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
+    /// Free-form attributes (firmware, OS, ...) reported with `SetMetadata`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    /// When `probe::run` last tried to reach the node's address, and whether it worked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_probe_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_probe_ok: Option<bool>,
+    /// Consecutive failed probes of the current address.
+    #[serde(skip)]
+    probe_failures: u32,
+    /// Whether `probe::run` is what made the node `Unhealthy`; a passing probe only undoes
+    /// its own verdict, not a `ReportError` or a snapshot restore.
+    #[serde(skip)]
+    probe_unhealthy: bool,
+    /// Most recent problem the node reported with `ReportError`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<NodeError>,
+    /// Copied from the registration; see `RegisteredNode::tenant`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+struct NodeError {
+    code: String,
+    message: String,
+    reported_at: DateTime<Utc>,
+}
+
+/// Serialized `ProxyNode` field names accepted by `/nodes?fields=`. Optional fields are left
+/// out of a node's output when unset, even if requested.
+const PROXY_NODE_FIELDS: &[&str] = &[
+    "id",
+    "name",
+    "ip",
+    "port",
+    "active",
+    "status",
+    "mac_id",
+    "tags",
+    "connected_at",
+    "updated_at",
+    "last_seen",
+    "version",
+    "source_ip",
+    "capabilities",
+    "pool",
+    "metadata",
+    "last_probe_at",
+    "last_probe_ok",
+    "last_error",
+    "tenant",
+];
+
+impl ProxyNode {
+    fn new(reg_node: &RegisteredNode, source_ip: IpAddr) -> Self {
+        let now = Utc::now();
+        let (ip, port) = match &reg_node.address {
+            Some(address) => (address.ip.clone(), address.port),
+            None => ("unknown".to_string(), 0),
+        };
+        ProxyNode {
+            id: reg_node.id,
+            name: format!("node-{}", &reg_node.id.to_string()[..8]),
+            ip,
+            port,
+            active: true,
+            status: NodeStatus::Healthy,
+            mac_id: reg_node.mac_id.clone(),
+            tags: reg_node.tags.clone(),
+            connected_at: now,
+            updated_at: now,
+            last_seen: now,
+            version: 0,
+            source_ip: Some(source_ip),
+            capabilities: Vec::new(),
+            pool: reg_node.pool.clone(),
+            metadata: BTreeMap::new(),
+            last_probe_at: None,
+            last_probe_ok: None,
+            probe_failures: 0,
+            probe_unhealthy: false,
+            last_error: None,
+            tenant: reg_node.tenant.clone(),
+        }
+    }
+
+    /// Applies an address update and returns the new version. If `expected_version` is given
+    /// and no longer current, the update is rejected with the current version instead.
+    fn set_address(
+        &mut self,
+        ip: String,
+        port: u16,
+        expected_version: Option<u64>,
+    ) -> Result<u64, u64> {
+        if expected_version.is_some_and(|expected| expected != self.version) {
+            return Err(self.version);
+        }
+        self.ip = ip;
+        self.port = port;
+        // Probe results were for the old address.
+        self.last_probe_at = None;
+        self.last_probe_ok = None;
+        self.probe_failures = 0;
+        self.mark_seen();
+        self.touch();
+        Ok(self.version)
+    }
+
+    /// Marks the record as changed; every update goes through here so `version` always moves.
+    fn touch(&mut self) {
+        self.updated_at = Utc::now();
+        self.version += 1;
+    }
+
+    /// Records a sign of life, which also clears any `Unhealthy` status unless the node's
+    /// address failed its last probe (only a passing probe clears that).
+    /// Returns whether the status changed.
+    fn mark_seen(&mut self) -> bool {
+        self.last_seen = Utc::now();
+        if self.last_probe_ok == Some(false) {
+            return false;
+        }
+        let was_unhealthy = self.status == NodeStatus::Unhealthy;
+        self.status = NodeStatus::Healthy;
+        self.probe_unhealthy = false;
+        was_unhealthy
+    }
+
+    /// Whether the caller may see this node: admins see everything, other users only nodes
+    /// of their own tenant sharing a tag with their scopes.
+    fn visible_to(&self, claims: &Claims) -> bool {
+        claims.is_admin()
+            || (claims.in_tenant(self.tenant.as_deref())
+                && self.tags.iter().any(|tag| claims.scopes.contains(tag)))
+    }
+
+    /// Healthy with a known address, i.e. something a client could connect to.
+    fn is_pickable(&self) -> bool {
+        self.status == NodeStatus::Healthy && self.port != 0
+    }
+}
+
+const MAX_METADATA_KEYS: usize = 32;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 256;
+
+fn validate_metadata(map: &HashMap<String, String>) -> Result<(), &'static str> {
+    if map.len() > MAX_METADATA_KEYS {
+        return Err("Too many metadata keys (max 32)");
+    }
+    if map
+        .keys()
+        .any(|key| key.is_empty() || key.len() > MAX_METADATA_KEY_LEN)
+    {
+        return Err("Metadata keys must be 1-64 bytes");
+    }
+    if map
+        .values()
+        .any(|value| value.len() > MAX_METADATA_VALUE_LEN)
+    {
+        return Err("Metadata values must be at most 256 bytes");
+    }
+    Ok(())
+}
+
+fn validate_address(ip: &str, port: u16) -> Result<(), &'static str> {
+    if ip.parse::<IpAddr>().is_err() {
+        return Err("Invalid IP address");
+    }
+    if port == 0 {
+        return Err("Invalid port");
+    }
+    Ok(())
 }
 
 type RegisteredNodes = Arc<Mutex<HashMap<Uuid, RegisteredNode>>>;
 type ActiveNodes = Arc<Mutex<HashMap<Uuid, ProxyNode>>>;
+/// The live ws session that currently owns each authenticated node id.
+type Sessions = Arc<Mutex<HashMap<Uuid, SessionHandle>>>;
 
-#[derive(Deserialize)]
+#[derive(Clone)]
+struct SessionHandle {
+    session_id: Uuid,
+    addr: Addr<ProxyWsSession>,
+    /// The node's registration tags, used to target broadcasts.
+    tags: Vec<String>,
+    /// Relays never cross tenants.
+    tenant: Option<String>,
+    mac_id: String,
+    connected_at: DateTime<Utc>,
+    source_ip: IpAddr,
+    capabilities: Vec<String>,
+    connection: ConnectionInfo,
+}
+
+/// Why the server is closing a ws session. Each kind maps to a close code so clients can
+/// tell e.g. "retry later" from "don't retry with these credentials".
+#[derive(Debug, Clone, Copy)]
+enum Rejection {
+    AuthFailed,
+    RateLimited,
+    ActiveNodeLimit,
+    /// A newer connection took over the node id.
+    Superseded,
+    Revoked,
+    Inactive,
+    FrameTooLarge,
+    ProtocolError,
+    /// Drained by `POST /admin/maintenance`.
+    Maintenance,
+    /// Too many failed password `Auth` attempts for the claimed node id.
+    AuthBanned,
+    /// The node's registration was removed (deregistered, or dropped by an import).
+    Deregistered,
+}
+
+impl Rejection {
+    fn disconnect_reason(self) -> DisconnectReason {
+        match self {
+            Rejection::Inactive => DisconnectReason::Timeout,
+            Rejection::Superseded
+            | Rejection::Revoked
+            | Rejection::Maintenance
+            | Rejection::Deregistered => DisconnectReason::Kicked,
+            _ => DisconnectReason::Rejected,
+        }
+    }
+
+    /// How long a node should wait before reconnecting, for rejections caused by load or
+    /// maintenance rather than by the node itself.
+    fn retry_after(self, config: &Config) -> Option<Duration> {
+        match self {
+            Rejection::Maintenance => Some(maintenance::RETRY_AFTER),
+            Rejection::ActiveNodeLimit => Some(config.heartbeat_interval),
+            _ => None,
+        }
+    }
+
+    fn close_code(self) -> ws::CloseCode {
+        match self {
+            Rejection::AuthFailed
+            | Rejection::RateLimited
+            | Rejection::Revoked
+            | Rejection::Deregistered => ws::CloseCode::Policy,
+            Rejection::ActiveNodeLimit | Rejection::Maintenance => ws::CloseCode::Again,
+            Rejection::Superseded => ws::CloseCode::Other(4000),
+            Rejection::Inactive => ws::CloseCode::Other(4001),
+            Rejection::AuthBanned => ws::CloseCode::Other(4003),
+            Rejection::FrameTooLarge => ws::CloseCode::Size,
+            Rejection::ProtocolError => ws::CloseCode::Protocol,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Rejection::AuthFailed => "Authentication failed",
+            Rejection::RateLimited => "Rate limit exceeded",
+            Rejection::ActiveNodeLimit => "Active node limit reached",
+            Rejection::Superseded => "Session replaced by a newer connection",
+            Rejection::Revoked => "Session revoked by an administrator",
+            Rejection::Inactive => "Closed for inactivity",
+            Rejection::FrameTooLarge => "Message too large",
+            Rejection::ProtocolError => "Protocol error",
+            Rejection::Maintenance => "Down for maintenance",
+            Rejection::AuthBanned => "Too many failed authentication attempts; try again later",
+            Rejection::Deregistered => "Node registration removed",
+        }
+    }
+}
+
+/// Close frame description for rejections that are worth retrying later, e.g.
+/// `{"reason":"Down for maintenance","retry_after_secs":137}`. Other close frames carry the
+/// plain message.
+#[derive(Serialize)]
+struct CloseHint {
+    reason: &'static str,
+    retry_after_secs: u64,
+}
+
+/// Tells a session to close, e.g. because a newer connection took over its node id
+/// or an admin revoked it.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect(Rejection);
+
+/// A payload relayed from another node's session.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Relay {
+    from: Uuid,
+    payload: serde_json::Value,
+}
+
+#[derive(Deserialize, Validate, ToSchema)]
 struct RegisterRequest {
-    id: Uuid,
+    /// Required unless the server assigns ids (`?server_assigned=true` or
+    /// `SERVER_ASSIGNED_IDS`), in which case it must be omitted.
+    #[serde(default)]
+    id: Option<Uuid>,
+    #[validate(length(min = 1, message = "must not be empty"))]
     password: String,
+    #[validate(custom(function = "validate_mac_id"))]
     mac_id: String,
     api_key: String,
+    #[serde(default)]
+    cert_fingerprint: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_pool_name"))]
+    pool: Option<String>,
+    /// Listed as the node's address until it sends `SetAddress`.
+    #[serde(default)]
+    #[validate(nested)]
+    address: Option<NodeAddress>,
+    /// Defaults to the registering user's tenant. Only admins may name another one.
+    #[serde(default)]
+    #[validate(custom(function = "validate_pool_name"))]
+    tenant: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RegisterQuery {
+    /// Overrides `SERVER_ASSIGNED_IDS` for this request.
+    server_assigned: Option<bool>,
+}
+
+/// Returned instead of the plain-text confirmation when the server picked the id.
+#[derive(Serialize, ToSchema)]
+struct RegisterResponse {
+    /// Use this id for ws `Auth` and everything else from now on.
+    id: Uuid,
 }
 
+#[utoipa::path(
+    params(RegisterQuery),
+    request_body = RegisterRequest,
+    responses(
+        (status = 200, description = "Registered, or an identical registration already exists; \
+            with a server-assigned id the body is a `RegisterResponse`"),
+        (status = 400, body = errors::ApiError),
+        (status = 401, description = "Invalid API key or user token"),
+        (status = 403, description = "Registration is disabled, or tenant not allowed"),
+        (status = 409, description = "ID already registered with different credentials"),
+        (status = 429, description = "Too many attempts for this mac_id; see Retry-After"),
+        (status = 507, description = "Registered node limit reached"),
+    )
+)]
 #[post("/register")]
 async fn register(
-    reg: web::Json<RegisterRequest>,
-    data: web::Data<RegisteredNodes>,
+    req: HttpRequest,
+    query: web::Query<RegisterQuery>,
+    reg: ValidJson<RegisterRequest>,
+    bearer: Option<BearerAuth>,
+    state: web::Data<AppState>,
 ) -> impl Responder {
-    let expected_api_key = env::var("API_KEY").unwrap_or_default();
-    if reg.api_key != expected_api_key {
+    if !state.config.registration_enabled {
+        return HttpResponse::Forbidden().body("Registration is disabled");
+    }
+
+    if !state.api_keys.accepts(&reg.api_key) {
         return HttpResponse::Unauthorized().body("Invalid API key");
     }
 
-    let mut reg_nodes = data.lock().await;
+    let server_assigned = query
+        .server_assigned
+        .unwrap_or(state.config.server_assigned_ids);
+    let id = match (server_assigned, reg.id) {
+        (true, None) => Uuid::new_v4(),
+        (false, Some(id)) => id,
+        (true, Some(_)) => return id_error("must be omitted when the server assigns ids"),
+        (false, None) => return id_error("is required"),
+    };
 
-    if reg_nodes.contains_key(&reg.id) {
-        return HttpResponse::BadRequest().body("ID already registered");
+    // A user token is optional; when given, that user owns the registration and the node
+    // joins their tenant. With the API key alone, nodes go to the default tenant.
+    let claims = match bearer {
+        Some(bearer) => match auth::authenticate_user(Some(&state), bearer.token()) {
+            Ok(claims) => Some(claims),
+            Err(failure) => {
+                auth::log_auth_failure(Some(&state), &req, &failure);
+                return HttpResponse::Unauthorized().body(failure.message);
+            }
+        },
+        None => None,
+    };
+    let tenant = match (&claims, &reg.tenant) {
+        (Some(claims), Some(tenant)) if claims.in_tenant(Some(tenant)) => Some(tenant.clone()),
+        (Some(claims), None) => claims.tenant.clone(),
+        (None, None) => None,
+        _ => return HttpResponse::Forbidden().body("Not allowed to register into that tenant"),
+    };
+    let owner = claims.map(|claims| claims.sub);
+
+    // Checked after the API key so unauthenticated callers can't lock a device out.
+    if let Err(wait) = state.registration_throttle.check(&reg.mac_id) {
+        return too_many_registrations(wait);
+    }
+
+    let mut reg_nodes = state.registered_nodes.lock().await;
+
+    // Retrying an identical registration is answered like the original, so the stored
+    // record is never echoed back; it also renews the registration's TTL. An expired
+    // registration no longer holds the id.
+    // (Fresh server-assigned ids can't collide, so this only applies to client ids.)
+    if let Some(existing) = reg_nodes.get_mut(&id).filter(|node| !node.is_expired()) {
+        if existing.secret.matches(&reg.password) && existing.mac_id == reg.mac_id {
+            existing.expires_at = state.config.registration_expiry();
+            state.registered_nodes_cache.invalidate();
+            return registered(id, server_assigned);
+        }
+        return HttpResponse::Conflict().body("ID already registered with different credentials");
+    }
+
+    // An expired entry for this id is replaced rather than added to.
+    if !reg_nodes.contains_key(&id)
+        && limit_reached(state.config.max_registered_nodes, reg_nodes.len())
+    {
+        return HttpResponse::InsufficientStorage().body("Registered node limit reached");
     }
 
     let node = RegisteredNode {
-        id: reg.id,
-        password: reg.password.clone(),
+        id,
+        secret: NodeSecret::new(&reg.password),
         mac_id: reg.mac_id.clone(),
+        cert_fingerprint: reg
+            .cert_fingerprint
+            .as_deref()
+            .map(tls::normalize_fingerprint),
+        tags: reg.tags.clone(),
+        pool: reg.pool.clone(),
+        address: reg.address.clone(),
+        owner,
+        tenant,
+        expires_at: state.config.registration_expiry(),
+        token_nonce: Uuid::new_v4(),
     };
 
-    reg_nodes.insert(reg.id, node);
+    insert_registration(
+        &state,
+        &mut reg_nodes,
+        node,
+        client_ip::real_client_ip(&req),
+    );
+    registered(id, server_assigned)
+}
+
+/// `/register`'s success response, which only ever carries the id.
+fn registered(id: Uuid, server_assigned: bool) -> HttpResponse {
+    if server_assigned {
+        return HttpResponse::Ok().json(RegisterResponse { id });
+    }
     HttpResponse::Ok().body("Registered successfully")
 }
 
+/// Adds a new registration and announces it (audit log, event stream).
+fn insert_registration(
+    state: &AppState,
+    reg_nodes: &mut HashMap<Uuid, RegisteredNode>,
+    node: RegisteredNode,
+    source_ip: IpAddr,
+) {
+    state.audit.record(AuditEvent::Register {
+        node_id: node.id,
+        mac_id: node.mac_id.clone(),
+        source_ip,
+    });
+    events::publish(
+        &state.events,
+        NodeEvent::Registered {
+            id: node.id,
+            mac_id: node.mac_id.clone(),
+        },
+    );
+    reg_nodes.insert(node.id, node);
+    state.registered_nodes_cache.invalidate();
+}
+
+fn too_many_registrations(wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            header::RETRY_AFTER,
+            (wait.as_secs_f64().ceil() as u64).max(1).to_string(),
+        ))
+        .body("Too many registration attempts for this device")
+}
+
+#[derive(Serialize, ToSchema)]
+struct SignedRegisterResponse {
+    id: Uuid,
+    /// Node token for ws `AuthToken`, so the node can connect before deriving its password.
+    token: String,
+}
+
+/// Registers a node that proves it holds `REGISTRATION_PSK` (see `auth::SignedRegistration`)
+/// instead of sending an API key and password. Registered nodes get no tags, pool or tenant.
+#[utoipa::path(
+    request_body = auth::SignedRegistration,
+    responses(
+        (status = 200, body = SignedRegisterResponse,
+            description = "Registered, or this node was already registered the same way"),
+        (status = 400, body = errors::ApiError),
+        (status = 401, description = "Invalid signature, or timestamp out of range"),
+        (status = 403, description = "Registration is disabled, or REGISTRATION_PSK is unset"),
+        (status = 409, description = "ID already registered with different credentials"),
+        (status = 429, description = "Too many attempts for this mac_id; see Retry-After"),
+        (status = 507, description = "Registered node limit reached"),
+    )
+)]
+#[post("/register/signed")]
+async fn register_signed(
+    req: HttpRequest,
+    reg: ValidJson<auth::SignedRegistration>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !state.config.registration_enabled {
+        return HttpResponse::Forbidden().body("Registration is disabled");
+    }
+    let Some(psk) = &state.config.registration_psk else {
+        return HttpResponse::Forbidden().body("Signed registration is not enabled");
+    };
+    if let Err(reason) = reg.verify(psk) {
+        return HttpResponse::Unauthorized().body(reason);
+    }
+    if let Err(wait) = state.registration_throttle.check(&reg.mac_id) {
+        return too_many_registrations(wait);
+    }
+
+    let password = auth::derive_node_password(psk, &reg.id);
+    let response = |nonce: &Uuid| SignedRegisterResponse {
+        id: reg.id,
+        token: auth::create_node_jwt(&reg.id, nonce),
+    };
+    let mut reg_nodes = state.registered_nodes.lock().await;
+    if let Some(existing) = reg_nodes.get_mut(&reg.id).filter(|node| !node.is_expired()) {
+        if existing.secret.matches(&password) && existing.mac_id == reg.mac_id {
+            existing.expires_at = state.config.registration_expiry();
+            state.registered_nodes_cache.invalidate();
+            return HttpResponse::Ok().json(response(&existing.token_nonce));
+        }
+        return HttpResponse::Conflict().body("ID already registered with different credentials");
+    }
+    if !reg_nodes.contains_key(&reg.id)
+        && limit_reached(state.config.max_registered_nodes, reg_nodes.len())
+    {
+        return HttpResponse::InsufficientStorage().body("Registered node limit reached");
+    }
+
+    let node = RegisteredNode {
+        id: reg.id,
+        secret: NodeSecret::new(&password),
+        mac_id: reg.mac_id.clone(),
+        cert_fingerprint: None,
+        tags: Vec::new(),
+        pool: None,
+        address: None,
+        owner: None,
+        tenant: None,
+        expires_at: state.config.registration_expiry(),
+        token_nonce: Uuid::new_v4(),
+    };
+    let response = response(&node.token_nonce);
+    insert_registration(
+        &state,
+        &mut reg_nodes,
+        node,
+        client_ip::real_client_ip(&req),
+    );
+    HttpResponse::Ok().json(response)
+}
+
+fn id_error(message: &str) -> HttpResponse {
+    let mut error = errors::ApiError::new(
+        actix_web::http::StatusCode::BAD_REQUEST,
+        "Validation failed",
+    );
+    error.fields.push(errors::FieldError {
+        field: "id".to_string(),
+        message: message.to_string(),
+    });
+    actix_web::ResponseError::error_response(&error)
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CheckKeyRequest {
+    api_key: String,
+}
+
+/// Checks an API key the same way `/register` does, without registering anything.
+#[utoipa::path(
+    request_body = CheckKeyRequest,
+    responses(
+        (status = 200, description = "API key is valid"),
+        (status = 401, description = "Invalid API key"),
+    )
+)]
+#[post("/register/check-key")]
+async fn check_key(body: web::Json<CheckKeyRequest>, state: web::Data<AppState>) -> impl Responder {
+    if !state.api_keys.accepts(&body.api_key) {
+        return HttpResponse::Unauthorized().body("Invalid API key");
+    }
+    HttpResponse::Ok().body("API key is valid")
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 enum WsMessage {
-    Auth { id: Uuid, password: String },
-    SetAddress { ip: String, port: u16 },
+    /// `capabilities` are the optional features the node supports; see `capabilities::negotiate`.
+    /// `protocol_version` is checked against `WS_PROTOCOL_MIN`/`WS_PROTOCOL_MAX`; absent means 1.
+    Auth {
+        id: Uuid,
+        password: String,
+        #[serde(default)]
+        capabilities: Option<Vec<String>>,
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+    AuthToken {
+        token: String,
+        #[serde(default)]
+        capabilities: Option<Vec<String>>,
+        #[serde(default)]
+        protocol_version: Option<u32>,
+    },
+    SetAddress {
+        ip: String,
+        port: u16,
+        #[serde(default)]
+        expected_version: Option<u64>,
+    },
+    /// Moves the node into `pool`, or out of any pool when it's null or absent.
+    SetPool {
+        #[serde(default)]
+        pool: Option<String>,
+    },
+    /// Relays `payload` to every other authenticated node, or only those sharing one of `tags`.
+    Broadcast {
+        payload: serde_json::Value,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    /// Replaces the node's metadata map (see `MAX_METADATA_KEYS` and friends for limits).
+    SetMetadata { map: HashMap<String, String> },
+    /// Renames the live node (see `validate_node_name`). Reconnecting resets the default name.
+    SetName { name: String },
+    /// Asks for the operational settings this node should follow; answered with `Config`.
+    GetConfig,
+    /// Lists the other active nodes, optionally only those in `pool` or sharing one of `tags`.
+    ListPeers {
+        #[serde(default)]
+        pool: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    /// Relays `payload` to a single node's live session.
+    SendTo {
+        target_id: Uuid,
+        payload: serde_json::Value,
+    },
+    /// Reports a node-side problem, shown as `last_error` on the node. `unhealthy` also marks
+    /// the node `Unhealthy` until its next heartbeat or address update.
+    ReportError {
+        code: String,
+        message: String,
+        #[serde(default)]
+        unhealthy: bool,
+    },
+}
+
+/// Inbound frame: a `WsMessage` plus an optional client-chosen `request_id`.
+#[derive(Deserialize)]
+struct WsEnvelope {
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    message: serde_json::Value,
+}
+
+/// Outbound frame. `request_id` echoes the message being answered; unsolicited
+/// responses (relayed messages, disconnects) carry none.
+#[derive(Serialize)]
+struct WsReply<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    #[serde(flatten)]
+    response: &'a WsResponse,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum WsResponse {
+    Authenticated {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Features enabled for this session.
+        capabilities: Vec<String>,
+    },
+    AddressUpdated {
+        version: u64,
+    },
+    ErrorReported,
+    PoolUpdated {
+        pool: Option<String>,
+    },
+    MetadataUpdated {
+        keys: usize,
+    },
+    NameUpdated {
+        name: String,
+    },
+    /// A versioned update lost a race; `current_version` is what the client should retry against.
+    Conflict {
+        current_version: u64,
+    },
+    /// Acknowledges a `Broadcast` with the number of sessions it was relayed to.
+    Broadcasted {
+        recipients: usize,
+    },
+    /// Acknowledges a `SendTo` once the payload is handed to the target's session.
+    Sent {
+        target_id: Uuid,
+    },
+    Peers {
+        nodes: Vec<PeerInfo>,
+    },
+    Config(NodeConfig),
+    /// A payload relayed from node `from`.
+    Message {
+        from: Uuid,
+        payload: serde_json::Value,
+    },
+    Error {
+        message: String,
+        /// When the request was refused for coming too soon: how long to wait before retrying.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+}
+
+/// What a node may learn about its peers: enough to connect, without `mac_id` or `source_ip`.
+#[derive(Serialize)]
+struct PeerInfo {
+    id: Uuid,
+    name: String,
+    ip: String,
+    port: u16,
+    status: NodeStatus,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
+    capabilities: Vec<String>,
+}
+
+impl From<&ProxyNode> for PeerInfo {
+    fn from(node: &ProxyNode) -> Self {
+        PeerInfo {
+            id: node.id,
+            name: node.name.clone(),
+            ip: node.ip.clone(),
+            port: node.port,
+            status: node.status,
+            tags: node.tags.clone(),
+            pool: node.pool.clone(),
+            capabilities: node.capabilities.clone(),
+        }
+    }
+}
+
+/// Settings a node should follow, from the server config and its registration.
+#[derive(Serialize)]
+struct NodeConfig {
+    node_id: Uuid,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
+    /// Features enabled for this session.
+    capabilities: Vec<String>,
+    /// How often to send a heartbeat or address update.
+    heartbeat_interval_secs: u64,
+    /// Idle sessions are closed after this long; 0 means never.
+    inactivity_timeout_secs: u64,
+    max_message_bytes: usize,
+    messages_per_sec: f64,
+    message_burst: f64,
+    broadcasts_per_sec: f64,
+    broadcast_burst: f64,
+}
+
+fn address_update_limit_message(limiter: &AddressUpdateLimiter) -> String {
+    format!(
+        "Address updates are limited to one per {}ms",
+        limiter.interval().as_millis()
+    )
+}
+
+/// `ListPeers` is meant for occasional discovery, not polling.
+const PEER_LISTS_PER_SEC: f64 = 0.2;
+const PEER_LIST_BURST: f64 = 3.0;
+
+/// `ReportError` codes become metric labels, so they're kept short and simple.
+const MAX_ERROR_CODE_LEN: usize = 64;
+const MAX_ERROR_MESSAGE_LEN: usize = 1024;
+/// Per node id, in `AppState::error_reports`.
+const ERROR_REPORTS_PER_SEC: f64 = 0.2;
+const ERROR_REPORT_BURST: f64 = 5.0;
+
+impl WsResponse {
+    fn error(message: &str) -> Self {
+        WsResponse::Error {
+            message: message.to_string(),
+            retry_after_ms: None,
+        }
+    }
 }
 
 struct ProxyWsSession {
     id: Uuid,
-    nodes: ActiveNodes,
-    reg_nodes: RegisteredNodes,
+    /// Unique per connection, unlike `id` which becomes the node id on auth.
+    session_id: Uuid,
+    state: web::Data<AppState>,
     authed: bool,
     mac_id: String,
+    /// The authenticated node's tenant; peers and relays are limited to it.
+    tenant: Option<String>,
+    source_ip: IpAddr,
+    connected_at: DateTime<Utc>,
+    connection: ConnectionInfo,
+    /// Node identity already proven by a client certificate during the TLS handshake.
+    cert_identity: Option<RegisteredNode>,
+    /// Limits inbound application messages; control frames don't count.
+    rate_limit: TokenBucket,
+    /// Further limits `Broadcast`, which fans out to every session.
+    broadcast_limit: TokenBucket,
+    peers_limit: TokenBucket,
+    /// Collects `Continuation` frames until a fragmented message is complete.
+    fragments: Reassembler,
+    /// Enabled features, negotiated on auth.
+    capabilities: Vec<String>,
+    /// Whether frames are currently deflated (the `compression` capability, once authenticated).
+    compress: bool,
+    /// `request_id` of the inbound message currently being handled.
+    request_id: Option<String>,
+    /// Last text/binary frame, for the inactivity timeout. Control frames don't count.
+    last_app_message: Instant,
+    /// Set when the server closes the session, for the audited disconnect reason.
+    closed_by: Option<(DisconnectReason, &'static str)>,
+}
+
+impl ProxyWsSession {
+    /// Sends `response`, tagged with the `request_id` of the message being handled (if any).
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, response: WsResponse) {
+        let reply = WsReply {
+            request_id: self.request_id.as_deref(),
+            response: &response,
+        };
+        let Ok(text) = serde_json::to_string(&reply) else {
+            return;
+        };
+        if self.compress && text.len() >= compression::MIN_COMPRESS_LEN {
+            if let Ok(bytes) = compression::deflate(text.as_bytes()) {
+                ctx.binary(bytes);
+            }
+        } else {
+            ctx.text(text);
+        }
+    }
+
+    /// Confirms authentication, then switches to compressed frames if negotiated.
+    /// The confirmation itself is always plain text.
+    fn send_authenticated(&mut self, ctx: &mut ws::WebsocketContext<Self>, token: Option<String>) {
+        let capabilities = self.capabilities.clone();
+        self.send(
+            ctx,
+            WsResponse::Authenticated {
+                token,
+                capabilities,
+            },
+        );
+        self.compress = self.has_capability(capabilities::COMPRESSION);
+    }
+
+    /// Parses and dispatches one inbound JSON message.
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let Ok(envelope) = serde_json::from_str::<WsEnvelope>(text) else {
+            self.send(ctx, WsResponse::error("Invalid message format"));
+            return;
+        };
+        self.request_id = envelope.request_id;
+        match serde_json::from_value::<WsMessage>(envelope.message) {
+            Ok(message) => self.handle_message(message, ctx),
+            Err(_) => self.send(ctx, WsResponse::error("Invalid message format")),
+        }
+        self.request_id = None;
+    }
+
+    /// Marks the session as the given node and adds it to `ActiveNodes`.
+    /// Returns false (and closes the session) when the active node limit is reached.
+    ///
+    /// If another session is already live for the same node id, the newest connection wins:
+    /// ownership moves to this session and the old one is told to close.
+    ///
+    /// Takes the locked maps rather than locking them itself, so the insert can't be skipped
+    /// under contention; see `authenticate_with`.
+    fn authenticate(
+        &mut self,
+        reg_node: RegisteredNode,
+        active_nodes: &mut HashMap<Uuid, ProxyNode>,
+        sessions: &mut HashMap<Uuid, SessionHandle>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        let full = limit_reached(self.state.config.max_active_nodes, active_nodes.len());
+        if !active_nodes.contains_key(&reg_node.id) && full {
+            self.reject(ctx, Rejection::ActiveNodeLimit);
+            return false;
+        }
+        let mut proxy_node = ProxyNode::new(&reg_node, self.source_ip);
+        proxy_node.capabilities = self.capabilities.clone();
+        // A reconnect replacing its own entry isn't churn.
+        if active_nodes
+            .insert(reg_node.id, proxy_node.clone())
+            .is_none()
+        {
+            self.state.metrics.count_join();
+        }
+        events::publish(&self.state.events, NodeEvent::Joined { node: proxy_node });
+
+        let handle = SessionHandle {
+            session_id: self.session_id,
+            addr: ctx.address(),
+            tags: reg_node.tags.clone(),
+            tenant: reg_node.tenant.clone(),
+            mac_id: reg_node.mac_id.clone(),
+            connected_at: self.connected_at,
+            source_ip: self.source_ip,
+            capabilities: self.capabilities.clone(),
+            connection: self.connection.clone(),
+        };
+        if let Some(previous) = sessions.insert(reg_node.id, handle) {
+            previous.addr.do_send(Disconnect(Rejection::Superseded));
+        }
+
+        println!(
+            "ws session {} authenticated as node {}",
+            self.session_id, reg_node.id
+        );
+        self.state.audit.record(AuditEvent::Auth {
+            session_id: self.session_id,
+            node_id: Some(reg_node.id),
+            mac_id: Some(reg_node.mac_id.clone()),
+            source_ip: self.source_ip,
+            success: true,
+        });
+        self.authed = true;
+        self.id = reg_node.id;
+        self.mac_id = reg_node.mac_id;
+        self.tenant = reg_node.tenant;
+        true
+    }
+
+    fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|enabled| enabled == name)
+    }
+
+    /// Relays `payload` to the other live relay-capable sessions matching `tags`
+    /// (all of them if empty) and returns how many it went to.
+    fn broadcast(
+        &self,
+        sessions: &HashMap<Uuid, SessionHandle>,
+        payload: serde_json::Value,
+        tags: &[String],
+    ) -> usize {
+        let mut recipients = 0;
+        for (id, handle) in sessions.iter() {
+            if *id == self.id || handle.tenant != self.tenant {
+                continue;
+            }
+            if !(tags.is_empty() || handle.tags.iter().any(|t| tags.contains(t))) {
+                continue;
+            }
+            if !handle.capabilities.iter().any(|c| c == capabilities::RELAY) {
+                continue;
+            }
+            handle.addr.do_send(Relay {
+                from: self.id,
+                payload: payload.clone(),
+            });
+            recipients += 1;
+        }
+        recipients
+    }
+
+    /// Relays `payload` to `target`'s live session.
+    fn send_to(
+        &self,
+        sessions: &HashMap<Uuid, SessionHandle>,
+        target: &Uuid,
+        payload: serde_json::Value,
+    ) -> Result<(), &'static str> {
+        let handle = sessions
+            .get(target)
+            .filter(|handle| handle.tenant == self.tenant)
+            .ok_or("Target node is not connected")?;
+        if !handle.capabilities.iter().any(|c| c == capabilities::RELAY) {
+            return Err("Target node does not support relay");
+        }
+        handle.addr.do_send(Relay {
+            from: self.id,
+            payload,
+        });
+        Ok(())
+    }
+
+    /// Dispatches a parsed inbound message.
+    fn handle_message(&mut self, message: WsMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match message {
+            WsMessage::Auth {
+                id,
+                password,
+                capabilities,
+                protocol_version,
+            } => {
+                if self.authed {
+                    self.send(ctx, WsResponse::error("Already authenticated"));
+                    return;
+                }
+                if !self.check_protocol(protocol_version, ctx) {
+                    return;
+                }
+                if self.state.node_auth_throttle.banned_for(&id).is_some() {
+                    self.state.stats.record_ws_auth_failure();
+                    self.audit_auth_failure(Some(id));
+                    self.reject(ctx, Rejection::AuthBanned);
+                    return;
+                }
+                self.capabilities = capabilities::negotiate(capabilities.as_deref());
+                let reg_nodes = self.state.registered_nodes.clone();
+                let lookup = async move { db::verify_node(&reg_nodes, &id, &password).await };
+                self.authenticate_with(lookup, Some(id), ctx);
+            }
+            WsMessage::AuthToken {
+                token,
+                capabilities,
+                protocol_version,
+            } => {
+                if self.authed {
+                    self.send(ctx, WsResponse::error("Already authenticated"));
+                    return;
+                }
+                if !self.check_protocol(protocol_version, ctx) {
+                    return;
+                }
+                self.capabilities = capabilities::negotiate(capabilities.as_deref());
+                // Deregistering revokes a node's tokens: they only name the registration
+                // they were issued for.
+                let reg_nodes = self.state.registered_nodes.clone();
+                let lookup = async move {
+                    match auth::validate_node_jwt(&token) {
+                        Ok(token) => db::find_node(&reg_nodes, &token).await,
+                        Err(_) => None,
+                    }
+                };
+                self.authenticate_with(lookup, None, ctx);
+            }
+            WsMessage::SetAddress {
+                ip,
+                port,
+                expected_version,
+            } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if let Err(reason) = validate_address(&ip, port) {
+                    self.send(ctx, WsResponse::error(reason));
+                    return;
+                }
+                let active_nodes = self.state.active_nodes.clone().lock_owned();
+                self.reply_when(active_nodes, ctx, move |act, mut map| {
+                    // Checked and recorded under the active-node lock, like the HTTP endpoint.
+                    let limiter = &act.state.address_updates;
+                    if let Some(wait) = limiter.retry_after(&act.id) {
+                        return WsResponse::Error {
+                            message: address_update_limit_message(limiter),
+                            retry_after_ms: Some(wait.as_millis().max(1) as u64),
+                        };
+                    }
+                    let Some(node) = map.get_mut(&act.id) else {
+                        return WsResponse::error("Node is no longer active");
+                    };
+                    let version = match node.set_address(ip, port, expected_version) {
+                        Ok(version) => version,
+                        Err(current_version) => return WsResponse::Conflict { current_version },
+                    };
+                    limiter.record(&act.id);
+                    act.state.audit.record(AuditEvent::AddressSet {
+                        session_id: Some(act.session_id),
+                        node_id: act.id,
+                        mac_id: act.mac_id.clone(),
+                        source_ip: act.source_ip,
+                        ip: node.ip.clone(),
+                        port: node.port,
+                    });
+                    let node = node.clone();
+                    events::publish(&act.state.events, NodeEvent::Updated { node });
+                    WsResponse::AddressUpdated { version }
+                });
+            }
+            WsMessage::SetPool { pool } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if let Some(Err(err)) = pool.as_deref().map(validate_pool_name) {
+                    let message = err.message.unwrap_or_default();
+                    self.send(
+                        ctx,
+                        WsResponse::error(&format!("Invalid pool: {}", message)),
+                    );
+                    return;
+                }
+                // Also saved on the registration so the pool survives reconnects.
+                // Registrations before active nodes, as in `import_topology`.
+                let registered_nodes = self.state.registered_nodes.clone();
+                let active_nodes = self.state.active_nodes.clone();
+                let locks = async move {
+                    let reg_nodes = registered_nodes.lock_owned().await;
+                    (reg_nodes, active_nodes.lock_owned().await)
+                };
+                self.reply_when(locks, ctx, move |act, (mut reg_nodes, mut map)| {
+                    if let Some(reg_node) = reg_nodes.get_mut(&act.id) {
+                        reg_node.pool = pool.clone();
+                        act.state.registered_nodes_cache.invalidate();
+                    }
+                    if let Some(node) = map.get_mut(&act.id) {
+                        node.pool = pool.clone();
+                        node.touch();
+                        let node = node.clone();
+                        events::publish(&act.state.events, NodeEvent::Updated { node });
+                    }
+                    WsResponse::PoolUpdated { pool }
+                });
+            }
+            WsMessage::Broadcast { payload, tags } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if !self.has_capability(capabilities::RELAY) {
+                    self.send(ctx, WsResponse::error("Relay capability not enabled"));
+                    return;
+                }
+                if !self.broadcast_limit.try_take() {
+                    self.send(ctx, WsResponse::error("Broadcast rate limit exceeded"));
+                    return;
+                }
+                let sessions = self.state.sessions.clone().lock_owned();
+                self.reply_when(sessions, ctx, move |act, sessions| {
+                    let recipients = act.broadcast(&sessions, payload, &tags);
+                    WsResponse::Broadcasted { recipients }
+                });
+            }
+            WsMessage::SetMetadata { map } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if let Err(reason) = validate_metadata(&map) {
+                    self.send(ctx, WsResponse::error(reason));
+                    return;
+                }
+                let keys = map.len();
+                let active_nodes = self.state.active_nodes.clone().lock_owned();
+                self.reply_when(active_nodes, ctx, move |act, mut nodes| {
+                    let Some(node) = nodes.get_mut(&act.id) else {
+                        return WsResponse::error("Node is no longer active");
+                    };
+                    node.metadata = map.into_iter().collect();
+                    node.touch();
+                    let node = node.clone();
+                    events::publish(&act.state.events, NodeEvent::Updated { node });
+                    WsResponse::MetadataUpdated { keys }
+                });
+            }
+            WsMessage::SetName { name } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if let Err(err) = validate_node_name(&name) {
+                    let message = err.message.unwrap_or_default();
+                    self.send(
+                        ctx,
+                        WsResponse::error(&format!("Invalid name: {}", message)),
+                    );
+                    return;
+                }
+                let active_nodes = self.state.active_nodes.clone().lock_owned();
+                self.reply_when(active_nodes, ctx, move |act, mut nodes| {
+                    let Some(node) = nodes.get_mut(&act.id) else {
+                        return WsResponse::error("Node is no longer active");
+                    };
+                    node.name = name.clone();
+                    node.touch();
+                    let node = node.clone();
+                    events::publish(&act.state.events, NodeEvent::Updated { node });
+                    WsResponse::NameUpdated { name }
+                });
+            }
+            WsMessage::GetConfig => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                let reg_nodes = self.state.registered_nodes.clone().lock_owned();
+                self.reply_when(reg_nodes, ctx, |act, reg_nodes| {
+                    let Some(reg_node) = reg_nodes.get(&act.id) else {
+                        return WsResponse::error("Node is no longer registered");
+                    };
+                    let config = &act.state.config;
+                    WsResponse::Config(NodeConfig {
+                        node_id: act.id,
+                        tags: reg_node.tags.clone(),
+                        pool: reg_node.pool.clone(),
+                        capabilities: act.capabilities.clone(),
+                        heartbeat_interval_secs: config.heartbeat_interval.as_secs(),
+                        inactivity_timeout_secs: config.ws_inactivity_timeout.as_secs(),
+                        max_message_bytes: config.ws_max_message_bytes,
+                        messages_per_sec: config.ws_messages_per_sec,
+                        message_burst: config.ws_message_burst,
+                        broadcasts_per_sec: config.ws_broadcasts_per_sec,
+                        broadcast_burst: config.ws_broadcast_burst,
+                    })
+                });
+            }
+            WsMessage::ListPeers { pool, tags } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if !self.peers_limit.try_take() {
+                    self.send(ctx, WsResponse::error("ListPeers rate limit exceeded"));
+                    return;
+                }
+                let active_nodes = self.state.active_nodes.clone().lock_owned();
+                self.reply_when(active_nodes, ctx, move |act, map| {
+                    let nodes = map
+                        .values()
+                        .filter(|node| node.id != act.id && node.tenant == act.tenant)
+                        .filter(|node| pool.is_none() || node.pool == pool)
+                        .filter(|node| {
+                            tags.is_empty() || node.tags.iter().any(|t| tags.contains(t))
+                        })
+                        .map(PeerInfo::from)
+                        .collect();
+                    WsResponse::Peers { nodes }
+                });
+            }
+            WsMessage::ReportError {
+                code,
+                message,
+                unhealthy,
+            } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if !self.state.error_reports.try_take(&self.id) {
+                    self.send(ctx, WsResponse::error("ReportError rate limit exceeded"));
+                    return;
+                }
+                let code_ok = !code.is_empty()
+                    && code.len() <= MAX_ERROR_CODE_LEN
+                    && code
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+                if !code_ok {
+                    self.send(
+                        ctx,
+                        WsResponse::error("code must be 1-64 letters, digits, '-', '_' or '.'"),
+                    );
+                    return;
+                }
+                let message: String = message.chars().take(MAX_ERROR_MESSAGE_LEN).collect();
+                eprintln!("Node {} reported error {}: {}", self.id, code, message);
+                self.state.metrics.count_node_error(&code);
+                let active_nodes = self.state.active_nodes.clone().lock_owned();
+                self.reply_when(active_nodes, ctx, move |act, mut map| {
+                    let Some(node) = map.get_mut(&act.id) else {
+                        return WsResponse::error("Node is no longer active");
+                    };
+                    node.last_error = Some(NodeError {
+                        code,
+                        message,
+                        reported_at: Utc::now(),
+                    });
+                    if unhealthy {
+                        node.status = NodeStatus::Unhealthy;
+                    }
+                    node.touch();
+                    let node = node.clone();
+                    events::publish(&act.state.events, NodeEvent::Updated { node });
+                    WsResponse::ErrorReported
+                });
+            }
+            WsMessage::SendTo { target_id, payload } => {
+                if !self.authed {
+                    self.send(ctx, WsResponse::error("Not authenticated"));
+                    return;
+                }
+                if !self.has_capability(capabilities::RELAY) {
+                    self.send(ctx, WsResponse::error("Relay capability not enabled"));
+                    return;
+                }
+                let sessions = self.state.sessions.clone().lock_owned();
+                self.reply_when(sessions, ctx, move |act, sessions| {
+                    match act.send_to(&sessions, &target_id, payload) {
+                        Ok(()) => WsResponse::Sent { target_id },
+                        Err(reason) => WsResponse::error(reason),
+                    }
+                });
+            }
+        }
+    }
+
+    fn reject(&mut self, ctx: &mut ws::WebsocketContext<Self>, rejection: Rejection) {
+        self.closed_by = Some((rejection.disconnect_reason(), rejection.message()));
+        self.send(ctx, WsResponse::error(rejection.message()));
+        let description = match rejection.retry_after(&self.state.config) {
+            Some(base) => {
+                // Spread by up to half again, so drained nodes don't all come back at once.
+                let base = base.as_secs().max(1);
+                let jitter = (self.session_id.as_u128() % (base as u128 / 2 + 1)) as u64;
+                serde_json::to_string(&CloseHint {
+                    reason: rejection.message(),
+                    retry_after_secs: base + jitter,
+                })
+                .unwrap_or_else(|_| rejection.message().to_string())
+            }
+            None => rejection.message().to_string(),
+        };
+        ctx.close(Some(ws::CloseReason {
+            code: rejection.close_code(),
+            description: Some(description),
+        }));
+        ctx.stop();
+    }
+
+    /// Closes the session if `version` is outside the configured range, and logs nodes on
+    /// versions older than the newest accepted one, which are next in line for removal.
+    fn check_protocol(
+        &mut self,
+        version: Option<u32>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        let config = &self.state.config;
+        let version = version.unwrap_or(1);
+        if version < config.ws_protocol_min || version > config.ws_protocol_max {
+            let reason = format!(
+                "Unsupported protocol version {}; supported versions are {}-{}",
+                version, config.ws_protocol_min, config.ws_protocol_max
+            );
+            self.send(ctx, WsResponse::error(&reason));
+            self.closed_by = Some((DisconnectReason::Rejected, "Unsupported protocol version"));
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Other(4002),
+                description: Some(reason),
+            }));
+            ctx.stop();
+            return false;
+        }
+        if version < config.ws_protocol_max {
+            eprintln!(
+                "Deprecated: ws session {} uses protocol version {} (newest is {})",
+                self.session_id, version, config.ws_protocol_max
+            );
+        }
+        true
+    }
+
+    fn audit_auth_failure(&self, node_id: Option<Uuid>) {
+        self.state.audit.record(AuditEvent::Auth {
+            session_id: self.session_id,
+            node_id,
+            mac_id: None,
+            source_ip: self.source_ip,
+            success: false,
+        });
+    }
+
+    /// Resolves `lookup` before handling further messages, then authenticates as the node found.
+    /// `password_id` is the node id claimed by a password `Auth`: a fresh node token is issued
+    /// on success, and failures count towards that id's ban.
+    fn authenticate_with<F>(
+        &mut self,
+        lookup: F,
+        password_id: Option<Uuid>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) where
+        F: Future<Output = Option<RegisteredNode>> + 'static,
+    {
+        // The reply is sent after `handle` returns, so carry the request id along.
+        let request_id = self.request_id.clone();
+        let sessions = self.state.sessions.clone();
+        let active_nodes = self.state.active_nodes.clone();
+        let lookup = async move {
+            let reg_node = lookup.await?;
+            // Awaited, not `try_lock`ed: an authenticated node must always end up in the maps.
+            // Same order as `stopped`.
+            let sessions = sessions.lock_owned().await;
+            let active_nodes = active_nodes.lock_owned().await;
+            Some((reg_node, sessions, active_nodes))
+        };
+        ctx.wait(lookup.into_actor(self).map(move |found, act, ctx| {
+            act.request_id = request_id;
+            match found {
+                Some((reg_node, mut sessions, mut active_nodes)) => {
+                    let token = password_id.map(|id| {
+                        act.state.node_auth_throttle.reset(&id);
+                        auth::create_node_jwt(&reg_node.id, &reg_node.token_nonce)
+                    });
+                    if act.authenticate(reg_node, &mut active_nodes, &mut sessions, ctx) {
+                        act.send_authenticated(ctx, token);
+                    }
+                }
+                None => {
+                    act.state.stats.record_ws_auth_failure();
+                    act.audit_auth_failure(password_id);
+                    match password_id {
+                        Some(id) if act.state.node_auth_throttle.record_failure(&id) => {
+                            eprintln!(
+                                "Node {} banned from ws auth for {}s after repeated failures (from {})",
+                                id,
+                                act.state.config.node_auth_ban.as_secs(),
+                                act.source_ip
+                            );
+                            act.reject(ctx, Rejection::AuthBanned);
+                        }
+                        _ => act.reject(ctx, Rejection::AuthFailed),
+                    }
+                }
+            }
+            act.request_id = None;
+        }));
+    }
+
+    /// Waits for `pending` (usually a lock) before handling further messages, then replies
+    /// with what `respond` makes of its output. Handlers use this instead of `try_lock`, so a
+    /// busy map delays the reply rather than dropping the request.
+    fn reply_when<F, R>(&mut self, pending: F, ctx: &mut ws::WebsocketContext<Self>, respond: R)
+    where
+        F: Future + 'static,
+        R: FnOnce(&mut Self, F::Output) -> WsResponse + 'static,
+    {
+        // As in `authenticate_with`, the reply goes out after `handle` returns.
+        let request_id = self.request_id.clone();
+        ctx.wait(pending.into_actor(self).map(move |output, act, ctx| {
+            act.request_id = request_id;
+            let response = respond(act, output);
+            act.send(ctx, response);
+            act.request_id = None;
+        }));
+    }
 }
 
 impl Actor for ProxyWsSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        println!(
+            "ws session {} opened from {} ({})",
+            self.session_id, self.source_ip, self.connection
+        );
+        self.state.audit.record(AuditEvent::Connect {
+            session_id: self.session_id,
+            source_ip: self.source_ip,
+        });
+        if let Some(reg_node) = self.cert_identity.take() {
+            self.authenticate_with(future::ready(Some(reg_node)), None, ctx);
+        }
+
+        let timeout = self.state.config.ws_inactivity_timeout;
+        if !timeout.is_zero() {
+            ctx.run_interval(timeout / 2, move |act, ctx| {
+                if act.authed && act.last_app_message.elapsed() >= timeout {
+                    act.reject(ctx, Rejection::Inactive);
+                }
+            });
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.state.stats.close_ws();
+        println!(
+            "ws session {} closed (request_id={})",
+            self.session_id, self.connection.request_id
+        );
+        let (reason, detail) = match self.closed_by {
+            Some((reason, detail)) => (reason, Some(detail)),
+            None => (DisconnectReason::Graceful, None),
+        };
+        self.state.audit.record(AuditEvent::Disconnect {
+            session_id: self.session_id,
+            node_id: self.authed.then_some(self.id),
+            mac_id: self.authed.then(|| self.mac_id.clone()),
+            source_ip: self.source_ip,
+            reason,
+            detail,
+        });
         if !self.authed {
-            ctx.text("Authentication required");
-            ctx.close(None);
-            ctx.stop();
             return;
         }
 
-        let proxy_node = ProxyNode {
-            id: self.id,
-            name: format!("node-{}", &self.id.to_string()[..8]),
-            ip: "unknown".to_string(),
-            port: 0,
-            active: true,
-            mac_id: self.mac_id.clone(),
-        };
+        // The actor can't wait for the locks here, and skipping the removal when they're busy
+        // would leave a dead node listed, so finish in a task.
+        let state = self.state.clone();
+        let (id, session_id) = (self.id, self.session_id);
+        actix_web::rt::spawn(async move {
+            // A superseded session no longer owns the node entry; leave it to the new owner.
+            let mut sessions = state.sessions.lock().await;
+            match sessions.get(&id) {
+                Some(owner) if owner.session_id == session_id => {
+                    sessions.remove(&id);
+                }
+                _ => return,
+            }
+            if state.active_nodes.lock().await.remove(&id).is_some() {
+                state.metrics.count_leave();
+                events::publish(&state.events, NodeEvent::Left { id });
+            }
+        });
+    }
+}
+
+impl Handler<Disconnect> for ProxyWsSession {
+    type Result = ();
+
+    fn handle(&mut self, Disconnect(rejection): Disconnect, ctx: &mut Self::Context) {
+        self.reject(ctx, rejection);
+    }
+}
+
+impl Handler<Relay> for ProxyWsSession {
+    type Result = ();
+
+    fn handle(&mut self, relay: Relay, ctx: &mut Self::Context) {
+        self.send(
+            ctx,
+            WsResponse::Message {
+                from: relay.from,
+                payload: relay.payload,
+            },
+        );
+    }
+}
+
+impl ProxyWsSession {
+    /// Handles a whole application message, whether it arrived in one frame or several.
+    fn handle_data(&mut self, message: ws::Message, ctx: &mut ws::WebsocketContext<Self>) {
+        self.last_app_message = Instant::now();
+        if !self.rate_limit.try_take() {
+            self.reject(ctx, Rejection::RateLimited);
+            return;
+        }
+
+        match message {
+            ws::Message::Text(text) => self.handle_text(&text, ctx),
+            ws::Message::Binary(bytes) if self.compress => {
+                let max_len = self.state.config.ws_max_message_bytes;
+                match compression::inflate(&bytes, max_len).map(String::from_utf8) {
+                    Ok(Ok(text)) => self.handle_text(&text, ctx),
+                    // Same as an uncompressed message over the limit.
+                    Err(err) if err.kind() == std::io::ErrorKind::FileTooLarge => {
+                        self.reject(ctx, Rejection::FrameTooLarge)
+                    }
+                    _ => self.send(ctx, WsResponse::error("Invalid compressed message")),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ProxyWsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(message @ (ws::Message::Text(_) | ws::Message::Binary(_))) => {
+                self.handle_data(message, ctx)
+            }
+            Ok(ws::Message::Continuation(item)) => match self.fragments.push(item) {
+                Ok(Some(message)) => self.handle_data(message, ctx),
+                Ok(None) => (),
+                Err(rejection) => self.reject(ctx, rejection),
+            },
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Pong(_)) => (),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(ws::ProtocolError::Overflow) => self.reject(ctx, Rejection::FrameTooLarge),
+            Err(_) => self.reject(ctx, Rejection::ProtocolError),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    /// User token for clients that can't set headers on the upgrade (browsers).
+    token: Option<String>,
+}
+
+/// The node publishing protocol. `/ws/` is the original path, kept for deployed nodes.
+///
+/// Outside the bearer-protected scope so browsers, which can't set `Authorization` on a ws
+/// handshake, can pass the user token as `?token=` instead. The header wins when both are
+/// given.
+#[routes]
+#[get("/ws/")]
+#[get("/ws/node")]
+async fn ws_index(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<WsQuery>,
+    bearer: Option<BearerAuth>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    if let Err(response) = auth::ws_claims(&req, bearer.as_ref(), query.token.as_deref()) {
+        return Ok(response);
+    }
+
+    // Checked before any per-session state is built; `stopped` releases the slot.
+    if !state.stats.try_open_ws(state.config.max_ws_connections) {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((
+                header::RETRY_AFTER,
+                state.config.heartbeat_interval.as_secs().to_string(),
+            ))
+            .body("Too many ws connections"));
+    }
+
+    // With mTLS, a client cert matching a registered node skips the password Auth step.
+    let cert_identity = match req.conn_data::<tls::ClientCertFingerprint>() {
+        Some(tls::ClientCertFingerprint(fingerprint)) => state
+            .registered_nodes
+            .lock()
+            .await
+            .values()
+            .find(|node| node.cert_fingerprint.as_ref() == Some(fingerprint) && !node.is_expired())
+            .cloned(),
+        None => None,
+    };
+
+    let rate_limit = TokenBucket::new(
+        state.config.ws_messages_per_sec,
+        state.config.ws_message_burst,
+    );
+    let broadcast_limit = TokenBucket::new(
+        state.config.ws_broadcasts_per_sec,
+        state.config.ws_broadcast_burst,
+    );
+    let max_message_bytes = state.config.ws_max_message_bytes;
+    let session = ProxyWsSession {
+        id: Uuid::new_v4(),
+        session_id: Uuid::new_v4(),
+        state: state.clone(),
+        authed: false,
+        mac_id: String::new(),
+        tenant: None,
+        source_ip: client_ip::real_client_ip(&req),
+        connected_at: Utc::now(),
+        connection: ConnectionInfo::from_request(&req),
+        cert_identity,
+        rate_limit,
+        broadcast_limit,
+        peers_limit: TokenBucket::new(PEER_LISTS_PER_SEC, PEER_LIST_BURST),
+        fragments: Reassembler::new(max_message_bytes),
+        // Cert-authenticated sessions never send `Auth`, so they start with the defaults.
+        capabilities: capabilities::negotiate(None),
+        compress: false,
+        request_id: None,
+        last_app_message: Instant::now(),
+        closed_by: None,
+    };
+
+    let response = ws::WsResponseBuilder::new(session, &req, stream)
+        .frame_size(max_message_bytes)
+        .start();
+    // A failed handshake never starts the actor, so `stopped` won't run for it.
+    if response.is_err() {
+        state.stats.close_ws();
+    }
+    response
+}
+
+/// Lists active nodes. Non-admin callers only see nodes sharing a tag with their token's scopes.
+/// `meta.<key>=<value>` query parameters keep only nodes whose metadata matches all of them.
+/// Results are ordered by id unless `sort` says otherwise; ties are broken by id.
+/// `fields=id,ip,port` trims each node to those fields; unknown names are ignored unless
+/// `strict=true`.
+#[utoipa::path(
+    params(
+        ("meta.{key}" = Option<String>, Query, description = "Metadata value to match"),
+        ("sort" = Option<String>, Query, description = "`id` (default), `name` or `connected_at`"),
+        ("fields" = Option<String>, Query, description = "Comma-separated `ProxyNode` fields to return"),
+        ("strict" = Option<bool>, Query, description = "Reject unknown `fields` names"),
+    ),
+    responses(
+        (status = 200, body = Vec<ProxyNode>),
+        (status = 400, description = "Unknown sort key, or unknown field with `strict=true`"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/nodes")]
+async fn nodes_endpoint(
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let meta_filters: Vec<(&str, &str)> = query
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix("meta.")?, value.as_str())))
+        .collect();
+    let sort = query.get("sort").map_or("id", String::as_str);
+    if !matches!(sort, "id" | "name" | "connected_at") {
+        return HttpResponse::BadRequest().body("sort must be one of id, name, connected_at");
+    }
+    // An empty `fields=` means no selection, i.e. full nodes.
+    let fields: Option<Vec<&str>> = query
+        .get("fields")
+        .map(|fields| {
+            fields
+                .split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|fields| !fields.is_empty());
+    if query.get("strict").is_some_and(|strict| strict == "true") {
+        let unknown = fields
+            .iter()
+            .flatten()
+            .find(|field| !PROXY_NODE_FIELDS.contains(field));
+        if let Some(unknown) = unknown {
+            return HttpResponse::BadRequest().body(format!("Unknown field {:?}", unknown));
+        }
+    }
+    let guard = state.active_nodes.lock().await;
+    let mut list: Vec<ProxyNode> = guard
+        .values()
+        .filter(|node| node.visible_to(&claims))
+        .filter(|node| {
+            meta_filters
+                .iter()
+                .all(|(key, value)| node.metadata.get(*key).map(String::as_str) == Some(*value))
+        })
+        .cloned()
+        .collect();
+    drop(guard);
+    match sort {
+        "name" => list.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id))),
+        "connected_at" => list.sort_by_key(|node| (node.connected_at, node.id)),
+        _ => list.sort_by_key(|node| node.id),
+    }
+    let Some(fields) = fields else {
+        return HttpResponse::Ok().json(list);
+    };
+    let trimmed: Vec<serde_json::Map<String, serde_json::Value>> = list
+        .iter()
+        .filter_map(|node| match serde_json::to_value(node) {
+            Ok(serde_json::Value::Object(mut map)) => {
+                map.retain(|key, _| fields.contains(&key.as_str()));
+                Some(map)
+            }
+            _ => None,
+        })
+        .collect();
+    HttpResponse::Ok().json(trimmed)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PickQuery {
+    tag: Option<String>,
+}
+
+/// The most recently seen pickable node among `nodes` that the caller may see,
+/// optionally restricted to `tag`.
+fn pick_from<'a>(
+    nodes: impl Iterator<Item = &'a ProxyNode>,
+    claims: &Claims,
+    tag: Option<&str>,
+) -> Option<&'a ProxyNode> {
+    nodes
+        .filter(|node| node.is_pickable())
+        .filter(|node| node.visible_to(claims))
+        .filter(|node| tag.is_none_or(|tag| node.tags.iter().any(|t| t == tag)))
+        .max_by_key(|node| node.last_seen)
+}
+
+fn no_eligible_node(state: &AppState) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((
+            header::RETRY_AFTER,
+            state.config.heartbeat_interval.as_secs().to_string(),
+        ))
+        .body("No eligible node available")
+}
+
+/// Largest `limit` accepted by `/nodes/search`, and its default.
+const MAX_SEARCH_LIMIT: usize = 500;
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+    /// Case-insensitive text matched against name, id prefix, ip, mac_id and tags.
+    q: String,
+    /// Page size, default 50, at most 500.
+    limit: Option<usize>,
+    /// Matches to skip, for the following pages.
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SearchResponse {
+    /// Matches in total, not just on this page.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    nodes: Vec<ProxyNode>,
+}
+
+impl ProxyNode {
+    /// Whether `needle` (already lowercased) occurs in the name, ip, mac_id or a tag, or
+    /// starts the id.
+    fn matches_search(&self, needle: &str) -> bool {
+        let contains = |value: &str| value.to_lowercase().contains(needle);
+        self.id.to_string().starts_with(needle)
+            || contains(&self.name)
+            || contains(&self.ip)
+            || contains(&self.mac_id)
+            || self.tags.iter().any(|tag| contains(tag))
+    }
+}
+
+/// Free-text search over the active nodes the caller may see, ordered by id and paged
+/// with `limit`/`offset`.
+#[utoipa::path(
+    params(SearchQuery),
+    responses(
+        (status = 200, body = SearchResponse),
+        (status = 400, description = "Empty q"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/nodes/search")]
+async fn search_nodes(
+    query: web::Query<SearchQuery>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let needle = query.q.trim().to_lowercase();
+    if needle.is_empty() {
+        return HttpResponse::BadRequest().body("q must not be empty");
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+    let mut matches: Vec<ProxyNode> = state
+        .active_nodes
+        .lock()
+        .await
+        .values()
+        .filter(|node| node.visible_to(&claims) && node.matches_search(&needle))
+        .cloned()
+        .collect();
+    matches.sort_by_key(|node| node.id);
+    let total = matches.len();
+    let nodes = matches.into_iter().skip(query.offset).take(limit).collect();
+    HttpResponse::Ok().json(SearchResponse {
+        total,
+        offset: query.offset,
+        limit,
+        nodes,
+    })
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ByMacQuery {
+    /// Only list MAC addresses with more than one active node.
+    #[serde(default)]
+    duplicates: bool,
+}
+
+/// The active nodes the caller may see, grouped by `mac_id` (each group ordered by id), to
+/// spot devices connected under several node ids.
+#[utoipa::path(
+    params(ByMacQuery),
+    responses((status = 200, body = BTreeMap<String, Vec<ProxyNode>>)),
+    security(("bearer" = []))
+)]
+#[get("/nodes/by-mac")]
+async fn nodes_by_mac(
+    query: web::Query<ByMacQuery>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let mut groups: BTreeMap<String, Vec<ProxyNode>> = BTreeMap::new();
+    for node in state.active_nodes.lock().await.values() {
+        if node.visible_to(&claims) {
+            groups
+                .entry(node.mac_id.clone())
+                .or_default()
+                .push(node.clone());
+        }
+    }
+    if query.duplicates {
+        groups.retain(|_, nodes| nodes.len() > 1);
+    }
+    for nodes in groups.values_mut() {
+        nodes.sort_by_key(|node| node.id);
+    }
+    let mut response = HttpResponse::Ok().json(groups);
+    response.extensions_mut().insert(json_case::MapBody);
+    response
+}
+
+/// One active node, subject to the same visibility rules as `/nodes`.
+#[utoipa::path(
+    params(("id" = Uuid, Path, description = "Node id")),
+    responses(
+        (status = 200, body = ProxyNode),
+        (status = 404, description = "No such active node"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/nodes/{id}")]
+async fn node_endpoint(
+    path: web::Path<Uuid>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let nodes = state.active_nodes.lock().await;
+    match nodes.get(&path.into_inner()) {
+        Some(node) if node.visible_to(&claims) => HttpResponse::Ok().json(node),
+        _ => HttpResponse::NotFound().body("Node not found"),
+    }
+}
 
-        let mut guard = self.nodes.try_lock();
-        if let Ok(ref mut map) = guard {
-            map.insert(self.id, proxy_node);
-        }
+/// Picks the most recently seen healthy node with a known address that the caller may see,
+/// optionally restricted to a tag. Responds 503 with `Retry-After` when none qualifies.
+#[utoipa::path(
+    params(PickQuery),
+    responses(
+        (status = 200, body = ProxyNode),
+        (status = 503, description = "No eligible node; see Retry-After"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/nodes/pick")]
+async fn pick_node(
+    query: web::Query<PickQuery>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let guard = state.active_nodes.lock().await;
+    match pick_from(guard.values(), &claims, query.tag.as_deref()) {
+        Some(node) => HttpResponse::Ok().json(node),
+        None => no_eligible_node(&state),
     }
+}
 
-    fn stopped(&mut self, _: &mut Self::Context) {
-        let mut guard = self.nodes.try_lock();
-        if let Ok(ref mut map) = guard {
-            map.remove(&self.id);
+#[utoipa::path(
+    params(("id" = Uuid, Path, description = "Node id")),
+    responses(
+        (status = 200, description = "Deregistered"),
+        (status = 403, description = "Only the node's owner or an admin may deregister it"),
+        (status = 404, description = "Node not registered"),
+    ),
+    security(("bearer" = []))
+)]
+#[delete("/registered-nodes/{id}")]
+async fn deregister(
+    path: web::Path<Uuid>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let id = path.into_inner();
+    {
+        let mut reg_nodes = state.registered_nodes.lock().await;
+        let Some(node) = reg_nodes
+            .get(&id)
+            .filter(|node| claims.in_tenant(node.tenant.as_deref()))
+        else {
+            return HttpResponse::NotFound().body("Node not registered");
+        };
+        if !claims.is_admin() && node.owner.as_deref() != Some(claims.sub.as_str()) {
+            return HttpResponse::Forbidden()
+                .body("Only the node's owner or an admin may deregister it");
         }
+        reg_nodes.remove(&id);
+        state.registered_nodes_cache.invalidate();
     }
+    disconnect_node(&state, id, Rejection::Deregistered).await;
+    HttpResponse::Ok().body("Deregistered successfully")
 }
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ProxyWsSession {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Text(text)) => match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::Auth { id, password }) => {
-                    if self.authed {
-                        ctx.text("Already authenticated");
-                        return;
-                    }
-                    let guard = self.reg_nodes.try_lock();
-                    if let Ok(reg_nodes) = guard {
-                        if let Some(reg_node) = reg_nodes.get(&id) {
-                            if reg_node.password == password {
-                                self.authed = true;
-                                self.id = id;
-                                self.mac_id = reg_node.mac_id.clone();
-                                ctx.text("Authenticated");
-                                return;
-                            }
-                        }
-                    }
-                    ctx.text("Authentication failed");
-                    ctx.close(None);
-                    ctx.stop();
-                }
-                Ok(WsMessage::SetAddress { ip, port }) => {
-                    if self.authed {
-                        let mut guard = self.nodes.try_lock();
-                        if let Ok(ref mut map) = guard {
-                            if let Some(node) = map.get_mut(&self.id) {
-                                node.ip = ip;
-                                node.port = port;
-                                ctx.text("Address updated");
-                                return;
-                            }
-                        }
-                    } else {
-                        ctx.text("Not authenticated");
-                    }
-                }
-                Err(_) => {
-                    ctx.text("Invalid message format");
-                }
-            },
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Pong(_)) => (),
-            Ok(ws::Message::Close(reason)) => {
-                ctx.close(reason);
-                ctx.stop();
-            }
-            _ => (),
-        }
+/// Closes the node's live session, if any, and drops it from the active nodes. For nodes
+/// that may no longer stay connected, e.g. because their registration is gone.
+async fn disconnect_node(state: &AppState, id: Uuid, rejection: Rejection) {
+    // Removed first, so the closing session's `stopped` leaves the active entry to us.
+    if let Some(handle) = state.sessions.lock().await.remove(&id) {
+        handle.addr.do_send(Disconnect(rejection));
+    }
+    if state.active_nodes.lock().await.remove(&id).is_some() {
+        state.metrics.count_leave();
+        events::publish(&state.events, NodeEvent::Left { id });
     }
 }
 
-#[get("/ws/")]
-async fn ws_index(
-    req: HttpRequest,
-    stream: web::Payload,
-    active_nodes: web::Data<ActiveNodes>,
-    registered_nodes: web::Data<RegisteredNodes>,
-) -> Result<HttpResponse, Error> {
-    let session = ProxyWsSession {
-        id: Uuid::new_v4(),
-        nodes: active_nodes.get_ref().clone(),
-        reg_nodes: registered_nodes.get_ref().clone(),
-        authed: false,
-        mac_id: String::new(),
+/// Registrations, without credentials; non-admins only see those in their own tenant.
+#[utoipa::path(
+    responses((status = 200, body = Vec<RegistrationInfo>)),
+    security(("bearer" = []))
+)]
+#[get("/registered-nodes")]
+async fn registered_nodes_endpoint(
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let guard = state.registered_nodes.lock().await;
+    // With a TTL, `expired` changes without a write, so the cached bytes could go stale.
+    let body = if claims.is_admin() && state.config.registration_ttl.is_zero() {
+        // Changes only on (de)registration but is polled often, so serve cached bytes.
+        state.registered_nodes_cache.get_or_render(|| {
+            let all: Vec<RegistrationInfo> = guard.values().map(RegistrationInfo::from).collect();
+            serde_json::to_vec(&all)
+        })
+    } else {
+        let visible: Vec<RegistrationInfo> = guard
+            .values()
+            .filter(|node| claims.in_tenant(node.tenant.as_deref()))
+            .map(RegistrationInfo::from)
+            .collect();
+        serde_json::to_vec(&visible).map(Into::into)
     };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(header::ContentType::json())
+            .body(body),
+        Err(err) => HttpResponse::from_error(JsonPayloadError::Serialize(err)),
+    }
+}
 
-    ws::start(session, &req, stream)
+#[derive(Serialize, ToSchema)]
+struct HealthResponse {
+    /// `ok`, or `degraded` when a sub-check is struggling. Either way the server is up.
+    status: &'static str,
+    /// Present when `AUDIT_LOG_PATH` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audit: Option<AuditHealth>,
 }
 
-#[get("/nodes")]
-async fn nodes_endpoint(data: web::Data<ActiveNodes>) -> impl Responder {
-    let guard = data.lock().await;
-    let list: Vec<ProxyNode> = guard.values().cloned().collect();
-    HttpResponse::Ok().json(list)
+/// Liveness, plus sub-checks. Always 200 while the server can answer, so a slow audit disk
+/// doesn't get the instance restarted.
+#[utoipa::path(responses((status = 200, body = HealthResponse)))]
+#[get("/health")]
+async fn health(state: web::Data<AppState>) -> impl Responder {
+    let audit = state.audit.health();
+    let degraded = audit.is_some_and(|audit| audit.is_degraded());
+    HttpResponse::Ok().json(HealthResponse {
+        status: if degraded { "degraded" } else { "ok" },
+        audit,
+    })
 }
 
-#[get("/registered-nodes")]
-async fn registered_nodes_endpoint(data: web::Data<RegisteredNodes>) -> impl Responder {
-    let guard = data.lock().await;
-    let list: Vec<RegisteredNode> = guard.values().cloned().collect();
-    HttpResponse::Ok().json(list)
+#[derive(Serialize, ToSchema)]
+struct VersionResponse {
+    name: &'static str,
+    version: &'static str,
+    /// Range of ws `protocol_version`s accepted; see `WS_PROTOCOL_MIN`/`WS_PROTOCOL_MAX`.
+    ws_protocol_min: u32,
+    ws_protocol_max: u32,
 }
 
-#[get("/health")]
-async fn health() -> impl Responder {
-    HttpResponse::Ok().body("OK")
+/// Server build and protocol versions, for deploy checks and for nodes choosing a protocol
+/// before they connect. Served during maintenance.
+#[utoipa::path(responses((status = 200, body = VersionResponse)))]
+#[get("/version")]
+async fn version_endpoint(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(VersionResponse {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        ws_protocol_min: state.config.ws_protocol_min,
+        ws_protocol_max: state.config.ws_protocol_max,
+    })
+}
+
+fn format_uptime(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!(
+        "{}d {}h {}m {}s",
+        secs / 86_400,
+        secs % 86_400 / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
 }
 
+#[derive(Serialize, ToSchema)]
+struct RootSummary {
+    name: &'static str,
+    active_nodes: usize,
+    registered_nodes: usize,
+    uptime_secs: u64,
+}
+
+/// Status page; `ROOT_RESPONSE` picks HTML, a JSON summary or a 404.
+#[utoipa::path(responses(
+    (status = 200, description = "HTML status page, or a `RootSummary` with ROOT_RESPONSE=json"),
+    (status = 404, description = "ROOT_RESPONSE=none"),
+))]
 #[get("/")]
-async fn index() -> impl Responder {
+async fn index(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if state.config.root_response == RootResponse::None {
+        return errors::not_found(req).await;
+    }
+    let active = state.active_nodes.lock().await.len();
+    let registered = state.registered_nodes.lock().await.len();
+    if state.config.root_response == RootResponse::Json {
+        return HttpResponse::Ok().json(RootSummary {
+            name: "Ferivonus Proxy API",
+            active_nodes: active,
+            registered_nodes: registered,
+            uptime_secs: state.started_at.elapsed().as_secs(),
+        });
+    }
+    let uptime = format_uptime(state.started_at.elapsed());
+
     let html = r#"
     <!DOCTYPE html>
     <html>
@@ -251,56 +2392,1268 @@ async fn index() -> impl Responder {
     </head>
     <body>
         <h1>Ferivonus Proxy Network API</h1>
+        <p>Status:</p>
+        <ul>
+            <li>Active nodes: <code>%ACTIVE_NODES%</code></li>
+            <li>Registered nodes: <code>%REGISTERED_NODES%</code></li>
+            <li>Uptime: <code>%UPTIME%</code></li>
+        </ul>
         <p>Available endpoints:</p>
         <ul>
-            <li><code class="public">GET /</code> - This page (public)</li>
-            <li><code class="public">GET /health</code> - Health check (public)</li>
-            <li><code class="public">POST /register</code> - Register proxy node (id, password, mac_id) (requires API key)</li>
-            <li><code class="secure">GET /ws/</code> - WebSocket for proxy nodes (requires authentication)</li>
-            <li><code class="secure">GET /nodes</code> - List active proxy nodes (requires authentication)</li>
+            <li><code class="public">GET /</code> - This status page (public)</li>
+            <li><code class="public">GET /health</code> - Health check with sub-checks (audit writer backlog) as JSON (public)</li>
+            <li><code class="public">GET /version</code> - Server version and accepted ws protocol versions (public)</li>
+            <li><code class="public">POST /register</code> - Register proxy node (id, password, mac_id, optional cert_fingerprint, tags) (requires API key)</li>
+            <li><code class="public">POST /register/signed</code> - Register with an HMAC signature from <code>REGISTRATION_PSK</code> instead of an API key and password</li>
+            <li><code class="public">POST /register/check-key</code> - Check an API key (<code>api_key</code>) without registering; 200 or 401</li>
+            <li><code class="public">GET /openapi.json</code> - OpenAPI description of the REST endpoints (public)</li>
+            <li><code class="public">GET /.well-known/jwks.json</code> - Token verification keys when JWT_ALG=RS256 (public)</li>
+            <li><code class="public">POST /auth/validate</code> - Check a token (body <code>token</code> or Authorization header) and get <code>valid</code>/<code>sub</code>/<code>exp</code>/<code>reason</code> (public)</li>
+            <li><code class="public">POST /login</code> - Obtain a bearer token (username, password); <code>?cookie=true</code> sets it as an HttpOnly <code>fer_net_token</code> cookie instead</li>
+            <li><code class="public">POST /users/bootstrap</code> - Create the first admin with the one-time <code>BOOTSTRAP_TOKEN</code> while no users exist</li>
+            <li><code class="public">POST /nodes/{id}/heartbeat</code> - Refresh node liveness without ws (password or node token)</li>
+            <li><code class="public">POST /nodes/{id}/address</code> - Set node ip/port without ws (password or node token, ip, port)</li>
+            <li><code class="secure">GET /ws/node</code> - WebSocket for proxy nodes, also at <code>/ws/</code> (requires authentication; browsers may pass the token as <code>?token=</code>)</li>
+            <li><code class="secure">GET /ws/monitor</code> - Read-only WebSocket for dashboards: a node snapshot then join/update/leave events, narrowed with <code>{"type":"Subscribe","node_id":...}</code> (requires authentication)</li>
+            <li><code class="secure">GET /nodes</code> - List active proxy nodes, filtered by token scopes unless admin, optionally by <code>?meta.key=value</code> (requires authentication)</li>
+            <li><code class="secure">GET /nodes/pick</code> - Pick a healthy node with a known address, optionally <code>?tag=</code>; 503 with Retry-After if none (requires authentication)</li>
+            <li><code class="secure">GET /pools</code> - List node pools with their node counts (requires authentication)</li>
+            <li><code class="secure">GET /pools/{name}/pick</code> - Pick a node from one pool, like <code>/nodes/pick</code> (requires authentication)</li>
+            <li><code class="secure">GET /nodes/ndjson</code> - Active nodes as newline-delimited JSON, streamed one node per line (requires authentication)</li>
+            <li><code class="secure">GET /nodes/by-mac</code> - Active nodes grouped by <code>mac_id</code>; <code>?duplicates=true</code> keeps only MACs with several nodes (requires authentication)</li>
+            <li><code class="secure">GET /nodes/search</code> - Case-insensitive <code>?q=</code> search of name, id prefix, ip, mac_id and tags, paged with <code>limit</code>/<code>offset</code> (requires authentication)</li>
+            <li><code class="secure">GET /nodes/{id}</code> - One active node, including its last reported error (requires authentication)</li>
+            <li><code class="secure">GET /nodes/stream</code> - Server-Sent Events stream of node joins/updates/leaves, optionally for one <code>?node_id=</code> (requires authentication)</li>
             <li><code class="secure">GET /registered-nodes</code> - List all registered nodes (requires authentication)</li>
+            <li><code>GET /metrics</code> - Prometheus metrics (request latency histograms)</li>
+            <li><code class="secure">GET /stats</code> - Aggregate node, login and auth-failure counts (requires the <code>stats:read</code> scope or admin)</li>
+            <li><code class="secure">DELETE /registered-nodes/{id}</code> - Deregister a node, revoke its tokens and close its live session (requires authentication)</li>
+            <li><code class="secure">GET /me/tokens</code> - List your live tokens' jti/issued_at/expires_at (requires authentication)</li>
+            <li><code class="secure">DELETE /me/tokens/{jti}</code> - Revoke one of your tokens (requires authentication)</li>
+            <li><code class="secure">GET /admin/sessions</code> - List live ws sessions (requires admin)</li>
+            <li><code class="secure">DELETE /admin/sessions/{id}</code> - Close a ws session by session id (requires admin)</li>
+            <li><code class="secure">GET /admin/export</code> - Download registrations, active nodes and users as JSON, without secrets (requires admin)</li>
+            <li><code class="secure">POST /admin/import</code> - Restore registrations and users from an export with passwords filled in; replaces unless <code>?merge=true</code> (requires admin)</li>
+            <li><code class="secure">POST /admin/maintenance</code> - Turn maintenance mode (503 for everything but <code>/health</code>, <code>/version</code> and <code>/login</code>) on or off, optionally <code>drain_sessions</code> (requires admin)</li>
+            <li><code class="secure">POST /admin/api-key/rotate</code> - Replace the registration API key, optionally <code>api_key</code> and <code>grace_period_secs</code> (requires admin)</li>
         </ul>
     </body>
     </html>
-    "#;
+    "#
+    .replace("%ACTIVE_NODES%", &active.to_string())
+    .replace("%REGISTERED_NODES%", &registered.to_string())
+    .replace("%UPTIME%", &uptime);
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html; charset=utf-8");
+    if !state.config.index_csp.is_empty() {
+        response.insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            state.config.index_csp.as_str(),
+        ));
+    }
+    response.body(html)
+}
 
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(html)
+/// Removes a socket file left behind by an unclean shutdown. Anything that isn't a socket is
+/// left alone so a misconfigured path can't delete a regular file.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    let port = env::var("PORT").unwrap_or_else(|_| "8000".to_string());
-    let addr = format!("0.0.0.0:{}", port);
+    auth::init_keys()?;
+    let config = Config::load()?;
+    let addrs = config.listen_addrs();
+    if !config.registration_enabled {
+        println!("Registration is disabled (REGISTRATION_ENABLED=false)");
+    }
 
-    println!("Listening on: {}", addr);
+    let restored = config
+        .snapshot_path
+        .as_deref()
+        .map(snapshot::load)
+        .unwrap_or_default();
+    let state = web::Data::new(AppState::new(config, restored));
+    if let Some(path) = &state.config.snapshot_path {
+        let interval = state.config.snapshot_interval;
+        let task = snapshot::run(
+            state.active_nodes.clone(),
+            path.clone(),
+            interval,
+            state.shutdown.signal(),
+        );
+        state.shutdown.spawn("snapshot", task);
+    }
+    if !state.config.probe_interval.is_zero() {
+        let task = probe::run(
+            state.active_nodes.clone(),
+            state.events.clone(),
+            state.config.probe_interval,
+            state.config.probe_failure_threshold,
+            state.shutdown.signal(),
+        );
+        state.shutdown.spawn("probe", task);
+    }
+    if !state.config.http_node_timeout.is_zero() || !state.config.registration_ttl.is_zero() {
+        let task = sweep::run(state.clone(), state.shutdown.signal());
+        state.shutdown.spawn("sweep", task);
+    }
+    if let (Some(url), Some(secret)) = (&state.config.webhook_url, &state.config.webhook_secret) {
+        let target = webhooks::WebhookTarget {
+            url: url.clone(),
+            secret: secret.clone(),
+            max_attempts: state.config.webhook_max_attempts,
+        };
+        let task = webhooks::run(target, state.events.subscribe(), state.shutdown.signal());
+        state.shutdown.spawn("webhooks", task);
+    }
+    if let (Some(path), Some(records)) = (&state.config.audit_log_path, state.audit.take_receiver())
+    {
+        let task = audit::run(path.clone(), records, state.shutdown.signal());
+        state.shutdown.spawn("audit", task);
+    }
+    // Users only come from the seed file or the bootstrap endpoint; there are no built-in ones.
+    if let Some(path) = &state.config.seed_file {
+        seed::load(path, &state).await?;
+    }
+    let no_users = state.users.lock().await.is_empty();
+    match (state.config.bootstrap_token.is_some(), no_users) {
+        (true, true) => println!(
+            "Bootstrap mode active: no users exist; create the first admin with POST /users/bootstrap"
+        ),
+        (true, false) => {
+            println!("BOOTSTRAP_TOKEN ignored: users already exist");
+            *state.bootstrap_token.lock_or_recover() = None;
+        }
+        (false, true) => eprintln!(
+            "No users exist and BOOTSTRAP_TOKEN is unset; nobody can log in. \
+             Set BOOTSTRAP_TOKEN or SEED_FILE to create one."
+        ),
+        (false, false) => {}
+    }
 
-    let registered_nodes: RegisteredNodes = Arc::new(Mutex::new(HashMap::new()));
-    let active_nodes: ActiveNodes = Arc::new(Mutex::new(HashMap::new()));
-    // Test kullanıcı ekle (prod’da DB’den çekilecek)
-    db::add_user("ferivonus", "password123").await;
+    let tls_config = tls::server_config()?;
+    let security_headers = SecurityHeaders::new(&state.config, tls_config.is_some());
+    // Same default as actix, but pinned so the summary below is accurate.
+    let workers = std::thread::available_parallelism().map_or(2, |n| n.get());
+    println!(
+        "Startup config: {} workers={} tls={} {}",
+        state.config.summary(),
+        workers,
+        if tls_config.is_some() { "on" } else { "off" },
+        auth::describe_keys()
+    );
+    let shutdown_state = state.clone();
 
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::bearer(validator);
+    let mut server = HttpServer::new(move || {
+        let auth = HttpAuthentication::with_fn(validator);
 
+        let timing_state = state.clone();
+        let default_case = state.config.json_case;
+        let maintenance_state = state.clone();
+        let security_headers = security_headers.clone();
         App::new()
-            .app_data(web::Data::new(registered_nodes.clone()))
-            .app_data(web::Data::new(active_nodes.clone()))
+            .wrap_fn(|req, srv| srv.call(req).map_ok(errors::serialization_failure))
+            .wrap_fn(
+                move |req, srv| match maintenance_state.maintenance.rejection(req.path()) {
+                    Some(response) => Either::Right(future::ready(Ok(
+                        req.into_response(response.map_into_right_body())
+                    ))),
+                    None => Either::Left(srv.call(req).map_ok(ServiceResponse::map_into_left_body)),
+                },
+            )
+            .wrap_fn(move |req, srv| {
+                let case = JsonCase::for_query(req.query_string(), default_case);
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    Ok(match case {
+                        JsonCase::Camel => json_case::to_camel(response).await,
+                        JsonCase::Snake => response.map_into_boxed_body(),
+                    })
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let state = timing_state.clone();
+                let method = req.method().to_string();
+                let start = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    // Routes that fail before matching (e.g. bearer auth) have no pattern.
+                    let route = response
+                        .as_ref()
+                        .ok()
+                        .and_then(|res| res.request().match_pattern())
+                        .unwrap_or_else(|| "unmatched".to_string());
+                    let elapsed = start.elapsed();
+                    if route != "/metrics" {
+                        state.metrics.observe(&route, &method, elapsed);
+                    }
+                    let threshold = state.config.slow_request_threshold;
+                    if !threshold.is_zero() && elapsed >= threshold {
+                        let status = response.as_ref().map_or_else(
+                            |err| err.as_response_error().status_code(),
+                            |res| res.status(),
+                        );
+                        eprintln!(
+                            "Slow request: {} {} took {}ms (status {})",
+                            method,
+                            route,
+                            elapsed.as_millis(),
+                            status.as_u16()
+                        );
+                    }
+                    response
+                }
+            })
+            // Outermost, so responses produced by the other middleware get the headers too.
+            .wrap_fn(move |req, srv| {
+                let security_headers = security_headers.clone();
+                srv.call(req)
+                    .map_ok(move |response| security_headers.apply(response))
+            })
+            .app_data(state.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .content_type_required(state.config.json_content_type_required)
+                    .error_handler(errors::json_error_handler),
+            )
             .service(index)
             .service(health)
+            .service(version_endpoint)
+            .service(metrics::metrics)
             .service(register)
+            .service(check_key)
+            .service(register_signed)
+            .service(user_handlers::login)
+            .service(user_handlers::bootstrap)
+            .service(auth::jwks)
+            .service(auth::validate_token)
+            .service(openapi::openapi_json)
+            .service(node_handlers::heartbeat)
+            .service(node_handlers::set_address)
+            .service(ws_index)
+            .service(monitor::ws_monitor)
             // korumalı yollar
             .service(
                 web::scope("")
                     .wrap(auth)
-                    .service(ws_index)
+                    // Outermost, so disallowed addresses are turned away before token checks.
+                    .wrap_fn(|req, srv| {
+                        if client_ip::admin_ip_allowed(req.request()) {
+                            Either::Left(srv.call(req).map_ok(ServiceResponse::map_into_left_body))
+                        } else {
+                            let response = HttpResponse::Forbidden()
+                                .body("Admin endpoints are not allowed from this address")
+                                .map_into_right_body();
+                            Either::Right(future::ready(Ok(req.into_response(response))))
+                        }
+                    })
+                    .service(user_handlers::hello)
+                    .service(user_handlers::my_tokens)
+                    .service(user_handlers::revoke_my_token)
+                    .service(node_handlers::nodes_stream)
+                    .service(node_handlers::nodes_ndjson)
+                    .service(pick_node)
+                    .service(search_nodes)
+                    .service(nodes_by_mac)
+                    .service(pools::list_pools)
+                    .service(pools::pick_from_pool)
                     .service(nodes_endpoint)
-                    .service(registered_nodes_endpoint),
+                    // After the fixed `/nodes/...` routes, which it would otherwise shadow.
+                    .service(node_endpoint)
+                    .service(registered_nodes_endpoint)
+                    .service(stats::stats_endpoint)
+                    .service(deregister)
+                    .service(admin_handlers::list_sessions)
+                    .service(admin_handlers::revoke_session)
+                    .service(admin_handlers::rotate_api_key)
+                    .service(admin_handlers::set_maintenance)
+                    .service(admin_handlers::export)
+                    .service(admin_handlers::import_topology)
+                    // The catch-all scope sees every unmatched path, so the 404 lives here.
+                    .default_service(web::to(errors::not_found)),
             )
     })
-    .bind(addr)?
-    .run()
-    .await
+    .on_connect(tls::on_connect)
+    .workers(workers)
+    // Signals are handled below so background tasks stop before the server does.
+    .disable_signals();
+
+    if tls_config.is_some() {
+        println!("TLS enabled");
+    }
+    if let Some(path) = &shutdown_state.config.bind_uds {
+        // Local socket for a fronting proxy on the same host, which terminates TLS itself.
+        if tls_config.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "BIND_UDS can't be combined with TLS",
+            ));
+        }
+        #[cfg(unix)]
+        {
+            remove_stale_socket(path)?;
+            server = server.bind_uds(path)?;
+        }
+    } else {
+        for addr in addrs {
+            server = match &tls_config {
+                Some(config) => server.bind_rustls_0_23(addr, config.clone())?,
+                None => server.bind(addr)?,
+            };
+        }
+    }
+    let server = server.run();
+    let server_handle = server.handle();
+    let signal_state = shutdown_state.clone();
+    actix_web::rt::spawn(async move {
+        shutdown::terminate_signal().await;
+        println!("Shutting down");
+        signal_state.shutdown.stop().await;
+        server_handle.stop(true).await;
+    });
+    server.await?;
+    // No-op after a signal; covers the server stopping any other way.
+    shutdown_state.shutdown.stop().await;
+
+    if let Some(path) = &shutdown_state.config.bind_uds {
+        let _ = std::fs::remove_file(path);
+    }
+
+    if let Some(path) = &shutdown_state.config.snapshot_path {
+        snapshot::write(&shutdown_state.active_nodes, path).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Role, User};
+    use actix_http::ws::{Item, ProtocolError};
+    use actix_web::dev::ServerHandle;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::web::Bytes;
+    use awc::ws::{Frame, Message};
+    use futures_util::future::join_all;
+    use futures_util::{Sink, SinkExt, Stream, StreamExt};
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+
+    /// A server on an ephemeral port with the ws endpoints, `/health`, `/login` and `/nodes`.
+    struct TestServer {
+        url: String,
+        state: web::Data<AppState>,
+        handle: ServerHandle,
+    }
+
+    impl TestServer {
+        async fn start(config: Config) -> Self {
+            let state = web::Data::new(AppState::new(config, HashMap::new()));
+            let app_state = state.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(app_state.clone())
+                    .service(ws_index)
+                    .service(health)
+                    .service(user_handlers::login)
+                    .service(
+                        web::scope("")
+                            .wrap(HttpAuthentication::with_fn(validator))
+                            .service(nodes_endpoint),
+                    )
+            })
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let url = format!("http://{}", server.addrs()[0]);
+            let server = server.run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+            TestServer { url, state, handle }
+        }
+
+        async fn register(&self, id: Uuid, password: &str) {
+            let node = RegisteredNode::test(id, password);
+            self.state.registered_nodes.lock().await.insert(id, node);
+        }
+
+        /// A ws session, opened with an admin token but not yet authenticated as a node.
+        async fn connect(&self) -> impl WsClient {
+            let url = format!("{}/ws/node?token={}", self.url, admin_token());
+            let (_, ws) = awc::Client::new().ws(url).connect().await.unwrap();
+            ws
+        }
+
+        /// A ws session authenticated as node `id`.
+        async fn connect_as(&self, id: Uuid, password: &str) -> impl WsClient {
+            let mut ws = self.connect().await;
+            let reply = request(
+                &mut ws,
+                json!({"type": "Auth", "id": id, "password": password}),
+            );
+            assert_eq!(reply.await["type"], "Authenticated");
+            ws
+        }
+
+        async fn stop(self) {
+            self.handle.stop(false).await;
+        }
+    }
+
+    trait WsClient:
+        Sink<Message, Error = ProtocolError> + Stream<Item = Result<Frame, ProtocolError>> + Unpin
+    {
+    }
+
+    impl<T> WsClient for T where
+        T: Sink<Message, Error = ProtocolError>
+            + Stream<Item = Result<Frame, ProtocolError>>
+            + Unpin
+    {
+    }
+
+    fn admin_token() -> String {
+        let admin = User {
+            username: "admin".to_string(),
+            password_hash: String::new(),
+            role: Role::Admin,
+            scopes: Vec::new(),
+            tenant: None,
+        };
+        auth::create_jwt(&admin).0
+    }
+
+    /// The next frame other than ping/pong; `None` once the connection is gone.
+    async fn next_frame(ws: &mut impl WsClient) -> Option<Frame> {
+        loop {
+            let frame = actix_web::rt::time::timeout(Duration::from_secs(5), ws.next())
+                .await
+                .expect("no frame within 5s");
+            match frame {
+                Some(Ok(Frame::Ping(_) | Frame::Pong(_))) => continue,
+                Some(Ok(frame)) => return Some(frame),
+                _ => return None,
+            }
+        }
+    }
+
+    async fn request(ws: &mut impl WsClient, message: Value) -> Value {
+        ws.send(Message::Text(message.to_string().into()))
+            .await
+            .unwrap();
+        match next_frame(ws).await {
+            Some(Frame::Text(text)) => serde_json::from_slice(&text).unwrap(),
+            other => panic!("expected a text reply, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn address_updates_are_limited_per_node_across_sessions() {
+        let config = Config {
+            address_update_min_interval: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let id = Uuid::new_v4();
+        server.register(id, "hunter22").await;
+
+        let mut ws = server.connect_as(id, "hunter22").await;
+        let set_address = |port: u16| json!({"type": "SetAddress", "ip": "10.0.0.1", "port": port});
+        let reply = request(&mut ws, set_address(8000)).await;
+        assert_eq!(reply["type"], "AddressUpdated");
+        for port in 8001..8005 {
+            let reply = request(&mut ws, set_address(port)).await;
+            assert_eq!(reply["type"], "Error");
+            assert!(reply["retry_after_ms"].as_u64().unwrap() > 59_000);
+        }
+
+        // A fresh session for the same node doesn't start a fresh interval.
+        let mut ws = server.connect_as(id, "hunter22").await;
+        let reply = request(&mut ws, set_address(8005)).await;
+        assert_eq!(reply["type"], "Error");
+
+        // The reconnect rebuilt the entry from the registration; the update didn't land.
+        let node = server.state.active_nodes.lock().await[&id].clone();
+        assert_eq!((node.port, node.version), (0, 0));
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn ws_upgrades_past_the_connection_limit_get_503() {
+        let config = Config {
+            max_ws_connections: Some(2),
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let upgrade = || {
+            let url = format!("{}/ws/node?token={}", server.url, admin_token());
+            async move { awc::Client::new().ws(url).connect().await }
+        };
+
+        let first = server.connect().await;
+        let _second = server.connect().await;
+        match upgrade().await {
+            Err(awc::error::WsClientError::InvalidResponseStatus(status)) => {
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            }
+            other => panic!("expected 503, got {:?}", other.map(|_| ())),
+        }
+
+        // Closing one frees its slot once the session stops.
+        drop(first);
+        let mut reopened = None;
+        for _ in 0..50 {
+            if let Ok((_, ws)) = upgrade().await {
+                reopened = Some(ws);
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(reopened.is_some(), "slot was never released");
+        server.stop().await;
+    }
+
+    /// Reads up to the server's close frame and returns its code and description.
+    async fn close_reason(ws: &mut impl WsClient) -> (ws::CloseCode, String) {
+        loop {
+            match next_frame(ws).await {
+                Some(Frame::Close(Some(reason))) => {
+                    return (reason.code, reason.description.unwrap_or_default());
+                }
+                Some(Frame::Text(_)) => continue,
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejections_map_to_distinct_close_codes() {
+        use ws::CloseCode;
+        let expected = [
+            (Rejection::AuthFailed, CloseCode::Policy),
+            (Rejection::RateLimited, CloseCode::Policy),
+            (Rejection::Revoked, CloseCode::Policy),
+            (Rejection::Deregistered, CloseCode::Policy),
+            (Rejection::ActiveNodeLimit, CloseCode::Again),
+            (Rejection::Maintenance, CloseCode::Again),
+            (Rejection::Superseded, CloseCode::Other(4000)),
+            (Rejection::Inactive, CloseCode::Other(4001)),
+            (Rejection::AuthBanned, CloseCode::Other(4003)),
+            (Rejection::FrameTooLarge, CloseCode::Size),
+            (Rejection::ProtocolError, CloseCode::Protocol),
+        ];
+        for (rejection, code) in expected {
+            assert_eq!(rejection.close_code(), code, "{:?}", rejection);
+        }
+        // Only load and maintenance tell the node to come back later.
+        let config = Config::default();
+        for (rejection, _) in expected {
+            let retry = matches!(
+                rejection,
+                Rejection::ActiveNodeLimit | Rejection::Maintenance
+            );
+            assert_eq!(
+                rejection.retry_after(&config).is_some(),
+                retry,
+                "{:?}",
+                rejection
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn failures_close_with_their_codes() {
+        let config = Config {
+            max_active_nodes: Some(1),
+            ws_max_message_bytes: 256,
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let (id, other) = (Uuid::new_v4(), Uuid::new_v4());
+        server.register(id, "hunter22").await;
+        server.register(other, "hunter22").await;
+
+        let mut ws = server.connect().await;
+        let auth = json!({"type": "Auth", "id": id, "password": "wrong"});
+        ws.send(Message::Text(auth.to_string().into()))
+            .await
+            .unwrap();
+        let (code, description) = close_reason(&mut ws).await;
+        assert_eq!(
+            (code, description.as_str()),
+            (ws::CloseCode::Policy, "Authentication failed")
+        );
+
+        let mut ws = server.connect_as(id, "hunter22").await;
+        let name = "x".repeat(1024);
+        let oversized = json!({"type": "SetName", "name": name});
+        ws.send(Message::Text(oversized.to_string().into()))
+            .await
+            .unwrap();
+        assert_eq!(close_reason(&mut ws).await.0, ws::CloseCode::Size);
+
+        // With `id` connected again, a second node is over the active node limit.
+        let mut first = server.connect_as(id, "hunter22").await;
+        let mut ws = server.connect().await;
+        let auth = json!({"type": "Auth", "id": other, "password": "hunter22"});
+        ws.send(Message::Text(auth.to_string().into()))
+            .await
+            .unwrap();
+        let (code, description) = close_reason(&mut ws).await;
+        assert_eq!(code, ws::CloseCode::Again);
+        let hint: Value = serde_json::from_str(&description).unwrap();
+        assert_eq!(hint["reason"], "Active node limit reached");
+        assert!(hint["retry_after_secs"].as_u64().unwrap() >= 30);
+
+        // A second session for the same node supersedes the first.
+        let _second = server.connect_as(id, "hunter22").await;
+        assert_eq!(close_reason(&mut first).await.0, ws::CloseCode::Other(4000));
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn fragmented_messages_are_reassembled() {
+        let config = Config {
+            ws_max_message_bytes: 256,
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let id = Uuid::new_v4();
+        server.register(id, "hunter22").await;
+        let mut ws = server.connect_as(id, "hunter22").await;
+
+        let fragments = [
+            Item::FirstText(Bytes::from(r#"{"type": "SetName", "#)),
+            Item::Continue(Bytes::from(r#""name": "edge-"#)),
+            Item::Last(Bytes::from(r#"01"}"#)),
+        ];
+        for fragment in fragments {
+            ws.send(Message::Continuation(fragment)).await.unwrap();
+        }
+        match next_frame(&mut ws).await {
+            Some(Frame::Text(text)) => {
+                let reply: Value = serde_json::from_slice(&text).unwrap();
+                assert_eq!(
+                    (reply["type"].as_str(), reply["name"].as_str()),
+                    (Some("NameUpdated"), Some("edge-01"))
+                );
+            }
+            other => panic!("expected a text reply, got {:?}", other),
+        }
+
+        // Each fragment fits, but together they're over the limit.
+        let chunk = Bytes::from("x".repeat(200));
+        ws.send(Message::Continuation(Item::FirstText(chunk.clone())))
+            .await
+            .unwrap();
+        ws.send(Message::Continuation(Item::Last(chunk)))
+            .await
+            .unwrap();
+        assert_eq!(close_reason(&mut ws).await.0, ws::CloseCode::Size);
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn message_burst_past_the_limit_closes_the_session() {
+        let config = Config {
+            ws_messages_per_sec: 0.01,
+            ws_message_burst: 4.0,
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let id = Uuid::new_v4();
+        server.register(id, "hunter22").await;
+
+        // `Auth` takes the first token.
+        let mut ws = server.connect_as(id, "hunter22").await;
+        for _ in 0..3 {
+            let reply = request(&mut ws, json!({"type": "GetConfig"})).await;
+            assert_eq!(reply["type"], "Config");
+        }
+        let reply = request(&mut ws, json!({"type": "GetConfig"})).await;
+        assert_eq!(reply["message"], "Rate limit exceeded");
+        match next_frame(&mut ws).await {
+            Some(Frame::Close(Some(reason))) => {
+                assert_eq!(reason.code, ws::CloseCode::Policy);
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert!(next_frame(&mut ws).await.is_none());
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn health_stays_responsive_during_concurrent_logins() {
+        let config = Config {
+            login_max_concurrent: 4,
+            login_queue_timeout: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let server = TestServer::start(config).await;
+        let user = User {
+            username: "alice".to_string(),
+            password_hash: bcrypt::hash("hunter22", 8).unwrap(),
+            role: Role::User,
+            scopes: Vec::new(),
+            tenant: None,
+        };
+        server
+            .state
+            .users
+            .lock()
+            .await
+            .insert(user.username.clone(), user);
+        let client = awc::Client::new();
+        let started = Instant::now();
+
+        let logins = async {
+            let statuses = join_all((0..16).map(|_| async {
+                let credentials = json!({"username": "alice", "password": "hunter22"});
+                let url = format!("{}/login", server.url);
+                client
+                    .post(url)
+                    .send_json(&credentials)
+                    .await
+                    .unwrap()
+                    .status()
+            }))
+            .await;
+            (statuses, started.elapsed())
+        };
+        let probes = async {
+            let mut slowest = Duration::ZERO;
+            for _ in 0..5 {
+                let sent = Instant::now();
+                let resp = client.get(format!("{}/health", server.url)).send().await;
+                assert_eq!(resp.unwrap().status(), StatusCode::OK);
+                slowest = slowest.max(sent.elapsed());
+                actix_web::rt::time::sleep(Duration::from_millis(5)).await;
+            }
+            (slowest, started.elapsed())
+        };
+        let ((statuses, logins_done), (slowest, probes_done)) = futures_util::join!(logins, probes);
+
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+        assert!(
+            probes_done < logins_done,
+            "logins finished before the probes, so nothing overlapped"
+        );
+        // Hashing runs off the single worker, so it never waits behind a login.
+        assert!(
+            slowest < Duration::from_millis(250),
+            "/health took {:?}",
+            slowest
+        );
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn replies_echo_the_request_id() {
+        let server = TestServer::start(Config::default()).await;
+        let (id, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        server.register(id, "hunter22").await;
+        server.register(peer, "hunter22").await;
+        let mut peer_ws = server.connect_as(peer, "hunter22").await;
+
+        let mut ws = server.connect().await;
+        let auth = json!({"type": "Auth", "id": id, "password": "hunter22", "request_id": "a-1"});
+        let reply = request(&mut ws, auth).await;
+        assert_eq!(
+            (reply["type"].as_str(), reply["request_id"].as_str()),
+            (Some("Authenticated"), Some("a-1"))
+        );
+
+        // Answered inline, after waiting for a lock, and as an error.
+        for (message, reply_type) in [
+            (json!({"type": "GetConfig", "request_id": "r-2"}), "Config"),
+            (
+                json!({"type": "SetName", "name": "edge", "request_id": "r-3"}),
+                "NameUpdated",
+            ),
+            (json!({"type": "NoSuchThing", "request_id": "r-4"}), "Error"),
+        ] {
+            let sent_id = message["request_id"].clone();
+            let reply = request(&mut ws, message).await;
+            assert_eq!(reply["type"], reply_type);
+            assert_eq!(reply["request_id"], sent_id);
+        }
+        let reply = request(&mut ws, json!({"type": "GetConfig"})).await;
+        assert!(reply.get("request_id").is_none());
+
+        // A relayed message is unsolicited for the recipient, so it carries no id.
+        let send =
+            json!({"type": "SendTo", "target_id": peer, "payload": "hi", "request_id": "r-5"});
+        let reply = request(&mut ws, send).await;
+        assert_eq!(reply["request_id"], "r-5");
+        match next_frame(&mut peer_ws).await {
+            Some(Frame::Text(text)) => {
+                let relayed: Value = serde_json::from_slice(&text).unwrap();
+                assert_eq!(relayed["type"], "Message");
+                assert!(relayed.get("request_id").is_none());
+            }
+            other => panic!("expected the relayed message, got {:?}", other),
+        }
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn every_concurrently_authenticated_node_is_listed() {
+        const NODES: usize = 100;
+        let server = TestServer::start(Config::default()).await;
+        let ids: Vec<Uuid> = (0..NODES).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            server.register(*id, "hunter22").await;
+        }
+        let list_nodes = || async {
+            let mut resp = awc::Client::new()
+                .get(format!("{}/nodes", server.url))
+                .bearer_auth(admin_token())
+                .send()
+                .await
+                .unwrap();
+            resp.json::<Vec<Value>>().limit(1 << 20).await.unwrap()
+        };
+
+        // Keep `/nodes` taking the active node lock while the sessions authenticate.
+        let sessions = join_all(ids.iter().map(|id| server.connect_as(*id, "hunter22")));
+        let pollers = join_all((0..8).map(|_| async {
+            for _ in 0..10 {
+                list_nodes().await;
+            }
+        }));
+        let (_sessions, _) = futures_util::join!(sessions, pollers);
+
+        let listed: HashSet<String> = list_nodes()
+            .await
+            .iter()
+            .map(|node| node["id"].as_str().unwrap().to_string())
+            .collect();
+        for id in &ids {
+            assert!(listed.contains(&id.to_string()), "node {} is missing", id);
+        }
+        assert_eq!(listed.len(), NODES);
+        server.stop().await;
+    }
+
+    fn search_node(name: &str, ip: &str, tags: &[&str]) -> ProxyNode {
+        let source_ip = IpAddr::from([127, 0, 0, 1]);
+        let mut node = ProxyNode::new(&RegisteredNode::test(Uuid::new_v4(), "pw"), source_ip);
+        node.name = name.to_string();
+        node.ip = ip.to_string();
+        node.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        node
+    }
+
+    #[actix_web::test]
+    async fn pick_without_an_eligible_node_is_503_with_retry_after() {
+        let config = Config {
+            heartbeat_interval: Duration::from_secs(17),
+            ..Config::default()
+        };
+        let node = search_node("edge", "10.0.0.1", &["eu"]);
+        let state = web::Data::new(AppState::new(config, HashMap::from([(node.id, node)])));
+        let app = init_service(
+            App::new().app_data(state).service(
+                web::scope("")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .service(pick_node),
+            ),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/nodes/pick?tag=us")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token())))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "17");
+    }
+
+    #[actix_web::test]
+    async fn search_matches_partial_fields_case_insensitively() {
+        let node = search_node("Berlin-Edge-01", "10.20.30.40", &["EU-West", "fast"]);
+        for needle in ["edge", "berlin-e", "20.30", "eu-w", "fas", "dd:ee"] {
+            assert!(node.matches_search(needle), "{:?} should match", needle);
+        }
+        let id = node.id.to_string();
+        assert!(node.matches_search(&id[..6]));
+        // The id only matches as a prefix, the rest anywhere.
+        assert!(!node.matches_search(&id[6..12]));
+        assert!(!node.matches_search("paris"));
+    }
+
+    #[actix_web::test]
+    async fn search_pages_through_matches() {
+        let state = web::Data::new(AppState::new(Config::default(), HashMap::new()));
+        {
+            let mut nodes = state.active_nodes.lock().await;
+            for (name, tags) in [
+                ("edge-a", &["eu"][..]),
+                ("edge-b", &[][..]),
+                ("core", &["edge-cache"][..]),
+                ("other", &[][..]),
+            ] {
+                let node = search_node(name, "10.0.0.1", tags);
+                nodes.insert(node.id, node);
+            }
+        }
+        let app = init_service(
+            App::new().app_data(state.clone()).service(
+                web::scope("")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .service(search_nodes),
+            ),
+        )
+        .await;
+        let search = |query: &str| {
+            TestRequest::get()
+                .uri(&format!("/nodes/search?{}", query))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token())))
+                .to_request()
+        };
+
+        let page: Value = call_and_read_body_json(&app, search("q=EDGE&limit=2")).await;
+        assert_eq!(
+            (page["total"].as_u64(), page["limit"].as_u64()),
+            (Some(3), Some(2))
+        );
+        assert_eq!(page["nodes"].as_array().unwrap().len(), 2);
+        let rest: Value = call_and_read_body_json(&app, search("q=edge&limit=2&offset=2")).await;
+        assert_eq!(rest["nodes"].as_array().unwrap().len(), 1);
+        let mut names: Vec<&str> = page["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(rest["nodes"].as_array().unwrap())
+            .map(|node| node["name"].as_str().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["core", "edge-a", "edge-b"]);
+
+        let resp = call_service(&app, search("q=%20")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn node_with_every_optional_field_serializes() {
+        let mut node = search_node("edge", "10.0.0.1", &["eu"]);
+        node.port = 8080;
+        node.source_ip = Some(IpAddr::from([192, 0, 2, 1]));
+        node.capabilities = vec!["compression".to_string()];
+        node.pool = Some("blue".to_string());
+        node.metadata = BTreeMap::from([("os".to_string(), "linux".to_string())]);
+        node.last_probe_at = Some(Utc::now());
+        node.last_probe_ok = Some(false);
+        node.probe_failures = 2;
+        node.last_error = Some(NodeError {
+            code: "upstream".to_string(),
+            message: "connection refused".to_string(),
+            reported_at: Utc::now(),
+        });
+        node.tenant = Some("acme".to_string());
+
+        let value = serde_json::to_value(&node).unwrap();
+        let keys: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        for key in &keys {
+            assert!(
+                PROXY_NODE_FIELDS.contains(key),
+                "{} missing from PROXY_NODE_FIELDS",
+                key
+            );
+        }
+        assert_eq!(keys.len(), PROXY_NODE_FIELDS.len());
+        assert_eq!(value["last_error"]["code"], "upstream");
+        assert_eq!(value["source_ip"], "192.0.2.1");
+
+        // Snapshots read nodes back; the skipped counter starts over.
+        let restored: ProxyNode = serde_json::from_value(value).unwrap();
+        assert_eq!(restored.metadata, node.metadata);
+        assert_eq!(restored.probe_failures, 0);
+    }
+
+    /// The `fields` of a 400 `ApiError` as `(field, message)` pairs.
+    async fn field_errors(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = Error>,
+        uri: &str,
+        body: Value,
+    ) -> Vec<(String, String)> {
+        let req = TestRequest::post().uri(uri).set_json(body).to_request();
+        let resp = call_service(app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        let body: Value = actix_web::test::read_body_json(resp).await;
+        body["fields"]
+            .as_array()
+            .unwrap_or_else(|| panic!("no field errors in {}", body))
+            .iter()
+            .map(|field| {
+                let text = |key: &str| field[key].as_str().unwrap().to_string();
+                (text("field"), text("message"))
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn missing_and_empty_fields_are_reported_per_field() {
+        // The id is only checked once the API key is accepted.
+        let config = Config {
+            api_key: "key".to_string(),
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let app = init_service(
+            App::new()
+                .app_data(state)
+                .app_data(web::JsonConfig::default().error_handler(errors::json_error_handler))
+                .service(register)
+                .service(user_handlers::login),
+        )
+        .await;
+        let pair = |field: &str, message: &str| vec![(field.to_string(), message.to_string())];
+
+        let credentials = json!({"username": "alice", "password": "hunter22"});
+        for field in ["username", "password"] {
+            let mut body = credentials.clone();
+            body.as_object_mut().unwrap().remove(field);
+            let errors = field_errors(&app, "/login", body).await;
+            assert_eq!(errors, pair(field, "is required"));
+
+            let mut body = credentials.clone();
+            body[field] = json!("");
+            let errors = field_errors(&app, "/login", body).await;
+            assert_eq!(errors, pair(field, "must not be empty"));
+        }
+
+        let registration = json!({
+            "id": Uuid::new_v4(),
+            "password": "hunter22",
+            "mac_id": "aa:bb:cc:dd:ee:ff",
+            "api_key": "key",
+        });
+        for field in ["password", "mac_id", "api_key"] {
+            let mut body = registration.clone();
+            body.as_object_mut().unwrap().remove(field);
+            let errors = field_errors(&app, "/register", body).await;
+            assert_eq!(errors, pair(field, "is required"));
+        }
+        let mut body = registration.clone();
+        body.as_object_mut().unwrap().remove("id");
+        let errors = field_errors(&app, "/register", body).await;
+        assert_eq!(errors, pair("id", "is required"));
+
+        let mut body = registration.clone();
+        body["password"] = json!("");
+        body["mac_id"] = json!("aa:bb:cc");
+        let errors = field_errors(&app, "/register", body).await;
+        assert_eq!(
+            errors,
+            [
+                pair("mac_id", "must be a MAC address like aa:bb:cc:dd:ee:ff"),
+                pair("password", "must not be empty"),
+            ]
+            .concat()
+        );
+    }
+
+    #[actix_web::test]
+    async fn registration_checks_come_before_the_id() {
+        let config = Config {
+            api_key: "test-key".to_string(),
+            registration_enabled: false,
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let app = init_service(App::new().app_data(state).service(register)).await;
+        // No id, which would otherwise be a 400.
+        let attempt = |api_key: &str| {
+            TestRequest::post()
+                .uri("/register")
+                .set_json(json!({
+                    "password": "hunter22",
+                    "mac_id": "aa:bb:cc:dd:ee:ff",
+                    "api_key": api_key,
+                }))
+                .to_request()
+        };
+        let resp = call_service(&app, attempt("test-key")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let config = Config {
+            api_key: "test-key".to_string(),
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let app = init_service(App::new().app_data(state).service(register)).await;
+        let resp = call_service(&app, attempt("wrong-key")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call_service(&app, attempt("test-key")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn rapid_repeat_registrations_get_429() {
+        let config = Config {
+            api_key: "test-key".to_string(),
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let app = init_service(App::new().app_data(state).service(register)).await;
+        let attempt = || {
+            TestRequest::post()
+                .uri("/register")
+                .set_json(json!({
+                    "id": Uuid::new_v4(),
+                    "password": "hunter22",
+                    "mac_id": "aa:bb:cc:dd:ee:ff",
+                    "api_key": "test-key",
+                }))
+                .to_request()
+        };
+        for _ in 0..4 {
+            assert_eq!(call_service(&app, attempt()).await.status(), StatusCode::OK);
+        }
+        let resp = call_service(&app, attempt()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = resp.headers().get(header::RETRY_AFTER).unwrap();
+        assert_eq!(retry_after, "2");
+    }
+
+    #[actix_web::test]
+    async fn just_expired_registration_is_flagged_and_frees_its_id() {
+        let config = Config {
+            api_key: "test-key".to_string(),
+            registration_ttl: Duration::from_secs(3600),
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let id = Uuid::new_v4();
+        let mut expired = RegisteredNode::test(id, "hunter22");
+        expired.expires_at = Some(Utc::now() - chrono::Duration::milliseconds(1));
+        state.registered_nodes.lock().await.insert(id, expired);
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(register)
+                .service(
+                    web::scope("")
+                        .wrap(HttpAuthentication::with_fn(validator))
+                        .service(registered_nodes_endpoint),
+                ),
+        )
+        .await;
+        let list = || {
+            TestRequest::get()
+                .uri("/registered-nodes")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token())))
+                .to_request()
+        };
+
+        let listed: Value = call_and_read_body_json(&app, list()).await;
+        assert_eq!(listed[0]["expired"], true);
+        assert!(db::verify_node(&state.registered_nodes, &id, "hunter22")
+            .await
+            .is_none());
+
+        // The id is free again: different credentials don't conflict.
+        let req = TestRequest::post()
+            .uri("/register")
+            .set_json(json!({
+                "id": id,
+                "password": "other-password",
+                "mac_id": "aa:bb:cc:dd:ee:01",
+                "api_key": "test-key",
+            }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let listed: Value = call_and_read_body_json(&app, list()).await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["expired"], false);
+        assert!(
+            db::verify_node(&state.registered_nodes, &id, "other-password")
+                .await
+                .is_some()
+        );
+    }
+
+    #[actix_web::test]
+    async fn token_from_before_a_reregistration_is_refused() {
+        let config = Config {
+            api_key: "test-key".to_string(),
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(register)
+                .service(node_handlers::heartbeat)
+                .service(
+                    web::scope("")
+                        .wrap(HttpAuthentication::with_fn(validator))
+                        .service(deregister),
+                ),
+        )
+        .await;
+        let id = Uuid::new_v4();
+        let registration = || {
+            TestRequest::post()
+                .uri("/register")
+                .set_json(json!({
+                    "id": id,
+                    "password": "hunter22",
+                    "mac_id": "aa:bb:cc:dd:ee:ff",
+                    "api_key": "test-key",
+                }))
+                .to_request()
+        };
+        let heartbeat = |token: &str| {
+            TestRequest::post()
+                .uri(&format!("/nodes/{}/heartbeat", id))
+                .set_json(json!({ "token": token }))
+                .to_request()
+        };
+
+        assert_eq!(
+            call_service(&app, registration()).await.status(),
+            StatusCode::OK
+        );
+        let nonce = state.registered_nodes.lock().await[&id].token_nonce;
+        let old_token = auth::create_node_jwt(&id, &nonce);
+        let resp = call_service(&app, heartbeat(&old_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = TestRequest::delete()
+            .uri(&format!("/registered-nodes/{}", id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token())))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(
+            call_service(&app, registration()).await.status(),
+            StatusCode::OK
+        );
+
+        let resp = call_service(&app, heartbeat(&old_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let nonce = state.registered_nodes.lock().await[&id].token_nonce;
+        let new_token = auth::create_node_jwt(&id, &nonce);
+        let resp = call_service(&app, heartbeat(&new_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,70 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// `Retry-After` sent while in maintenance; operators rarely know the real duration.
+pub const RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// Paths still served during maintenance: health and version checks, and the switch to turn
+/// it off along with the login an admin needs to reach it.
+const EXEMPT_PATHS: &[&str] = &["/health", "/version", "/login", "/admin/maintenance"];
+
+/// The maintenance switch: starts as `Config::maintenance_mode`, toggled by
+/// `POST /admin/maintenance`.
+pub struct Maintenance {
+    enabled: AtomicBool,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool) -> Self {
+        Maintenance {
+            enabled: AtomicBool::new(enabled),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The 503 to send instead of handling a request for `path`, if any. This includes
+    /// ws upgrades, so no new sessions start.
+    pub fn rejection(&self, path: &str) -> Option<HttpResponse> {
+        if !self.is_enabled() || EXEMPT_PATHS.contains(&path) {
+            return None;
+        }
+        Some(
+            HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, RETRY_AFTER.as_secs().to_string()))
+                .body("Down for maintenance"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+
+    #[test]
+    fn only_exempt_paths_are_served_during_maintenance() {
+        let maintenance = Maintenance::new(true);
+        for path in EXEMPT_PATHS {
+            assert!(maintenance.rejection(path).is_none(), "{} rejected", path);
+        }
+        let response = maintenance.rejection("/nodes").expect("/nodes served");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "120");
+    }
+
+    #[test]
+    fn nothing_is_rejected_when_disabled() {
+        let maintenance = Maintenance::new(false);
+        assert!(maintenance.rejection("/nodes").is_none());
+        assert!(maintenance.rejection("/ws/").is_none());
+    }
+}
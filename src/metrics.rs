@@ -0,0 +1,206 @@
+use crate::audit::AuditHealth;
+use crate::state::AppState;
+use crate::sync::LockExt;
+use actix_web::{get, web, HttpResponse, Responder};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the request duration buckets: Prometheus' default buckets,
+/// 5ms to 10s, which cover everything from cached reads to slow password hashing.
+pub const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Distinct `ReportError` codes labeled individually; later ones are counted as `other`.
+const MAX_NODE_ERROR_CODES: usize = 50;
+const OTHER_NODE_ERROR_CODE: &str = "other";
+
+#[derive(Default)]
+struct Histogram {
+    /// Per-bucket (not cumulative) counts; the last slot is `+Inf`.
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+/// `fer_net_request_duration_seconds`, labeled by route pattern and method.
+#[derive(Default)]
+pub struct RequestMetrics {
+    durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// `fer_net_node_errors_total`, by the code nodes send with `ReportError`.
+    node_errors: Mutex<BTreeMap<String, u64>>,
+    /// `fer_net_node_joins_total` / `fer_net_node_leaves_total`; their rates are the churn.
+    node_joins: AtomicU64,
+    node_leaves: AtomicU64,
+}
+
+impl RequestMetrics {
+    pub fn observe(&self, route: &str, method: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(DURATION_BUCKETS.len());
+        let mut durations = self.durations.lock_or_recover();
+        let histogram = durations
+            .entry((route.to_string(), method.to_string()))
+            .or_default();
+        histogram.buckets[bucket] += 1;
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    /// Nodes pick the codes, so only the first `MAX_NODE_ERROR_CODES` get their own series.
+    pub fn count_node_error(&self, code: &str) {
+        let mut node_errors = self.node_errors.lock_or_recover();
+        let labeled = node_errors
+            .keys()
+            .filter(|code| *code != OTHER_NODE_ERROR_CODE)
+            .count();
+        let code = if node_errors.contains_key(code) || labeled < MAX_NODE_ERROR_CODES {
+            code
+        } else {
+            OTHER_NODE_ERROR_CODE
+        };
+        *node_errors.entry(code.to_string()).or_default() += 1;
+    }
+
+    /// Call wherever a node is added to `ActiveNodes` (next to publishing `Joined`).
+    pub fn count_join(&self) {
+        self.node_joins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Call wherever a node is removed from `ActiveNodes` (next to publishing `Left`).
+    pub fn count_leave(&self) {
+        self.node_leaves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition format, with the current node counts as gauges (and the
+    /// audit writer's backlog, when auditing is on).
+    pub fn render(
+        &self,
+        registered_nodes: usize,
+        active_nodes: usize,
+        audit: Option<AuditHealth>,
+    ) -> String {
+        let mut out = String::from(
+            "# HELP fer_net_request_duration_seconds HTTP request latency by route and method.\n\
+             # TYPE fer_net_request_duration_seconds histogram\n",
+        );
+        for ((route, method), histogram) in self.durations.lock_or_recover().iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape(route), escape(method));
+            let mut cumulative = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "fer_net_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "fer_net_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "fer_net_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "fer_net_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        out.push_str(
+            "# HELP fer_net_node_errors_total Errors reported by nodes, by code.\n\
+             # TYPE fer_net_node_errors_total counter\n",
+        );
+        for (code, count) in self.node_errors.lock_or_recover().iter() {
+            let _ = writeln!(
+                out,
+                "fer_net_node_errors_total{{code=\"{}\"}} {}",
+                escape(code),
+                count
+            );
+        }
+        let _ = write!(
+            out,
+            "# HELP fer_net_registered_nodes Registered nodes.\n\
+             # TYPE fer_net_registered_nodes gauge\n\
+             fer_net_registered_nodes {}\n\
+             # HELP fer_net_active_nodes Nodes currently online.\n\
+             # TYPE fer_net_active_nodes gauge\n\
+             fer_net_active_nodes {}\n\
+             # HELP fer_net_node_joins_total Nodes that came online.\n\
+             # TYPE fer_net_node_joins_total counter\n\
+             fer_net_node_joins_total {}\n\
+             # HELP fer_net_node_leaves_total Nodes that went offline.\n\
+             # TYPE fer_net_node_leaves_total counter\n\
+             fer_net_node_leaves_total {}\n",
+            registered_nodes,
+            active_nodes,
+            self.node_joins.load(Ordering::Relaxed),
+            self.node_leaves.load(Ordering::Relaxed)
+        );
+        if let Some(audit) = audit {
+            let _ = write!(
+                out,
+                "# HELP fer_net_audit_queue_depth Audit records waiting to be written.\n\
+                 # TYPE fer_net_audit_queue_depth gauge\n\
+                 fer_net_audit_queue_depth {}\n\
+                 # HELP fer_net_audit_dropped_total Audit records dropped because the queue was full.\n\
+                 # TYPE fer_net_audit_dropped_total counter\n\
+                 fer_net_audit_dropped_total {}\n",
+                audit.queue_depth, audit.dropped
+            );
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus scrape endpoint. Requests to it aren't measured themselves.
+#[utoipa::path(responses((status = 200, description = "Prometheus metrics", content_type = "text/plain")))]
+#[get("/metrics")]
+pub async fn metrics(state: web::Data<AppState>) -> impl Responder {
+    let registered = state.registered_nodes.lock().await.len();
+    let active = state.active_nodes.lock().await.len();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(
+            state
+                .metrics
+                .render(registered, active, state.audit.health()),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_error_codes_beyond_the_cap_are_counted_as_other() {
+        let request_metrics = RequestMetrics::default();
+        for i in 0..MAX_NODE_ERROR_CODES + 10 {
+            request_metrics.count_node_error(&format!("code-{}", i));
+        }
+        // Already-labeled codes keep their series.
+        request_metrics.count_node_error("code-0");
+        let node_errors = request_metrics.node_errors.lock_or_recover();
+        assert_eq!(node_errors.len(), MAX_NODE_ERROR_CODES + 1);
+        assert_eq!(node_errors["code-0"], 2);
+        assert_eq!(node_errors[OTHER_NODE_ERROR_CODE], 10);
+    }
+}
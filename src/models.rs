@@ -1,24 +1,80 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::Validate;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+}
 
 #[derive(Clone)]
 pub struct User {
     pub username: String,
     pub password_hash: String,
+    pub role: Role,
+    /// Node tags this user may see; ignored for admins.
+    pub scopes: Vec<String>,
+    /// The user only sees nodes of this tenant; `None` is the default tenant.
+    pub tenant: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate, ToSchema)]
 pub struct LoginRequest {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub password: String,
+}
+
+#[derive(Deserialize, Validate, ToSchema)]
+pub struct BootstrapRequest {
+    /// Must match `BOOTSTRAP_TOKEN`.
+    pub token: String,
+    #[validate(length(min = 1, message = "must not be empty"))]
     pub username: String,
+    #[validate(length(min = 8, message = "must be at least 8 characters"))]
     pub password: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct LoginResponse {
     pub token: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    /// Unique token id; set on user tokens so they can be listed and revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Tenant the user belongs to; absent for the default tenant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// On node tokens, the registration they were issued for (`RegisteredNode::token_nonce`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl Claims {
+    pub fn is_admin(&self) -> bool {
+        self.role == Some(Role::Admin)
+    }
+
+    /// Whether the caller may see or act on records of `tenant`. Admins cross tenants.
+    pub fn in_tenant(&self, tenant: Option<&str>) -> bool {
+        self.is_admin() || self.tenant.as_deref() == tenant
+    }
 }
@@ -0,0 +1,167 @@
+use crate::auth;
+use crate::events::NodeEvent;
+use crate::models::Claims;
+use crate::node_handlers::visible_event;
+use crate::state::AppState;
+use crate::{ProxyNode, WsQuery};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// Inbound monitor message.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum MonitorMessage {
+    /// Follow one node only, or every visible node again with `null`. Answered with a fresh
+    /// snapshot for the new selection.
+    Subscribe { node_id: Option<Uuid> },
+}
+
+/// Outbound frames besides the `NodeEvent`s themselves, tagged the same way.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum MonitorFrame<'a> {
+    Snapshot { nodes: Vec<ProxyNode> },
+    Error { message: &'a str },
+}
+
+/// Read-only ws session for dashboards: a snapshot of the active nodes the caller may see,
+/// then the same `joined`/`updated`/`left`/`registered` events as `/nodes/stream`. Monitors
+/// have no node protocol at all, so they can't authenticate as a node or change node state.
+struct MonitorSession {
+    state: web::Data<AppState>,
+    claims: Claims,
+    node_id: Option<Uuid>,
+    /// Node ids the monitor currently knows about; see `visible_event`.
+    visible: HashSet<Uuid>,
+    events: Option<broadcast::Receiver<NodeEvent>>,
+    snapshot: Vec<ProxyNode>,
+}
+
+impl MonitorSession {
+    fn send<T: Serialize>(&self, ctx: &mut ws::WebsocketContext<Self>, frame: &T) {
+        if let Ok(text) = serde_json::to_string(frame) {
+            ctx.text(text);
+        }
+    }
+
+    fn send_snapshot(&mut self, nodes: Vec<ProxyNode>, ctx: &mut ws::WebsocketContext<Self>) {
+        let nodes: Vec<ProxyNode> = nodes
+            .into_iter()
+            .filter(|node| self.node_id.is_none_or(|id| id == node.id))
+            .filter(|node| node.visible_to(&self.claims))
+            .collect();
+        self.visible = nodes.iter().map(|node| node.id).collect();
+        self.send(ctx, &MonitorFrame::Snapshot { nodes });
+    }
+
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, message: &str) {
+        self.send(ctx, &MonitorFrame::Error { message });
+    }
+}
+
+impl Actor for MonitorSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(rx) = self.events.take() {
+            ctx.add_stream(stream::unfold(rx, |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) => return Some((event, rx)),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }));
+        }
+        let snapshot = std::mem::take(&mut self.snapshot);
+        self.send_snapshot(snapshot, ctx);
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.state.stats.close_ws();
+    }
+}
+
+impl StreamHandler<NodeEvent> for MonitorSession {
+    fn handle(&mut self, event: NodeEvent, ctx: &mut Self::Context) {
+        if self.node_id.is_some_and(|id| id != event.node_id()) {
+            return;
+        }
+        if let Some(event) = visible_event(event, &self.claims, &mut self.visible) {
+            self.send(ctx, &event);
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MonitorSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(MonitorMessage::Subscribe { node_id }) => {
+                    let nodes: Vec<ProxyNode> = match self.state.active_nodes.try_lock() {
+                        Ok(nodes) => nodes.values().cloned().collect(),
+                        Err(_) => {
+                            self.send_error(ctx, "Snapshot unavailable, try again");
+                            return;
+                        }
+                    };
+                    self.node_id = node_id;
+                    self.send_snapshot(nodes, ctx);
+                }
+                Err(_) => self.send_error(ctx, "Unknown message; monitors can only Subscribe"),
+            },
+            Ok(ws::Message::Binary(_)) => self.send_error(ctx, "Binary frames not supported"),
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// ws endpoint for dashboards; see `MonitorSession`. Authenticated like `/ws/node`.
+#[get("/ws/monitor")]
+pub async fn ws_monitor(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<WsQuery>,
+    bearer: Option<BearerAuth>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let claims = match auth::ws_claims(&req, bearer.as_ref(), query.token.as_deref()) {
+        Ok(claims) => claims,
+        Err(response) => return Ok(response),
+    };
+    if !state.stats.try_open_ws(state.config.max_ws_connections) {
+        return Ok(HttpResponse::ServiceUnavailable().body("Too many ws connections"));
+    }
+
+    // Subscribe before snapshotting so no event between the two is lost.
+    let events = state.events.subscribe();
+    let snapshot = state.active_nodes.lock().await.values().cloned().collect();
+    let session = MonitorSession {
+        state: state.clone(),
+        claims,
+        node_id: None,
+        visible: HashSet::new(),
+        events: Some(events),
+        snapshot,
+    };
+    let response = ws::start(session, &req, stream);
+    // A failed handshake never starts the actor, so `stopped` won't run for it.
+    if response.is_err() {
+        state.stats.close_ws();
+    }
+    response
+}
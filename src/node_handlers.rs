@@ -0,0 +1,487 @@
+use crate::audit::AuditEvent;
+use crate::auth::validate_node_jwt;
+use crate::client_ip::real_client_ip;
+use crate::config::limit_reached;
+use crate::db;
+use crate::events::{self, NodeEvent};
+use crate::models::Claims;
+use crate::state::AppState;
+use crate::{address_update_limit_message, validate_address, ProxyNode, RegisteredNode};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+#[derive(Deserialize, ToSchema)]
+pub struct HeartbeatRequest {
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddressRequest {
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub ip: String,
+    pub port: u16,
+    #[serde(default)]
+    pub expected_version: Option<u64>,
+}
+
+/// Checks the node's password or node-scoped token and returns its registration, or the
+/// response to send instead. Password attempts go through `node_auth_throttle` like ws `Auth`,
+/// so the HTTP endpoints can't be used to keep guessing while the id is banned.
+async fn authenticate_node(
+    state: &AppState,
+    id: &Uuid,
+    password: Option<&str>,
+    token: Option<&str>,
+) -> Result<RegisteredNode, HttpResponse> {
+    let throttle = &state.node_auth_throttle;
+    let found = match (password, token) {
+        (Some(password), _) => {
+            if let Some(wait) = throttle.banned_for(id) {
+                return Err(banned_response(wait));
+            }
+            match db::verify_node(&state.registered_nodes, id, password).await {
+                Some(reg_node) => {
+                    throttle.reset(id);
+                    Some(reg_node)
+                }
+                None if throttle.record_failure(id) => {
+                    eprintln!(
+                        "Node {} banned from auth for {}s after repeated failures",
+                        id,
+                        state.config.node_auth_ban.as_secs()
+                    );
+                    return Err(banned_response(state.config.node_auth_ban));
+                }
+                None => None,
+            }
+        }
+        (None, Some(token)) => match validate_node_jwt(token) {
+            Ok(token) if token.node_id == *id => {
+                db::find_node(&state.registered_nodes, &token).await
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    found.ok_or_else(|| HttpResponse::Unauthorized().body("Authentication failed"))
+}
+
+fn banned_response(wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, wait.as_secs().max(1).to_string()))
+        .body("Too many failed authentication attempts; try again later")
+}
+
+/// Returns the active entry for a node, creating it (and announcing the join) if missing,
+/// and records `source_ip` on it. Returns `None` when creating it would exceed the active node limit.
+fn upsert_node<'a>(
+    nodes: &'a mut HashMap<Uuid, ProxyNode>,
+    reg_node: &RegisteredNode,
+    source_ip: IpAddr,
+    state: &AppState,
+) -> Option<&'a mut ProxyNode> {
+    let full = limit_reached(state.config.max_active_nodes, nodes.len());
+    match nodes.entry(reg_node.id) {
+        Entry::Occupied(entry) => {
+            let node = entry.into_mut();
+            node.source_ip = Some(source_ip);
+            Some(node)
+        }
+        Entry::Vacant(_) if full => None,
+        Entry::Vacant(entry) => {
+            let node = ProxyNode::new(reg_node, source_ip);
+            state.metrics.count_join();
+            events::publish(&state.events, NodeEvent::Joined { node: node.clone() });
+            Some(entry.insert(node))
+        }
+    }
+}
+
+#[utoipa::path(
+    params(("id" = Uuid, Path, description = "Node id")),
+    request_body = HeartbeatRequest,
+    responses(
+        (status = 200, description = "Heartbeat received"),
+        (status = 401, description = "Authentication failed"),
+        (status = 429, description = "Node id banned after repeated password failures; see Retry-After"),
+        (status = 503, description = "Active node limit reached"),
+    )
+)]
+#[post("/nodes/{id}/heartbeat")]
+pub async fn heartbeat(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<HeartbeatRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let reg_node =
+        match authenticate_node(&state, &id, body.password.as_deref(), body.token.as_deref()).await
+        {
+            Ok(reg_node) => reg_node,
+            Err(response) => return response,
+        };
+
+    let mut nodes = state.active_nodes.lock().await;
+    let Some(node) = upsert_node(&mut nodes, &reg_node, real_client_ip(&req), &state) else {
+        return HttpResponse::ServiceUnavailable().body("Active node limit reached");
+    };
+    if node.mark_seen() {
+        let node = node.clone();
+        events::publish(&state.events, NodeEvent::Updated { node });
+    }
+    HttpResponse::Ok().body("Heartbeat received")
+}
+
+#[utoipa::path(
+    params(("id" = Uuid, Path, description = "Node id")),
+    request_body = AddressRequest,
+    responses(
+        (status = 200, description = "Address updated"),
+        (status = 400, description = "Invalid address"),
+        (status = 401, description = "Authentication failed"),
+        (status = 409, description = "expected_version is stale"),
+        (status = 429, description = "Updated too recently, or node id banned after repeated password failures; see Retry-After"),
+        (status = 503, description = "Active node limit reached"),
+    )
+)]
+#[post("/nodes/{id}/address")]
+pub async fn set_address(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<AddressRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let reg_node =
+        match authenticate_node(&state, &id, body.password.as_deref(), body.token.as_deref()).await
+        {
+            Ok(reg_node) => reg_node,
+            Err(response) => return response,
+        };
+    if let Err(reason) = validate_address(&body.ip, body.port) {
+        return HttpResponse::BadRequest().body(reason);
+    }
+
+    let source_ip = real_client_ip(&req);
+    let mut nodes = state.active_nodes.lock().await;
+    // Same per-node limit as ws `SetAddress`, so switching transports doesn't get around it.
+    let limiter = &state.address_updates;
+    if let Some(wait) = limiter.retry_after(&id) {
+        return HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, wait.as_secs().max(1).to_string()))
+            .body(address_update_limit_message(limiter));
+    }
+    let Some(node) = upsert_node(&mut nodes, &reg_node, source_ip, &state) else {
+        return HttpResponse::ServiceUnavailable().body("Active node limit reached");
+    };
+    match node.set_address(body.ip.clone(), body.port, body.expected_version) {
+        Ok(version) => {
+            limiter.record(&id);
+            state.audit.record(AuditEvent::AddressSet {
+                session_id: None,
+                node_id: id,
+                mac_id: reg_node.mac_id.clone(),
+                source_ip,
+                ip: body.ip.clone(),
+                port: body.port,
+            });
+            let node = node.clone();
+            events::publish(&state.events, NodeEvent::Updated { node });
+            HttpResponse::Ok().body(format!("Address updated (version {})", version))
+        }
+        Err(current) => HttpResponse::Conflict()
+            .body(format!("Version conflict: current version is {}", current)),
+    }
+}
+
+/// The active nodes the caller may see, ordered by id, as newline-delimited JSON (one node per
+/// line). Nodes are snapshotted under one lock and serialized as the response is written, so
+/// neither side holds the whole list as a single JSON document.
+#[utoipa::path(
+    responses((status = 200, description = "One `ProxyNode` per line", content_type = "application/x-ndjson")),
+    security(("bearer" = []))
+)]
+#[get("/nodes/ndjson")]
+pub async fn nodes_ndjson(
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let mut nodes: Vec<ProxyNode> = state
+        .active_nodes
+        .lock()
+        .await
+        .values()
+        .filter(|node| node.visible_to(&claims))
+        .cloned()
+        .collect();
+    nodes.sort_by_key(|node| node.id);
+
+    let lines = stream::iter(nodes).filter_map(|node| async move {
+        match serde_json::to_vec(&node) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                Some(Ok::<_, Error>(Bytes::from(line)))
+            }
+            Err(err) => {
+                eprintln!(
+                    "Failed to serialize node {} for /nodes/ndjson: {}",
+                    node.id, err
+                );
+                None
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines)
+}
+
+/// Filters `event` down to what `claims` may see, tracking which node ids are currently
+/// `visible` to the caller.
+pub fn visible_event(
+    event: NodeEvent,
+    claims: &Claims,
+    visible: &mut HashSet<Uuid>,
+) -> Option<NodeEvent> {
+    match &event {
+        NodeEvent::Joined { node } | NodeEvent::Updated { node } => {
+            if node.visible_to(claims) {
+                visible.insert(node.id);
+                Some(event)
+            } else if visible.remove(&node.id) {
+                Some(NodeEvent::Left { id: node.id })
+            } else {
+                None
+            }
+        }
+        NodeEvent::Left { id } => visible.remove(id).then_some(event),
+        NodeEvent::Registered { .. } => claims.is_admin().then_some(event),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamQuery {
+    /// Only stream events for this node.
+    pub node_id: Option<Uuid>,
+}
+
+/// The `snapshot` frame of the active nodes `claims` may see (only `filter` if given), and
+/// their ids.
+async fn snapshot_frame(
+    state: &AppState,
+    claims: &Claims,
+    filter: Option<Uuid>,
+) -> (Bytes, HashSet<Uuid>) {
+    let snapshot: Vec<ProxyNode> = state
+        .active_nodes
+        .lock()
+        .await
+        .values()
+        .filter(|node| filter.is_none_or(|id| id == node.id) && node.visible_to(claims))
+        .cloned()
+        .collect();
+    let visible = snapshot.iter().map(|node| node.id).collect();
+    (events::sse_frame("snapshot", &snapshot), visible)
+}
+
+/// Streams a `snapshot` of the active nodes followed by incremental `joined`/`updated`/`left`/
+/// `registered` events, optionally restricted to a single node. Callers only get events for
+/// nodes they could see in `/nodes`; a node becoming invisible to them streams as `left`, and
+/// `registered` events go to admins only. A caller too slow to keep up with the events gets a
+/// fresh `snapshot` in place of the ones it missed, and should replace its view with it.
+#[utoipa::path(
+    params(StreamQuery),
+    responses((status = 200, description = "Server-Sent Events", content_type = "text/event-stream")),
+    security(("bearer" = []))
+)]
+#[get("/nodes/stream")]
+pub async fn nodes_stream(
+    query: web::Query<StreamQuery>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let filter = query.node_id;
+    let matches = move |id: &Uuid| filter.is_none_or(|wanted| wanted == *id);
+    let claims = Rc::new(claims.into_inner());
+
+    // Subscribe before snapshotting so no event between the two is lost.
+    let rx = state.events.subscribe();
+    let (first, visible) = snapshot_frame(&state, &claims, filter).await;
+
+    let updates = stream::unfold((rx, visible), move |(mut rx, mut visible)| {
+        let claims = claims.clone();
+        let state = state.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) if matches(&event.node_id()) => {
+                        if let Some(event) = visible_event(event, &claims, &mut visible) {
+                            return Some((Ok::<_, Error>(event.to_sse()), (rx, visible)));
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        // The backlog still queued predates the resync, so skip it too, again
+                        // subscribing before snapshotting.
+                        eprintln!("Event stream fell {} events behind; resyncing", missed);
+                        rx = rx.resubscribe();
+                        let (frame, fresh) = snapshot_frame(&state, &claims, filter).await;
+                        return Some((Ok(frame), (rx, fresh)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream::once(async move { Ok(first) }).chain(updates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::Role;
+    use actix_web::body::MessageBody;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use serde_json::json;
+    use std::future::poll_fn;
+    use std::pin::Pin;
+
+    #[actix_web::test]
+    async fn http_password_failures_ban_the_node_id() {
+        let config = Config {
+            node_auth_max_failures: 2,
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let id = Uuid::new_v4();
+        let reg_node = RegisteredNode::test(id, "hunter22");
+        state.registered_nodes.lock().await.insert(id, reg_node);
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(heartbeat)
+                .service(set_address),
+        )
+        .await;
+        let heartbeat_with = |password: &str| {
+            TestRequest::post()
+                .uri(&format!("/nodes/{}/heartbeat", id))
+                .set_json(json!({ "password": password }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, heartbeat_with("hunter22")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, heartbeat_with("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = call_service(&app, heartbeat_with("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Banned: even the right password is refused, on either endpoint.
+        let resp = call_service(&app, heartbeat_with("hunter22")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        let req = TestRequest::post()
+            .uri(&format!("/nodes/{}/address", id))
+            .set_json(json!({ "password": "hunter22", "ip": "10.0.0.1", "port": 8000 }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(state.node_auth_throttle.banned_for(&id).is_some());
+    }
+
+    #[actix_web::test]
+    async fn http_success_resets_failures() {
+        let config = Config {
+            node_auth_max_failures: 2,
+            ..Config::default()
+        };
+        let state = web::Data::new(AppState::new(config, HashMap::new()));
+        let id = Uuid::new_v4();
+        let reg_node = RegisteredNode::test(id, "hunter22");
+        state.registered_nodes.lock().await.insert(id, reg_node);
+        let app = init_service(App::new().app_data(state.clone()).service(heartbeat)).await;
+
+        for password in ["wrong", "hunter22", "wrong"] {
+            let req = TestRequest::post()
+                .uri(&format!("/nodes/{}/heartbeat", id))
+                .set_json(json!({ "password": password }))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert!(state.node_auth_throttle.banned_for(&id).is_none());
+    }
+
+    #[actix_web::test]
+    async fn lagging_event_stream_gets_a_fresh_snapshot() {
+        let state = web::Data::new(AppState::new(Config::default(), HashMap::new()));
+        let admin = Claims {
+            sub: "admin".to_string(),
+            exp: usize::MAX,
+            iat: None,
+            jti: None,
+            iss: None,
+            aud: None,
+            role: Some(Role::Admin),
+            scopes: Vec::new(),
+            tenant: None,
+            nonce: None,
+        };
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap_fn(move |req, srv| {
+                    // Stands in for `auth::validator`.
+                    req.extensions_mut().insert(admin.clone());
+                    srv.call(req)
+                })
+                .service(nodes_stream),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().uri("/nodes/stream").to_request()).await;
+        let mut body = resp.into_body();
+        let mut next_chunk = async || {
+            let chunk = poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await;
+            String::from_utf8(chunk.unwrap().unwrap().to_vec()).unwrap()
+        };
+        assert!(next_chunk().await.starts_with("event: snapshot\ndata: []"));
+
+        // Overflow the subscriber's queue while it isn't reading.
+        let id = Uuid::new_v4();
+        let source_ip = IpAddr::from([127, 0, 0, 1]);
+        let node = ProxyNode::new(&RegisteredNode::test(id, "hunter22"), source_ip);
+        state.active_nodes.lock().await.insert(id, node.clone());
+        for _ in 0..300 {
+            let node = node.clone();
+            events::publish(&state.events, NodeEvent::Updated { node });
+        }
+
+        let resync = next_chunk().await;
+        assert!(resync.starts_with("event: snapshot\n"));
+        assert!(resync.contains(&id.to_string()));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::errors::{ApiError, FieldError};
+use actix_web::{get, HttpResponse, Responder};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+/// The REST API description served at `/openapi.json`. The ws protocol isn't covered.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Ferivonus Proxy Network API"),
+    paths(
+        crate::index,
+        crate::health,
+        crate::version_endpoint,
+        crate::metrics::metrics,
+        crate::register,
+        crate::check_key,
+        crate::register_signed,
+        crate::user_handlers::login,
+        crate::user_handlers::bootstrap,
+        crate::user_handlers::hello,
+        crate::user_handlers::my_tokens,
+        crate::user_handlers::revoke_my_token,
+        crate::auth::jwks,
+        crate::auth::validate_token,
+        crate::node_handlers::heartbeat,
+        crate::node_handlers::set_address,
+        crate::node_handlers::nodes_stream,
+        crate::node_handlers::nodes_ndjson,
+        crate::nodes_endpoint,
+        crate::node_endpoint,
+        crate::pick_node,
+        crate::search_nodes,
+        crate::nodes_by_mac,
+        crate::pools::list_pools,
+        crate::pools::pick_from_pool,
+        crate::registered_nodes_endpoint,
+        crate::deregister,
+        crate::stats::stats_endpoint,
+        crate::admin_handlers::list_sessions,
+        crate::admin_handlers::revoke_session,
+        crate::admin_handlers::rotate_api_key,
+        crate::admin_handlers::set_maintenance,
+        crate::admin_handlers::export,
+        crate::admin_handlers::import_topology,
+    ),
+    components(schemas(ApiError, FieldError)),
+    modifiers(&BearerAuth)
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+#[get("/openapi.json")]
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
@@ -0,0 +1,103 @@
+use actix_web::web;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Hashes and checks user passwords.
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, String>;
+    fn verify(&self, password: &str, hash: &str) -> bool;
+}
+
+pub struct Bcrypt;
+
+impl PasswordHasher for Bcrypt {
+    fn hash(&self, password: &str) -> Result<String, String> {
+        bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|err| err.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+pub struct Argon2id;
+
+impl PasswordHasher for Argon2id {
+    fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).map_err(|e| e.to_string())?;
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+    }
+}
+
+/// Algorithm for new hashes (`PASSWORD_HASH=argon2|bcrypt`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordHashAlgorithm {
+    #[default]
+    Bcrypt,
+    Argon2,
+}
+
+impl FromStr for PasswordHashAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "bcrypt" => Ok(PasswordHashAlgorithm::Bcrypt),
+            "argon2" => Ok(PasswordHashAlgorithm::Argon2),
+            other => Err(format!("unknown password hash {:?}", other)),
+        }
+    }
+}
+
+impl PasswordHashAlgorithm {
+    pub fn hasher(self) -> Arc<dyn PasswordHasher> {
+        match self {
+            PasswordHashAlgorithm::Bcrypt => Arc::new(Bcrypt),
+            PasswordHashAlgorithm::Argon2 => Arc::new(Argon2id),
+        }
+    }
+}
+
+/// Checks `password` against a stored hash of either kind, detected from its prefix,
+/// so existing hashes keep working after `PASSWORD_HASH` changes.
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        Argon2id.verify(password, hash)
+    } else {
+        Bcrypt.verify(password, hash)
+    }
+}
+
+/// `hasher.hash` on the blocking pool; hashing is deliberately slow and would otherwise
+/// stall the worker's other requests.
+pub async fn hash_blocking(
+    hasher: Arc<dyn PasswordHasher>,
+    password: String,
+) -> Result<String, String> {
+    web::block(move || hasher.hash(&password))
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+/// `verify` on the blocking pool.
+pub async fn verify_blocking(password: String, hash: String) -> bool {
+    web::block(move || verify(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
@@ -0,0 +1,75 @@
+use crate::models::Claims;
+use crate::state::AppState;
+use crate::{no_eligible_node, pick_from, PickQuery};
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct PoolSummary {
+    pub name: String,
+    /// Active nodes in the pool that the caller may see.
+    pub nodes: usize,
+    /// Of those, how many `/pools/{name}/pick` could return.
+    pub pickable: usize,
+}
+
+/// Lists the pools of active nodes visible to the caller, sorted by name.
+#[utoipa::path(
+    responses((status = 200, body = Vec<PoolSummary>)),
+    security(("bearer" = []))
+)]
+#[get("/pools")]
+pub async fn list_pools(
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let guard = state.active_nodes.lock().await;
+    let mut pools: BTreeMap<&str, PoolSummary> = BTreeMap::new();
+    for node in guard.values() {
+        let Some(pool) = node.pool.as_deref() else {
+            continue;
+        };
+        if !node.visible_to(&claims) {
+            continue;
+        }
+        let summary = pools.entry(pool).or_insert_with(|| PoolSummary {
+            name: pool.to_string(),
+            nodes: 0,
+            pickable: 0,
+        });
+        summary.nodes += 1;
+        if node.is_pickable() {
+            summary.pickable += 1;
+        }
+    }
+    HttpResponse::Ok().json(pools.into_values().collect::<Vec<_>>())
+}
+
+/// Like `/nodes/pick`, restricted to one pool.
+#[utoipa::path(
+    params(("name" = String, Path, description = "Pool name"), PickQuery),
+    responses(
+        (status = 200, body = crate::ProxyNode),
+        (status = 503, description = "No eligible node in the pool; see Retry-After"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/pools/{name}/pick")]
+pub async fn pick_from_pool(
+    path: web::Path<String>,
+    query: web::Query<PickQuery>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    let pool = path.into_inner();
+    let guard = state.active_nodes.lock().await;
+    let in_pool = guard
+        .values()
+        .filter(|node| node.pool.as_deref() == Some(pool.as_str()));
+    match pick_from(in_pool, &claims, query.tag.as_deref()) {
+        Some(node) => HttpResponse::Ok().json(node),
+        None => no_eligible_node(&state),
+    }
+}
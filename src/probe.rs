@@ -0,0 +1,155 @@
+use crate::events::{self, NodeEvent, NodeEvents};
+use crate::shutdown::ShutdownSignal;
+use crate::{ActiveNodes, NodeStatus};
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::time::timeout;
+use chrono::Utc;
+use futures_util::future::join_all;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Upper bound on a single connect attempt; shorter intervals shorten it further.
+const MAX_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Periodically TCP-connects to every active node's advertised `ip:port`. After `threshold`
+/// consecutive failures a healthy node is marked `Unhealthy` (so `/pick` skips it); the next
+/// successful probe marks it `Healthy` again. Nodes unhealthy for other reasons are left to
+/// their heartbeats. Nodes without an address yet aren't probed.
+pub async fn run(
+    active_nodes: ActiveNodes,
+    events: NodeEvents,
+    interval: Duration,
+    threshold: u32,
+    mut shutdown: ShutdownSignal,
+) {
+    let probe_timeout = interval.min(MAX_PROBE_TIMEOUT);
+    let mut ticker = actix_web::rt::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        probe_all(&active_nodes, &events, probe_timeout, threshold).await;
+    }
+}
+
+async fn probe_all(
+    active_nodes: &ActiveNodes,
+    events: &NodeEvents,
+    probe_timeout: Duration,
+    threshold: u32,
+) {
+    let targets: Vec<(Uuid, SocketAddr)> = active_nodes
+        .lock()
+        .await
+        .values()
+        .filter_map(|node| {
+            let ip: IpAddr = node.ip.parse().ok()?;
+            (node.port != 0).then(|| (node.id, SocketAddr::new(ip, node.port)))
+        })
+        .collect();
+    let results = join_all(targets.into_iter().map(|(id, addr)| async move {
+        let ok = matches!(
+            timeout(probe_timeout, TcpStream::connect(addr)).await,
+            Ok(Ok(_))
+        );
+        (id, addr, ok)
+    }))
+    .await;
+
+    let now = Utc::now();
+    let mut nodes = active_nodes.lock().await;
+    for (id, addr, ok) in results {
+        let Some(node) = nodes.get_mut(&id) else {
+            continue;
+        };
+        // The address changed while probing; that result no longer applies.
+        if node.ip.parse() != Ok(addr.ip()) || node.port != addr.port() {
+            continue;
+        }
+        node.last_probe_at = Some(now);
+        node.last_probe_ok = Some(ok);
+        if ok {
+            node.probe_failures = 0;
+            if !node.probe_unhealthy {
+                continue;
+            }
+            node.probe_unhealthy = false;
+            node.status = NodeStatus::Healthy;
+        } else {
+            node.probe_failures += 1;
+            if node.probe_failures < threshold || node.status != NodeStatus::Healthy {
+                continue;
+            }
+            eprintln!(
+                "Node {} unreachable at {} after {} probes; marked unhealthy",
+                id, addr, node.probe_failures
+            );
+            node.probe_unhealthy = true;
+            node.status = NodeStatus::Unhealthy;
+        }
+        let node = node.clone();
+        events::publish(events, NodeEvent::Updated { node });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProxyNode, RegisteredNode};
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn node_at(addr: SocketAddr, status: NodeStatus) -> ProxyNode {
+        let reg_node = RegisteredNode::test(Uuid::new_v4(), "pw");
+        let mut node = ProxyNode::new(&reg_node, addr.ip());
+        node.ip = addr.ip().to_string();
+        node.port = addr.port();
+        node.status = status;
+        node
+    }
+
+    async fn probe_once(nodes: Vec<ProxyNode>) -> HashMap<Uuid, ProxyNode> {
+        let active_nodes: ActiveNodes = Arc::new(Mutex::new(
+            nodes.into_iter().map(|node| (node.id, node)).collect(),
+        ));
+        probe_all(&active_nodes, &events::channel(), MAX_PROBE_TIMEOUT, 1).await;
+        let nodes = active_nodes.lock().await.clone();
+        nodes
+    }
+
+    #[actix_web::test]
+    async fn passing_probe_only_clears_its_own_verdict() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut probed = node_at(addr, NodeStatus::Unhealthy);
+        probed.probe_unhealthy = true;
+        // E.g. `ReportError { unhealthy: true }` or a snapshot restore.
+        let reported = node_at(addr, NodeStatus::Unhealthy);
+        let (probed_id, reported_id) = (probed.id, reported.id);
+
+        let nodes = probe_once(vec![probed, reported]).await;
+        assert_eq!(nodes[&probed_id].status, NodeStatus::Healthy);
+        assert!(!nodes[&probed_id].probe_unhealthy);
+        assert_eq!(nodes[&reported_id].status, NodeStatus::Unhealthy);
+        assert_eq!(nodes[&reported_id].last_probe_ok, Some(true));
+    }
+
+    #[actix_web::test]
+    async fn failing_probe_marks_healthy_nodes_unhealthy() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let node = node_at(addr, NodeStatus::Healthy);
+        let id = node.id;
+
+        let nodes = probe_once(vec![node]).await;
+        assert_eq!(nodes[&id].status, NodeStatus::Unhealthy);
+        assert!(nodes[&id].probe_unhealthy);
+    }
+}
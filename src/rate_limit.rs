@@ -0,0 +1,343 @@
+use crate::sync::LockExt;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Classic token bucket: refills continuously at `rate` tokens per second up to `capacity`.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes one token, returning false if the bucket is empty.
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has refilled completely, i.e. is no different from a new one.
+    fn is_full(&self) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+/// A `TokenBucket` per node id, for limits that must survive reconnects (e.g. ws
+/// `ReportError`), which a bucket on the session would not.
+pub struct NodeRateLimiter {
+    rate: f64,
+    capacity: f64,
+    buckets: Mutex<HashMap<Uuid, TokenBucket>>,
+}
+
+impl NodeRateLimiter {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        NodeRateLimiter {
+            rate,
+            capacity,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one of `id`'s tokens, returning false if it has none left.
+    pub fn try_take(&self, id: &Uuid) -> bool {
+        let mut buckets = self.buckets.lock_or_recover();
+        buckets.retain(|_, bucket| !bucket.is_full());
+        buckets
+            .entry(*id)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.capacity))
+            .try_take()
+    }
+}
+
+/// Attempts per window allowed before cooldowns kick in.
+const FREE_ATTEMPTS: u32 = 3;
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+const BASE_COOLDOWN: Duration = Duration::from_secs(2);
+const MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
+struct Attempts {
+    count: u32,
+    window_start: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Per-device (`mac_id`) throttle for `/register`. Beyond `FREE_ATTEMPTS` within a window,
+/// each further attempt doubles the cooldown, up to `MAX_COOLDOWN`.
+pub struct RegistrationThrottle {
+    attempts: Mutex<HashMap<String, Attempts>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl Default for RegistrationThrottle {
+    fn default() -> Self {
+        RegistrationThrottle {
+            attempts: Mutex::new(HashMap::new()),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl RegistrationThrottle {
+    /// Records an attempt for `mac_id`, or returns how long to wait if it's cooling down.
+    pub fn check(&self, mac_id: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.prune(now);
+
+        let mut attempts = self.attempts.lock_or_recover();
+        let entry = attempts
+            .entry(mac_id.to_ascii_lowercase())
+            .or_insert(Attempts {
+                count: 0,
+                window_start: now,
+                blocked_until: None,
+            });
+        if let Some(until) = entry.blocked_until.filter(|until| *until > now) {
+            return Err(until - now);
+        }
+        if now.duration_since(entry.window_start) > ATTEMPT_WINDOW {
+            entry.count = 0;
+            entry.window_start = now;
+        }
+
+        entry.count += 1;
+        if entry.count > FREE_ATTEMPTS {
+            let doublings = (entry.count - FREE_ATTEMPTS - 1).min(16);
+            let cooldown = (BASE_COOLDOWN * 2u32.pow(doublings)).min(MAX_COOLDOWN);
+            entry.blocked_until = Some(now + cooldown);
+            // Keep the entry around at least until the cooldown ends.
+            entry.window_start = now;
+        }
+        Ok(())
+    }
+
+    /// Drops idle entries, at most once per window.
+    fn prune(&self, now: Instant) {
+        let mut last_prune = self.last_prune.lock_or_recover();
+        if now.duration_since(*last_prune) < ATTEMPT_WINDOW {
+            return;
+        }
+        *last_prune = now;
+        self.attempts.lock_or_recover().retain(|_, entry| {
+            now.duration_since(entry.window_start) <= ATTEMPT_WINDOW.max(MAX_COOLDOWN)
+        });
+    }
+}
+
+/// Failed ws password `Auth` attempts per claimed node id. After `max_failures` within the
+/// ban period the id is refused for that long, whichever connection the attempts come from.
+pub struct NodeAuthThrottle {
+    max_failures: u32,
+    ban: Duration,
+    failures: Mutex<HashMap<Uuid, Attempts>>,
+}
+
+impl NodeAuthThrottle {
+    /// `max_failures == 0` disables banning.
+    pub fn new(max_failures: u32, ban: Duration) -> Self {
+        NodeAuthThrottle {
+            max_failures,
+            ban,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time left on `id`'s ban, if it is banned.
+    pub fn banned_for(&self, id: &Uuid) -> Option<Duration> {
+        let now = Instant::now();
+        self.failures
+            .lock_or_recover()
+            .get(id)
+            .and_then(|entry| entry.blocked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Counts a failure for `id`; returns true if this one triggered a ban.
+    pub fn record_failure(&self, id: &Uuid) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock_or_recover();
+        failures.retain(|_, entry| {
+            now.duration_since(entry.window_start) <= self.ban
+                || entry.blocked_until.is_some_and(|until| until > now)
+        });
+        let entry = failures.entry(*id).or_insert(Attempts {
+            count: 0,
+            window_start: now,
+            blocked_until: None,
+        });
+        entry.count += 1;
+        if entry.count < self.max_failures {
+            return false;
+        }
+        entry.count = 0;
+        entry.window_start = now;
+        entry.blocked_until = Some(now + self.ban);
+        true
+    }
+
+    /// Forgets earlier failures once the node authenticates.
+    pub fn reset(&self, id: &Uuid) {
+        self.failures.lock_or_recover().remove(id);
+    }
+}
+
+/// Minimum gap between accepted address updates per node id (`Config::address_update_min_interval`).
+/// Kept here rather than on the ws session, so reconnecting or switching to the HTTP
+/// endpoint doesn't reset it.
+pub struct AddressUpdateLimiter {
+    interval: Duration,
+    last_update: Mutex<HashMap<Uuid, Instant>>,
+}
+
+impl AddressUpdateLimiter {
+    /// A zero `interval` disables the limit.
+    pub fn new(interval: Duration) -> Self {
+        AddressUpdateLimiter {
+            interval,
+            last_update: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How long `id` must still wait before its next update is accepted, if at all.
+    pub fn retry_after(&self, id: &Uuid) -> Option<Duration> {
+        self.last_update
+            .lock_or_recover()
+            .get(id)
+            .map(|last| self.interval.saturating_sub(last.elapsed()))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Starts `id`'s interval; call once an update is accepted.
+    pub fn record(&self, id: &Uuid) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut last_update = self.last_update.lock_or_recover();
+        last_update.retain(|_, last| now.duration_since(*last) < self.interval);
+        last_update.insert(*id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refills() {
+        let mut bucket = TokenBucket::new(100.0, 3.0);
+        for _ in 0..3 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+        sleep(Duration::from_millis(20));
+        assert!(bucket.try_take());
+    }
+
+    #[test]
+    fn node_rate_limit_is_per_node_and_outlives_the_caller() {
+        let limiter = NodeRateLimiter::new(0.001, 2.0);
+        let (noisy, quiet) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(limiter.try_take(&noisy));
+        assert!(limiter.try_take(&noisy));
+        assert!(!limiter.try_take(&noisy));
+        assert!(limiter.try_take(&quiet));
+        // Only refilled buckets are pruned, so the noisy node stays limited.
+        assert!(!limiter.try_take(&noisy));
+    }
+
+    #[test]
+    fn rapid_registrations_hit_growing_cooldowns() {
+        let throttle = RegistrationThrottle::default();
+        for _ in 0..=FREE_ATTEMPTS {
+            assert!(throttle.check("aa:bb:cc:dd:ee:ff").is_ok());
+        }
+        // Same device however the MAC is written; other devices are unaffected.
+        let wait = throttle.check("AA:BB:CC:DD:EE:FF").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= BASE_COOLDOWN);
+        assert!(throttle.check("aa:bb:cc:dd:ee:00").is_ok());
+
+        // Once the cooldown has passed, the next attempt in the window doubles it.
+        let now = Instant::now();
+        let mut attempts = throttle.attempts.lock_or_recover();
+        attempts.get_mut("aa:bb:cc:dd:ee:ff").unwrap().blocked_until = Some(now);
+        drop(attempts);
+        assert!(throttle.check("aa:bb:cc:dd:ee:ff").is_ok());
+        let wait = throttle.check("aa:bb:cc:dd:ee:ff").unwrap_err();
+        assert!(wait > BASE_COOLDOWN && wait <= BASE_COOLDOWN * 2);
+    }
+
+    #[test]
+    fn registration_throttle_prunes_idle_devices() {
+        let throttle = RegistrationThrottle::default();
+        assert!(throttle.check("aa:bb:cc:dd:ee:ff").is_ok());
+        let now = Instant::now();
+        let long_ago = now - ATTEMPT_WINDOW.max(MAX_COOLDOWN) - Duration::from_secs(1);
+        throttle
+            .attempts
+            .lock_or_recover()
+            .get_mut("aa:bb:cc:dd:ee:ff")
+            .unwrap()
+            .window_start = long_ago;
+        *throttle.last_prune.lock_or_recover() = now - ATTEMPT_WINDOW - Duration::from_secs(1);
+
+        assert!(throttle.check("aa:bb:cc:dd:ee:00").is_ok());
+        let attempts = throttle.attempts.lock_or_recover();
+        assert!(!attempts.contains_key("aa:bb:cc:dd:ee:ff"));
+        assert!(attempts.contains_key("aa:bb:cc:dd:ee:00"));
+    }
+
+    #[test]
+    fn address_updates_wait_out_the_interval_per_node() {
+        let limiter = AddressUpdateLimiter::new(Duration::from_millis(50));
+        let (node, other) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(limiter.retry_after(&node), None);
+        limiter.record(&node);
+        for _ in 0..5 {
+            let wait = limiter.retry_after(&node).expect("rapid update accepted");
+            assert!(wait <= Duration::from_millis(50));
+        }
+        assert_eq!(limiter.retry_after(&other), None);
+        sleep(Duration::from_millis(60));
+        assert_eq!(limiter.retry_after(&node), None);
+    }
+
+    #[test]
+    fn zero_interval_never_limits() {
+        let limiter = AddressUpdateLimiter::new(Duration::ZERO);
+        let node = Uuid::new_v4();
+        limiter.record(&node);
+        assert_eq!(limiter.retry_after(&node), None);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::config::Config;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use std::sync::Arc;
+
+/// The headers configured by `Config::header_*`, resolved once at startup.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Arc<[(HeaderName, HeaderValue)]>,
+}
+
+impl SecurityHeaders {
+    /// `Strict-Transport-Security` is only included when `tls` is on; browsers ignore it over
+    /// plain HTTP anyway.
+    pub fn new(config: &Config, tls: bool) -> Self {
+        let mut headers = Vec::new();
+        if config.header_nosniff {
+            headers.push((
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ));
+        }
+        let configured = [
+            (
+                header::X_FRAME_OPTIONS,
+                config.header_frame_options.as_str(),
+            ),
+            (
+                header::REFERRER_POLICY,
+                config.header_referrer_policy.as_str(),
+            ),
+            (
+                header::STRICT_TRANSPORT_SECURITY,
+                if tls { config.header_hsts.as_str() } else { "" },
+            ),
+        ];
+        for (name, value) in configured {
+            // `Config::validate` already rejected invalid values.
+            if let Ok(value) = HeaderValue::from_str(value) {
+                if !value.is_empty() {
+                    headers.push((name, value));
+                }
+            }
+        }
+        SecurityHeaders {
+            headers: headers.into(),
+        }
+    }
+
+    /// Adds the headers a handler hasn't set itself.
+    pub fn apply<B>(&self, mut response: ServiceResponse<B>) -> ServiceResponse<B> {
+        let response_headers = response.headers_mut();
+        for (name, value) in self.headers.iter() {
+            if !response_headers.contains_key(name) {
+                response_headers.insert(name.clone(), value.clone());
+            }
+        }
+        response
+    }
+}
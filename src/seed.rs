@@ -0,0 +1,135 @@
+use crate::crypto::NodeSecret;
+use crate::db;
+use crate::models::Role;
+use crate::state::AppState;
+use crate::tls;
+use crate::validation::{validate_mac_id, validate_pool_name};
+use crate::RegisteredNode;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Contents of a `SEED_FILE`. Dev-only: passwords are plaintext in the file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Seed {
+    #[serde(default)]
+    users: Vec<SeedUser>,
+    #[serde(default)]
+    nodes: Vec<SeedNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeedUser {
+    username: String,
+    /// Hashed with the configured `PASSWORD_HASH` on load.
+    password: String,
+    #[serde(default = "default_role")]
+    role: Role,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeedNode {
+    id: Uuid,
+    password: String,
+    mac_id: String,
+    #[serde(default)]
+    cert_fingerprint: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    pool: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+fn default_role() -> Role {
+    Role::User
+}
+
+/// Inserts the users and registered nodes from a JSON seed file, skipping any that already
+/// exist. Unlike the node snapshot, a bad seed file fails startup.
+pub async fn load(path: &Path, state: &AppState) -> io::Result<()> {
+    let data = fs::read(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let seed: Seed = serde_json::from_slice(&data).map_err(|err| invalid(path, err))?;
+    for node in &seed.nodes {
+        validate_mac_id(&node.mac_id).map_err(|err| invalid(path, err))?;
+        if let Some(pool) = &node.pool {
+            validate_pool_name(pool).map_err(|err| invalid(path, err))?;
+        }
+        if let Some(tenant) = &node.tenant {
+            validate_pool_name(tenant).map_err(|err| invalid(path, err))?;
+        }
+    }
+
+    let mut users_added = 0;
+    for user in seed.users {
+        if state.users.lock().await.contains_key(&user.username) {
+            continue;
+        }
+        db::add_user(
+            &state.users,
+            state.password_hasher.clone(),
+            &user.username,
+            &user.password,
+            user.role,
+            user.scopes,
+            user.tenant,
+        )
+        .await;
+        users_added += 1;
+    }
+
+    let mut nodes_added = 0;
+    let mut reg_nodes = state.registered_nodes.lock().await;
+    for node in seed.nodes {
+        if reg_nodes.contains_key(&node.id) {
+            continue;
+        }
+        reg_nodes.insert(
+            node.id,
+            RegisteredNode {
+                id: node.id,
+                secret: NodeSecret::new(&node.password),
+                mac_id: node.mac_id,
+                cert_fingerprint: node
+                    .cert_fingerprint
+                    .as_deref()
+                    .map(tls::normalize_fingerprint),
+                tags: node.tags,
+                pool: node.pool,
+                address: None,
+                owner: None,
+                tenant: node.tenant,
+                expires_at: state.config.registration_expiry(),
+                token_nonce: Uuid::new_v4(),
+            },
+        );
+        nodes_added += 1;
+    }
+    state.registered_nodes_cache.invalidate();
+
+    println!(
+        "Seeded {} users and {} nodes from {} (dev only)",
+        users_added,
+        nodes_added,
+        path.display()
+    );
+    Ok(())
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), err),
+    )
+}
@@ -0,0 +1,81 @@
+use crate::sync::LockExt;
+use actix_web::rt::task::JoinHandle;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How long `stop` waits for each task before giving up on it.
+const TASK_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Coordinates background tasks: each one is spawned through `spawn`, watches its
+/// `ShutdownSignal`, and is awaited by `stop` when the process is shutting down.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    tasks: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+
+/// Resolves once shutdown has started.
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub async fn wait(&mut self) {
+        // An error means the `Shutdown` is gone, which also means we're shutting down.
+        let _ = self.0.wait_for(|stopping| *stopping).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            sender: watch::channel(false).0,
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.sender.subscribe())
+    }
+
+    /// Spawns a named background task that `stop` will wait for. The task must return
+    /// promptly once its `ShutdownSignal` fires.
+    pub fn spawn<F>(&self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        let handle = actix_web::rt::spawn(task);
+        self.tasks.lock_or_recover().push((name, handle));
+    }
+
+    /// Signals every task to stop and waits for each, logging the outcome.
+    pub async fn stop(&self) {
+        self.sender.send_replace(true);
+        let tasks = std::mem::take(&mut *self.tasks.lock_or_recover());
+        for (name, handle) in tasks {
+            match actix_web::rt::time::timeout(TASK_STOP_TIMEOUT, handle).await {
+                Ok(Ok(())) => println!("Background task {} stopped", name),
+                Ok(Err(err)) => eprintln!("Background task {} failed: {}", name, err),
+                Err(_) => eprintln!("Background task {} did not stop in time", name),
+            }
+        }
+    }
+}
+
+/// Resolves on SIGINT or (on Unix) SIGTERM.
+pub async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = actix_web::rt::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = actix_web::rt::signal::ctrl_c().await;
+}
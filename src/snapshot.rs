@@ -0,0 +1,68 @@
+use crate::shutdown::ShutdownSignal;
+use crate::{ActiveNodes, NodeStatus, ProxyNode};
+use actix_web::web;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Loads the last-known topology. Restored nodes are `Unhealthy` until they check in again.
+/// A missing or corrupt snapshot yields an empty map rather than failing startup.
+pub fn load(path: &Path) -> HashMap<Uuid, ProxyNode> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(_) => return HashMap::new(),
+    };
+    match serde_json::from_slice::<Vec<ProxyNode>>(&data) {
+        Ok(nodes) => nodes
+            .into_iter()
+            .map(|mut node| {
+                node.status = NodeStatus::Unhealthy;
+                (node.id, node)
+            })
+            .collect(),
+        Err(err) => {
+            eprintln!("Ignoring corrupt node snapshot {}: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Serializes `ActiveNodes` to `path`, writing to a temp file first so a crash mid-write
+/// never leaves a truncated snapshot behind.
+pub async fn write(active_nodes: &ActiveNodes, path: &Path) {
+    let nodes: Vec<ProxyNode> = active_nodes.lock().await.values().cloned().collect();
+    let Ok(data) = serde_json::to_vec(&nodes) else {
+        return;
+    };
+
+    let path = path.to_path_buf();
+    let result = web::block(move || {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)
+    })
+    .await;
+    if let Ok(Err(err)) = result {
+        eprintln!("Failed to write node snapshot: {}", err);
+    }
+}
+
+/// Periodically snapshots `ActiveNodes` until shutdown. The final snapshot is written by
+/// `main` once the server has stopped.
+pub async fn run(
+    active_nodes: ActiveNodes,
+    path: PathBuf,
+    interval: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    let mut ticker = actix_web::rt::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => write(&active_nodes, &path).await,
+            _ = shutdown.wait() => return,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::api_key::ApiKeys;
+use crate::audit::AuditLog;
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::db::UserStore;
+use crate::events::{self, NodeEvents};
+use crate::maintenance::Maintenance;
+use crate::metrics::RequestMetrics;
+use crate::password::PasswordHasher;
+use crate::rate_limit::{
+    AddressUpdateLimiter, NodeAuthThrottle, NodeRateLimiter, RegistrationThrottle,
+};
+use crate::shutdown::Shutdown;
+use crate::stats::AppStats;
+use crate::tokens::TokenStore;
+use crate::{
+    ActiveNodes, ProxyNode, RegisteredNodes, Sessions, ERROR_REPORTS_PER_SEC, ERROR_REPORT_BURST,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, Semaphore};
+use uuid::Uuid;
+
+/// Everything handlers and ws sessions share, registered once as `web::Data<AppState>`.
+pub struct AppState {
+    pub config: Config,
+    /// Checked by `/register`; rotatable via `/admin/api-key/rotate`.
+    pub api_keys: ApiKeys,
+    pub registered_nodes: RegisteredNodes,
+    /// Serialized `/registered-nodes`; invalidate on every `registered_nodes` mutation.
+    pub registered_nodes_cache: ResponseCache,
+    pub active_nodes: ActiveNodes,
+    /// The live ws session that currently owns each authenticated node id.
+    pub sessions: Sessions,
+    pub events: NodeEvents,
+    pub stats: AppStats,
+    pub metrics: RequestMetrics,
+    pub users: UserStore,
+    /// Slots for `/login` password checks; see `Config::login_max_concurrent`.
+    pub login_permits: Semaphore,
+    /// Hashes new user passwords per `Config::password_hash`.
+    pub password_hasher: Arc<dyn PasswordHasher>,
+    pub tokens: TokenStore,
+    pub registration_throttle: RegistrationThrottle,
+    /// Failed ws password auth per node id; see `Config::node_auth_max_failures`.
+    pub node_auth_throttle: NodeAuthThrottle,
+    /// Shared by ws `SetAddress` and `POST /nodes/{id}/address`.
+    pub address_updates: AddressUpdateLimiter,
+    /// Ws `ReportError` per node id, so reconnecting doesn't refill it.
+    pub error_reports: NodeRateLimiter,
+    pub maintenance: Maintenance,
+    /// Node lifecycle trail; disabled unless `Config::audit_log_path` is set.
+    pub audit: AuditLog,
+    /// `Config::bootstrap_token` until `/users/bootstrap` consumes it.
+    pub bootstrap_token: std::sync::Mutex<Option<String>>,
+    /// Background tasks and the signal telling them to stop.
+    pub shutdown: Shutdown,
+    /// Used for the uptime shown on the index page.
+    pub started_at: Instant,
+}
+
+impl AppState {
+    /// Fresh state with no registrations; `active_nodes` may be seeded from a snapshot.
+    pub fn new(config: Config, active_nodes: HashMap<Uuid, ProxyNode>) -> Self {
+        AppState {
+            password_hasher: config.password_hash.hasher(),
+            login_permits: Semaphore::new(config.login_max_concurrent),
+            api_keys: ApiKeys::new(config.api_key.clone()),
+            bootstrap_token: std::sync::Mutex::new(config.bootstrap_token.clone()),
+            maintenance: Maintenance::new(config.maintenance_mode),
+            audit: AuditLog::new(config.audit_log_path.is_some()),
+            node_auth_throttle: NodeAuthThrottle::new(
+                config.node_auth_max_failures,
+                config.node_auth_ban,
+            ),
+            address_updates: AddressUpdateLimiter::new(config.address_update_min_interval),
+            error_reports: NodeRateLimiter::new(ERROR_REPORTS_PER_SEC, ERROR_REPORT_BURST),
+            config,
+            registered_nodes: Arc::new(Mutex::new(HashMap::new())),
+            registered_nodes_cache: ResponseCache::default(),
+            active_nodes: Arc::new(Mutex::new(active_nodes)),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            events: events::channel(),
+            stats: AppStats::default(),
+            metrics: RequestMetrics::default(),
+            users: Arc::new(Mutex::new(HashMap::new())),
+            tokens: TokenStore::default(),
+            registration_throttle: RegistrationThrottle::default(),
+            shutdown: Shutdown::default(),
+            started_at: Instant::now(),
+        }
+    }
+}
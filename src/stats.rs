@@ -0,0 +1,158 @@
+use crate::auth_scope::AuthScope;
+use crate::config::limit_reached;
+use crate::state::AppState;
+use crate::sync::LockExt;
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+const LOGIN_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Process-wide counters shared across handlers and ws sessions.
+#[derive(Default)]
+pub struct AppStats {
+    pub logins: AtomicU64,
+    pub ws_auth_failures: AtomicU64,
+    /// Open ws connections, authenticated or not; see `try_open_ws`.
+    ws_connections: AtomicUsize,
+    recent_logins: Mutex<VecDeque<Instant>>,
+}
+
+impl AppStats {
+    pub fn record_login(&self) {
+        self.logins.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent_logins.lock_or_recover();
+        let now = Instant::now();
+        prune(&mut recent, now);
+        recent.push_back(now);
+    }
+
+    pub fn record_ws_auth_failure(&self) {
+        self.ws_auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a new ws connection unless `limit` is already reached. Every success must be
+    /// paired with `close_ws`.
+    pub fn try_open_ws(&self, limit: Option<usize>) -> bool {
+        self.ws_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (!limit_reached(limit, open)).then_some(open + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn close_ws(&self) {
+        self.ws_connections.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn logins_last_hour(&self) -> usize {
+        let mut recent = self.recent_logins.lock_or_recover();
+        prune(&mut recent, Instant::now());
+        recent.len()
+    }
+}
+
+fn prune(recent: &mut VecDeque<Instant>, now: Instant) {
+    while recent
+        .front()
+        .is_some_and(|at| now.duration_since(*at) > LOGIN_WINDOW)
+    {
+        recent.pop_front();
+    }
+}
+
+/// Response body of `/stats`.
+#[derive(Serialize, ToSchema)]
+pub struct StatsSummary {
+    /// Nodes currently in `ActiveNodes` (ws sessions and HTTP heartbeaters).
+    pub active_nodes: usize,
+    /// Nodes holding credentials, whether connected or not.
+    pub registered_nodes: usize,
+    /// Registered nodes that aren't currently active.
+    pub offline_nodes: usize,
+    /// Successful logins since startup.
+    pub total_logins: u64,
+    /// Successful logins in the trailing hour.
+    pub logins_last_hour: usize,
+    /// Failed ws `Auth`/`AuthToken` attempts since startup.
+    pub ws_auth_failures: u64,
+    /// Open ws connections, including ones that haven't authenticated yet.
+    pub ws_connections: usize,
+    /// Active node count per tag; untagged nodes aren't counted here.
+    pub active_nodes_by_tag: HashMap<String, usize>,
+}
+
+/// Counts across every tenant, including tag names, so callers need the `stats:read` scope
+/// (or the admin role).
+#[utoipa::path(
+    responses(
+        (status = 200, body = StatsSummary),
+        (status = 403, description = "stats:read scope required"),
+    ),
+    security(("bearer" = []))
+)]
+#[get("/stats", wrap = "AuthScope::scope(\"stats:read\")")]
+pub async fn stats_endpoint(state: web::Data<AppState>) -> impl Responder {
+    let stats = &state.stats;
+    let mut by_tag: HashMap<String, usize> = HashMap::new();
+    let (active, online_registered) = {
+        let active_nodes = state.active_nodes.lock().await;
+        for tag in active_nodes.values().flat_map(|node| &node.tags) {
+            *by_tag.entry(tag.clone()).or_default() += 1;
+        }
+        let ids: Vec<_> = active_nodes.keys().copied().collect();
+        (active_nodes.len(), ids)
+    };
+
+    // Expired registrations awaiting the sweep count as gone, as they do for auth.
+    let (registered, offline) = {
+        let registered_nodes = state.registered_nodes.lock().await;
+        let live = registered_nodes
+            .values()
+            .filter(|node| !node.is_expired())
+            .count();
+        let online = online_registered
+            .iter()
+            .filter(|id| {
+                registered_nodes
+                    .get(id)
+                    .is_some_and(|node| !node.is_expired())
+            })
+            .count();
+        (live, live - online)
+    };
+
+    HttpResponse::Ok().json(StatsSummary {
+        active_nodes: active,
+        registered_nodes: registered,
+        offline_nodes: offline,
+        total_logins: stats.logins.load(Ordering::Relaxed),
+        logins_last_hour: stats.logins_last_hour(),
+        ws_auth_failures: stats.ws_auth_failures.load(Ordering::Relaxed),
+        ws_connections: stats.ws_connections.load(Ordering::Relaxed),
+        active_nodes_by_tag: by_tag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ws_connections_stop_at_the_limit() {
+        let stats = AppStats::default();
+        assert!(stats.try_open_ws(Some(2)));
+        assert!(stats.try_open_ws(Some(2)));
+        assert!(!stats.try_open_ws(Some(2)));
+        assert_eq!(stats.ws_connections.load(Ordering::Relaxed), 2);
+
+        stats.close_ws();
+        assert!(stats.try_open_ws(Some(2)));
+        assert!(!stats.try_open_ws(Some(2)));
+        assert!(stats.try_open_ws(None));
+    }
+}
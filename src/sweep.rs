@@ -0,0 +1,137 @@
+use crate::events::{self, NodeEvent};
+use crate::shutdown::ShutdownSignal;
+use crate::state::AppState;
+use crate::{disconnect_node, Rejection};
+use actix_web::web;
+use chrono::Utc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Periodically drops what has outlived its welcome:
+/// - active nodes that have no ws session and haven't checked in for
+///   `Config::http_node_timeout`: nodes that only use the HTTP heartbeat endpoints (and
+///   entries restored from a snapshot whose node never came back). Ws nodes leave when their
+///   session ends, so they are never swept.
+/// - registrations past `Config::registration_ttl`, disconnecting their nodes.
+///
+/// Either check is skipped when its setting is zero.
+pub async fn run(state: web::Data<AppState>, mut shutdown: ShutdownSignal) {
+    let timeout = state.config.http_node_timeout;
+    let ttl = state.config.registration_ttl;
+    let interval = [timeout, ttl]
+        .into_iter()
+        .filter(|period| !period.is_zero())
+        .min()
+        .unwrap_or(Duration::from_secs(60));
+    let mut ticker = actix_web::rt::time::interval((interval / 2).max(Duration::from_secs(1)));
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        if !timeout.is_zero() {
+            sweep_stale_nodes(&state, timeout).await;
+        }
+        if !ttl.is_zero() {
+            sweep_expired_registrations(&state).await;
+        }
+    }
+}
+
+/// Removes and announces the session-less nodes last seen more than `timeout` ago.
+pub async fn sweep_stale_nodes(state: &AppState, timeout: Duration) -> Vec<Uuid> {
+    let Ok(timeout) = chrono::Duration::from_std(timeout) else {
+        return Vec::new();
+    };
+    let cutoff = Utc::now() - timeout;
+    // Same order as the ws session, so a node authenticating meanwhile is either already
+    // in `sessions` or not yet in `active_nodes`.
+    let sessions = state.sessions.lock().await;
+    let mut nodes = state.active_nodes.lock().await;
+    let stale: Vec<Uuid> = nodes
+        .values()
+        .filter(|node| node.last_seen < cutoff && !sessions.contains_key(&node.id))
+        .map(|node| node.id)
+        .collect();
+    for id in &stale {
+        nodes.remove(id);
+        state.metrics.count_leave();
+        events::publish(&state.events, NodeEvent::Left { id: *id });
+    }
+    stale
+}
+
+/// Removes expired registrations and disconnects their nodes, as deregistering would.
+pub async fn sweep_expired_registrations(state: &AppState) -> Vec<Uuid> {
+    let mut reg_nodes = state.registered_nodes.lock().await;
+    let expired: Vec<Uuid> = reg_nodes
+        .values()
+        .filter(|node| node.is_expired())
+        .map(|node| node.id)
+        .collect();
+    for id in &expired {
+        reg_nodes.remove(id);
+        state.registered_nodes_cache.invalidate();
+    }
+    // Released first: `disconnect_node` takes the session and active node locks.
+    drop(reg_nodes);
+    for id in &expired {
+        disconnect_node(state, *id, Rejection::Deregistered).await;
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::{ProxyNode, RegisteredNode};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[actix_web::test]
+    async fn drops_only_nodes_past_the_timeout() {
+        let state = AppState::new(Config::default(), HashMap::new());
+        let mut events = state.events.subscribe();
+        let source_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (stale, fresh) = (Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut nodes = state.active_nodes.lock().await;
+            for id in [stale, fresh] {
+                let node = ProxyNode::new(&RegisteredNode::test(id, "hunter22"), source_ip);
+                nodes.insert(id, node);
+            }
+            nodes.get_mut(&stale).unwrap().last_seen = Utc::now() - chrono::Duration::seconds(91);
+        }
+
+        let swept = sweep_stale_nodes(&state, Duration::from_secs(90)).await;
+        assert_eq!(swept, vec![stale]);
+        let nodes = state.active_nodes.lock().await;
+        assert!(!nodes.contains_key(&stale) && nodes.contains_key(&fresh));
+        assert!(matches!(events.try_recv(), Ok(NodeEvent::Left { id }) if id == stale));
+    }
+
+    #[actix_web::test]
+    async fn prunes_only_expired_registrations() {
+        let state = AppState::new(Config::default(), HashMap::new());
+        let (expired, live) = (Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut reg_nodes = state.registered_nodes.lock().await;
+            let mut node = RegisteredNode::test(expired, "hunter22");
+            node.expires_at = Some(Utc::now() - chrono::Duration::milliseconds(1));
+            reg_nodes.insert(expired, node);
+            let mut node = RegisteredNode::test(live, "hunter22");
+            node.expires_at = Some(Utc::now() + chrono::Duration::seconds(60));
+            reg_nodes.insert(live, node);
+        }
+        let source_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = ProxyNode::new(&RegisteredNode::test(expired, "hunter22"), source_ip);
+        state.active_nodes.lock().await.insert(expired, node);
+
+        assert_eq!(sweep_expired_registrations(&state).await, vec![expired]);
+        let reg_nodes = state.registered_nodes.lock().await;
+        assert!(!reg_nodes.contains_key(&expired) && reg_nodes.contains_key(&live));
+        assert!(!state.active_nodes.lock().await.contains_key(&expired));
+    }
+}
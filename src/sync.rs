@@ -0,0 +1,50 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Locking for the std mutexes in `AppState`. A panic while holding one of them poisons it,
+/// and `.lock().unwrap()` would then panic on every later request. Their contents are simple
+/// bookkeeping that stays usable after a panic, so recover instead.
+///
+/// The node maps use tokio's `Mutex`, which doesn't poison.
+pub trait LockExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            eprintln!(
+                "Recovering poisoned lock on {} after a panic",
+                std::any::type_name::<T>()
+            );
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn lock_or_recover_survives_a_poisoned_lock() {
+        let counts = Arc::new(Mutex::new(vec![1, 2]));
+        let poisoner = counts.clone();
+        let panicked = thread::spawn(move || {
+            let mut guard = poisoner.lock().unwrap();
+            guard.push(3);
+            panic!("poisoning the lock");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(counts.is_poisoned());
+
+        // The write made before the panic is kept, and the lock is usable again.
+        counts.lock_or_recover().push(4);
+        assert_eq!(*counts.lock_or_recover(), [1, 2, 3, 4]);
+        assert!(!counts.is_poisoned());
+        assert!(counts.lock().is_ok());
+    }
+}
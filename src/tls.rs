@@ -0,0 +1,147 @@
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::pki_types::CertificateDer;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+/// SHA-256 fingerprint of the client certificate presented on this connection.
+#[derive(Clone)]
+pub struct ClientCertFingerprint(pub String);
+
+fn invalid<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+/// Builds the rustls config from `TLS_CERT_FILE`/`TLS_KEY_FILE`, or `None` when TLS is off.
+/// Setting `TLS_CLIENT_CA_FILE` additionally accepts (but doesn't require) client certificates.
+pub fn server_config() -> io::Result<Option<ServerConfig>> {
+    let (Ok(cert_path), Ok(key_path)) = (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE"))
+    else {
+        return Ok(None);
+    };
+
+    let certs = load_certs(&cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid("no private key found"))?;
+
+    let versions = protocol_versions()?;
+    let mut provider = rustls::crypto::ring::default_provider();
+    provider.cipher_suites = cipher_suites(provider.cipher_suites, versions)?;
+    let provider = Arc::new(provider);
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)
+        .map_err(invalid)?;
+
+    let builder = match env::var("TLS_CLIENT_CA_FILE") {
+        Ok(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&ca_path)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(invalid)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .map(Some)
+        .map_err(invalid)
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// `TLS_MIN_VERSION=1.2` (default) or `1.3`.
+fn protocol_versions() -> io::Result<&'static [&'static SupportedProtocolVersion]> {
+    match env::var("TLS_MIN_VERSION").as_deref() {
+        Err(_) | Ok("1.2") => Ok(rustls::ALL_VERSIONS),
+        Ok("1.3") => Ok(TLS13_ONLY),
+        Ok(other) => Err(invalid(format!(
+            "TLS_MIN_VERSION: expected 1.2 or 1.3, got {:?}",
+            other
+        ))),
+    }
+}
+
+/// Narrows `suites` to the comma-separated rustls names in `TLS_CIPHER_SUITES` (e.g.
+/// `TLS13_AES_256_GCM_SHA384`), keeping rustls' defaults when unset. Fails on unknown names
+/// and when nothing usable with `versions` is left.
+fn cipher_suites(
+    suites: Vec<SupportedCipherSuite>,
+    versions: &[&SupportedProtocolVersion],
+) -> io::Result<Vec<SupportedCipherSuite>> {
+    let name = |suite: &SupportedCipherSuite| suite.suite().as_str().unwrap_or_default();
+    let suites = match env::var("TLS_CIPHER_SUITES") {
+        Ok(list) => {
+            let wanted: Vec<&str> = list
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .collect();
+            if let Some(unknown) = wanted
+                .iter()
+                .find(|wanted| !suites.iter().any(|suite| name(suite) == **wanted))
+            {
+                let known: Vec<&str> = suites.iter().map(name).collect();
+                return Err(invalid(format!(
+                    "TLS_CIPHER_SUITES: unknown suite {:?} (available: {})",
+                    unknown,
+                    known.join(", ")
+                )));
+            }
+            suites
+                .into_iter()
+                .filter(|suite| wanted.contains(&name(suite)))
+                .collect()
+        }
+        Err(_) => suites,
+    };
+    let usable: Vec<SupportedCipherSuite> = suites
+        .into_iter()
+        .filter(|suite| versions.contains(&suite.version()))
+        .collect();
+    if usable.is_empty() {
+        return Err(invalid(
+            "TLS_CIPHER_SUITES leaves no cipher suite usable with TLS_MIN_VERSION",
+        ));
+    }
+    Ok(usable)
+}
+
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Normalizes a user-supplied fingerprint (`AB:CD:...` or `abcd...`) to lowercase hex.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_lowercase()
+}
+
+/// `HttpServer::on_connect` hook stashing the client certificate fingerprint in connection data.
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = tls.get_ref();
+        if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+            ext.insert(ClientCertFingerprint(fingerprint(cert)));
+        }
+    }
+}
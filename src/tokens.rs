@@ -0,0 +1,85 @@
+use crate::models::Claims;
+use crate::sync::LockExt;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use utoipa::ToSchema;
+
+/// Metadata about an issued user token. The token string itself is never kept.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct IssuedToken {
+    pub jti: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Tracks user tokens issued since startup, per subject, and the ids of revoked ones.
+/// Revoked ids are kept until the token would have expired anyway.
+#[derive(Default)]
+pub struct TokenStore {
+    issued: Mutex<HashMap<String, Vec<IssuedToken>>>,
+    revoked: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl TokenStore {
+    pub fn record(&self, claims: &Claims) {
+        let Some(jti) = &claims.jti else {
+            return;
+        };
+        let token = IssuedToken {
+            jti: jti.clone(),
+            issued_at: timestamp(claims.iat.unwrap_or_default()),
+            expires_at: timestamp(claims.exp),
+        };
+        let mut issued = self.issued.lock_or_recover();
+        let tokens = issued.entry(claims.sub.clone()).or_default();
+        prune(tokens);
+        tokens.push(token);
+    }
+
+    /// Unexpired, unrevoked tokens issued to `sub`.
+    pub fn list(&self, sub: &str) -> Vec<IssuedToken> {
+        let mut issued = self.issued.lock_or_recover();
+        match issued.get_mut(sub) {
+            Some(tokens) => {
+                prune(tokens);
+                tokens.clone()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Revokes one of `sub`'s tokens. Returns false if `sub` has no such live token.
+    pub fn revoke(&self, sub: &str, jti: &str) -> bool {
+        let removed = {
+            let mut issued = self.issued.lock_or_recover();
+            let Some(tokens) = issued.get_mut(sub) else {
+                return false;
+            };
+            let Some(index) = tokens.iter().position(|token| token.jti == jti) else {
+                return false;
+            };
+            tokens.remove(index)
+        };
+
+        let mut revoked = self.revoked.lock_or_recover();
+        let now = Utc::now();
+        revoked.retain(|_, expires_at| *expires_at > now);
+        revoked.insert(removed.jti, removed.expires_at);
+        true
+    }
+
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.revoked.lock_or_recover().contains_key(jti)
+    }
+}
+
+fn prune(tokens: &mut Vec<IssuedToken>) {
+    let now = Utc::now();
+    tokens.retain(|token| token.expires_at > now);
+}
+
+fn timestamp(secs: usize) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs as i64, 0).unwrap_or_default()
+}
@@ -1,24 +1,195 @@
-use crate::auth::create_jwt;
-use crate::{
-    db::USERS,
-    models::{LoginRequest, LoginResponse},
-};
-use actix_web::{get, post, web, HttpResponse, Responder};
-use bcrypt::verify;
+use crate::api_key::constant_time_eq;
+use crate::auth::{self, create_jwt};
+use crate::models::{BootstrapRequest, Claims, LoginRequest, LoginResponse, Role, User};
+use crate::password;
+use crate::state::AppState;
+use crate::sync::LockExt;
+use crate::tokens::IssuedToken;
+use crate::validation::ValidJson;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::rt::time::timeout;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use utoipa::IntoParams;
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LoginQuery {
+    /// Set the token as an `HttpOnly` cookie (`auth::TOKEN_COOKIE`) instead of returning it.
+    pub cookie: Option<bool>,
+}
+
+#[utoipa::path(
+    params(LoginQuery),
+    request_body = LoginRequest,
+    responses(
+        (status = 200, body = LoginResponse),
+        (status = 204, description = "Logged in with `?cookie=true`; the token is in Set-Cookie"),
+        (status = 400, body = crate::errors::ApiError),
+        (status = 401, description = "Invalid username or password"),
+        (status = 503, description = "Too many logins in progress; see Retry-After"),
+    )
+)]
 #[post("/login")]
-pub async fn login(data: web::Json<LoginRequest>) -> impl Responder {
-    let users = USERS.lock().await;
-    if let Some(user) = users.get(&data.username) {
-        if verify(&data.password, &user.password_hash).unwrap_or(false) {
-            let token = create_jwt(&user.username);
-            return HttpResponse::Ok().json(LoginResponse { token });
-        }
+pub async fn login(
+    query: web::Query<LoginQuery>,
+    data: ValidJson<LoginRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let Some(user) = state.users.lock().await.get(&data.username).cloned() else {
+        return HttpResponse::Unauthorized().body("Invalid username or password");
+    };
+
+    // Password hashing is slow on purpose; cap how many checks run at once so a login flood
+    // can't tie up every blocking thread.
+    let permit = timeout(
+        state.config.login_queue_timeout,
+        state.login_permits.acquire(),
+    )
+    .await;
+    let Ok(Ok(_permit)) = permit else {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .body("Too many logins in progress, try again");
+    };
+    let valid = password::verify_blocking(data.password.clone(), user.password_hash.clone());
+    if !valid.await {
+        return HttpResponse::Unauthorized().body("Invalid username or password");
+    }
+
+    let (token, claims) = create_jwt(&user);
+    state.tokens.record(&claims);
+    state.stats.record_login();
+    if query.cookie == Some(true) {
+        // The body stays empty so scripts never see the token. SameSite=Strict keeps other
+        // sites from making authenticated requests with it.
+        let lifetime = (claims.exp as i64 - chrono::Utc::now().timestamp()).max(0);
+        let cookie = Cookie::build(auth::TOKEN_COOKIE, token)
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(CookieDuration::seconds(lifetime))
+            .finish();
+        return HttpResponse::NoContent().cookie(cookie).finish();
     }
-    HttpResponse::Unauthorized().body("Invalid username or password")
+    HttpResponse::Ok().json(LoginResponse { token })
 }
 
+#[utoipa::path(
+    responses((status = 200, description = "Greeting for authenticated callers")),
+    security(("bearer" = []))
+)]
 #[get("/hello")]
 pub async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello! You are authenticated.")
 }
+
+/// Lists the caller's live tokens (metadata only) issued since the server started.
+#[utoipa::path(
+    responses((status = 200, body = Vec<IssuedToken>)),
+    security(("bearer" = []))
+)]
+#[get("/me/tokens")]
+pub async fn my_tokens(state: web::Data<AppState>, claims: web::ReqData<Claims>) -> impl Responder {
+    HttpResponse::Ok().json(state.tokens.list(&claims.sub))
+}
+
+#[utoipa::path(
+    params(("jti" = String, Path, description = "Token id")),
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 404, description = "No such token for this user"),
+    ),
+    security(("bearer" = []))
+)]
+#[delete("/me/tokens/{jti}")]
+pub async fn revoke_my_token(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+    claims: web::ReqData<Claims>,
+) -> impl Responder {
+    if state.tokens.revoke(&claims.sub, &path) {
+        HttpResponse::Ok().body("Token revoked")
+    } else {
+        HttpResponse::NotFound().body("Token not found")
+    }
+}
+
+/// Creates the first admin while no users exist, using the one-time `BOOTSTRAP_TOKEN`.
+/// The token is consumed on success, so this works at most once per process.
+#[utoipa::path(
+    request_body = BootstrapRequest,
+    responses(
+        (status = 201, description = "Admin created"),
+        (status = 400, body = crate::errors::ApiError),
+        (status = 401, description = "Invalid bootstrap token"),
+        (status = 403, description = "Bootstrap is not available"),
+        (status = 503, description = "Too many logins in progress; see Retry-After"),
+    )
+)]
+#[post("/users/bootstrap")]
+pub async fn bootstrap(
+    data: ValidJson<BootstrapRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    // The token is checked before hashing, so wrong guesses cost no hashing work.
+    if let Err(response) = check_bootstrap_token(&state, &data.token) {
+        return response;
+    }
+    // Hash before taking the users lock; bcrypt is slow. Like `/login`, it takes one of the
+    // login slots so bootstrap attempts can't tie up the blocking threads either.
+    let permit = timeout(
+        state.config.login_queue_timeout,
+        state.login_permits.acquire(),
+    )
+    .await;
+    let Ok(Ok(permit)) = permit else {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .body("Too many logins in progress, try again");
+    };
+    let hashed =
+        password::hash_blocking(state.password_hasher.clone(), data.password.clone()).await;
+    drop(permit);
+    let Ok(password_hash) = hashed else {
+        return HttpResponse::InternalServerError().body("Failed to hash password");
+    };
+
+    let mut users = state.users.lock().await;
+    if !users.is_empty() {
+        *state.bootstrap_token.lock_or_recover() = None;
+        return HttpResponse::Forbidden().body("Bootstrap is not available");
+    }
+    // Checked again: a concurrent bootstrap may have used the token while this one hashed.
+    if let Err(response) = check_bootstrap_token(&state, &data.token) {
+        return response;
+    }
+    *state.bootstrap_token.lock_or_recover() = None;
+    users.insert(
+        data.username.clone(),
+        User {
+            username: data.username.clone(),
+            password_hash,
+            role: Role::Admin,
+            scopes: Vec::new(),
+            tenant: None,
+        },
+    );
+    println!(
+        "Bootstrap: created admin {:?}; bootstrap mode is now off",
+        data.username
+    );
+    HttpResponse::Created().body("Admin created")
+}
+
+/// Compares `token` with the unused bootstrap token in constant time.
+fn check_bootstrap_token(state: &AppState, token: &str) -> Result<(), HttpResponse> {
+    match state.bootstrap_token.lock_or_recover().as_deref() {
+        Some(expected) if constant_time_eq(expected, token) => Ok(()),
+        Some(_) => Err(HttpResponse::Unauthorized().body("Invalid bootstrap token")),
+        None => Err(HttpResponse::Forbidden().body("Bootstrap is not available")),
+    }
+}
@@ -0,0 +1,119 @@
+use crate::errors::ApiError;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::{self, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+use validator::{Validate, ValidationError};
+
+/// JSON body extractor that also runs the body's `Validate` rules, rejecting with a
+/// field-level `ApiError` before the handler runs.
+pub struct ValidJson<T>(pub T);
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = json.await?.into_inner();
+            body.validate()
+                .map_err(|errors| ApiError::validation(&errors))?;
+            Ok(ValidJson(body))
+        })
+    }
+}
+
+/// Optional JSON body. Unlike `Option<web::Json<T>>`, only an empty body is `None`: a body
+/// that is sent must have a JSON content type and parse, or the request fails (415, 400)
+/// instead of the body being silently ignored.
+pub struct OptionalJson<T>(pub Option<T>);
+
+impl<T: DeserializeOwned + 'static> FromRequest for OptionalJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if !has_body(req) {
+            return Box::pin(future::ready(Ok(OptionalJson(None))));
+        }
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move { Ok(OptionalJson(Some(json.await?.into_inner()))) })
+    }
+}
+
+fn has_body(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(header::TRANSFER_ENCODING)
+        || headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|len| len > 0)
+}
+
+/// Accepts `aa:bb:cc:dd:ee:ff` or `aa-bb-cc-dd-ee-ff` (any case).
+pub fn validate_mac_id(mac_id: &str) -> Result<(), ValidationError> {
+    let octets: Vec<&str> = mac_id.split([':', '-']).collect();
+    let well_formed = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+    if well_formed {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("mac_id");
+        error.message = Some("must be a MAC address like aa:bb:cc:dd:ee:ff".into());
+        Err(error)
+    }
+}
+
+pub fn validate_ip(ip: &str) -> Result<(), ValidationError> {
+    if ip.parse::<std::net::IpAddr>().is_ok() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("ip");
+        error.message = Some("must be an IPv4 or IPv6 address".into());
+        Err(error)
+    }
+}
+
+/// Pool names are 1-64 characters of letters, digits, `-`, `_` or `.`, so they're safe in paths.
+pub fn validate_pool_name(pool: &str) -> Result<(), ValidationError> {
+    let well_formed = !pool.is_empty()
+        && pool.len() <= 64
+        && pool
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if well_formed {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("pool");
+        error.message = Some("must be 1-64 letters, digits, '-', '_' or '.'".into());
+        Err(error)
+    }
+}
+
+/// Node display names are 1-64 characters, not all whitespace, with no control characters.
+pub fn validate_node_name(name: &str) -> Result<(), ValidationError> {
+    let well_formed = !name.trim().is_empty()
+        && name.chars().count() <= 64
+        && !name.chars().any(char::is_control);
+    if well_formed {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("name");
+        error.message = Some("must be 1-64 characters with no control characters".into());
+        Err(error)
+    }
+}
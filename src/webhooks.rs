@@ -0,0 +1,121 @@
+use crate::crypto::hmac_sha256_hex;
+use crate::events::NodeEvent;
+use crate::shutdown::ShutdownSignal;
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Delay before the first retry; doubled after each further failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how to deliver webhooks; see `Config::webhook_url`.
+pub struct WebhookTarget {
+    pub url: String,
+    pub secret: String,
+    pub max_attempts: u32,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a NodeEvent,
+    occurred_at: DateTime<Utc>,
+}
+
+/// Forwards register/join/leave events to the webhook until shutdown. Each delivery runs in
+/// its own task, so a slow or failing endpoint never holds up the event channel; deliveries
+/// may therefore arrive out of order (use `occurred_at`).
+pub async fn run(
+    target: WebhookTarget,
+    mut events: broadcast::Receiver<NodeEvent>,
+    mut shutdown: ShutdownSignal,
+) {
+    let target = Rc::new(target);
+    let client = awc::Client::builder().timeout(REQUEST_TIMEOUT).finish();
+    loop {
+        let event = tokio::select! {
+            _ = shutdown.wait() => return,
+            event = events.recv() => event,
+        };
+        let event = match event {
+            Ok(NodeEvent::Updated { .. }) => continue,
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                eprintln!(
+                    "Webhook: skipped {} events that arrived too quickly",
+                    missed
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let body = match serde_json::to_vec(&Payload {
+            event: &event,
+            occurred_at: Utc::now(),
+        }) {
+            Ok(body) => body,
+            Err(err) => {
+                eprintln!("Webhook: can't serialize {} event: {}", event.name(), err);
+                continue;
+            }
+        };
+        actix_web::rt::spawn(deliver(
+            client.clone(),
+            target.clone(),
+            event.name(),
+            Bytes::from(body),
+            shutdown.clone(),
+        ));
+    }
+}
+
+/// POSTs one payload, signed as `X-Fer-Net-Signature: sha256=<hex HMAC of the body>`,
+/// retrying with exponential backoff; dropped after `max_attempts` failures or when shutdown
+/// starts.
+async fn deliver(
+    client: awc::Client,
+    target: Rc<WebhookTarget>,
+    event: &'static str,
+    body: Bytes,
+    mut shutdown: ShutdownSignal,
+) {
+    let signature = format!("sha256={}", hmac_sha256_hex(&target.secret, &body));
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=target.max_attempts {
+        let result = client
+            .post(&target.url)
+            .content_type("application/json")
+            .insert_header(("X-Fer-Net-Event", event))
+            .insert_header(("X-Fer-Net-Signature", signature.as_str()))
+            .send_body(body.clone())
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("status {}", response.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt == target.max_attempts {
+            eprintln!(
+                "Webhook: dropping {} event after {} attempts ({})",
+                event, attempt, error
+            );
+            return;
+        }
+        eprintln!(
+            "Webhook: {} event attempt {} failed ({}); retrying in {}s",
+            event,
+            attempt,
+            error,
+            backoff.as_secs()
+        );
+        tokio::select! {
+            _ = shutdown.wait() => return,
+            _ = actix_web::rt::time::sleep(backoff) => {}
+        }
+        backoff *= 2;
+    }
+}